@@ -1,4 +1,5 @@
-use std::net::IpAddr;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
 
 use thiserror::Error;
 use memcache::MemcacheError;
@@ -17,20 +18,209 @@ pub enum Error {
     ReqwestError(#[from] reqwest::Error),
     #[error("got unexpected status code: {0}")]
     ReqwestUnexpectedStatusCodeError(reqwest::StatusCode),
+    #[error("checksum mismatch: expected {0}, got {1}")]
+    ChecksumMismatch(String, String),
     #[error("memcached: {0}")]
     MemcachedError(MemcacheError),
+    #[error("ban store: {0}")]
+    BanStoreError(String),
     #[error("invalid connection")]
     InvalidConnection,
     #[error("IP address is blocked ip={0}")]
-    IpBlockedInCache(IpAddr),
+    IpBlockedInCache(IpAddr, Option<BanNotice>),
+    #[error("IP address is blocked as a scanner ip={0}")]
+    ScannerBlockedInCache(IpAddr, Option<BanNotice>),
+    #[error("IP address is blocked ip={0} subnet={1}")]
+    SubnetBlockedInCache(IpAddr, String, Option<BanNotice>),
+    #[error("IP address is blocklisted ip={0} list={1} net={2}")]
+    Blocklisted(IpAddr, String, String),
     #[error("ASN is blocked ip={0}")]
-    AsnBlocked(IpAddr),
+    AsnBlocked(IpAddr, Option<u32>),
     #[error("Country is blocked ip={0}")]
-    CountryBlocked(IpAddr),
+    CountryBlocked(IpAddr, Option<String>),
     #[error("{0}")]
     AnyhowError(#[from] anyhow::Error),
     #[error("Not enough data for calculation")]
     InsufficientData,
+    #[error("timed out waiting for {0}")]
+    Timeout(Timeout),
+    #[error("protocol violation: {0}")]
+    ProtocolViolation(String),
+    #[error("backend {0}: connect timed out")]
+    BackendConnectTimeout(SocketAddr),
+    #[error("backend {0}: connect failed: {1}")]
+    BackendConnectFailed(SocketAddr, std::io::Error),
+    #[error("no backend available")]
+    BackendUnavailable,
+}
+
+/// What a banned client is told about its ban.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BanNotice {
+    /// Unix time the ban ends, in seconds.
+    pub expires_at: u64,
+    /// Quoted by the player to have the ban looked up.
+    pub appeal_code: String,
+}
+
+/// Stage of a connection that ran out of time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Timeout {
+    FirstByte,
+    Handshake,
+    LoginStart,
+    Idle,
+    Lifetime,
+}
+
+impl Timeout {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Timeout::FirstByte => "first_byte",
+            Timeout::Handshake => "handshake",
+            Timeout::LoginStart => "login_start",
+            Timeout::Idle => "idle",
+            Timeout::Lifetime => "lifetime",
+        }
+    }
+}
+
+impl fmt::Display for Timeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Why a connection was let through or turned away. Drives the verdict
+/// metrics, the audit events and the disconnect shown to the client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Reason {
+    Passed,
+    Whitelisted,
+    RateLimit,
+    SubnetRateLimit,
+    ConnectionLimit,
+    Overloaded,
+    SessionLimit,
+    CachedBan,
+    Scanner,
+    Blocklist,
+    Asn,
+    Country,
+    AdmissionError,
+    Timeout,
+    ProtocolViolation,
+    ShuttingDown,
+    BackendDown,
+}
+
+impl Reason {
+    pub const ALL: [Reason; 17] = [
+        Reason::Passed,
+        Reason::Whitelisted,
+        Reason::RateLimit,
+        Reason::SubnetRateLimit,
+        Reason::ConnectionLimit,
+        Reason::Overloaded,
+        Reason::SessionLimit,
+        Reason::CachedBan,
+        Reason::Scanner,
+        Reason::Blocklist,
+        Reason::Asn,
+        Reason::Country,
+        Reason::AdmissionError,
+        Reason::Timeout,
+        Reason::ProtocolViolation,
+        Reason::ShuttingDown,
+        Reason::BackendDown,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Reason::Passed => "passed",
+            Reason::Whitelisted => "whitelisted",
+            Reason::RateLimit => "rate_limit",
+            Reason::SubnetRateLimit => "subnet_rate_limit",
+            Reason::ConnectionLimit => "connection_limit",
+            Reason::Overloaded => "overloaded",
+            Reason::SessionLimit => "session_limit",
+            Reason::CachedBan => "cached_ban",
+            Reason::Scanner => "scanner",
+            Reason::Blocklist => "blocklist",
+            Reason::Asn => "asn",
+            Reason::Country => "country",
+            Reason::AdmissionError => "admission_error",
+            Reason::Timeout => "timeout",
+            Reason::ProtocolViolation => "protocol_violation",
+            Reason::ShuttingDown => "shutting_down",
+            Reason::BackendDown => "backend_down",
+        }
+    }
+}
+
+impl std::str::FromStr for Reason {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Reason::ALL
+            .into_iter()
+            .find(|reason| reason.as_str() == s)
+            .ok_or_else(|| format!("unknown reason {}", s))
+    }
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl serde::Serialize for Reason {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::ser::Serializer,
+    {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Reason {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::de::Deserializer<'de>,
+    {
+        let s = <String as serde::Deserialize>::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl Error {
+    /// The rejection reason of a connection that failed with this error.
+    pub fn reason(&self) -> Reason {
+        match self {
+            Error::IpBlockedInCache(..) | Error::SubnetBlockedInCache(..) => Reason::CachedBan,
+            Error::ScannerBlockedInCache(..) => Reason::Scanner,
+            Error::Blocklisted(..) => Reason::Blocklist,
+            Error::AsnBlocked(..) => Reason::Asn,
+            Error::CountryBlocked(..) => Reason::Country,
+            Error::Timeout(_) => Reason::Timeout,
+            Error::ProtocolViolation(_) | Error::InvalidConnection => Reason::ProtocolViolation,
+            Error::BackendConnectTimeout(_)
+            | Error::BackendConnectFailed(..)
+            | Error::BackendUnavailable => Reason::BackendDown,
+            _ => Reason::AdmissionError,
+        }
+    }
+
+    /// Details of the ban behind a cached rejection, if the entry had any.
+    pub fn ban_notice(&self) -> Option<&BanNotice> {
+        match self {
+            Error::IpBlockedInCache(_, notice)
+            | Error::ScannerBlockedInCache(_, notice)
+            | Error::SubnetBlockedInCache(_, _, notice) => notice.as_ref(),
+            _ => None,
+        }
+    }
 }
 
 impl serde::Serialize for Error {
@@ -1,3 +1,3 @@
 mod error;
 
-pub use error::Error;
+pub use error::{BanNotice, Error, Reason, Timeout};
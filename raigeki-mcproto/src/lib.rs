@@ -1,4 +1,5 @@
 pub mod protocol;
+pub mod raknet;
 pub use protocol::*;
 
 #[derive(thiserror::Error, Debug)]
@@ -14,6 +15,12 @@ pub enum PacketError {
     
     #[error("Invalid packet ID: {0}")]
     InvalidPacketId(i32),
+
+    #[error("Invalid next state: {0}")]
+    InvalidNextState(i32),
+    
+    #[error("Invalid RakNet magic")]
+    InvalidMagic,
     
     #[error("Packet too large: {0} bytes")]
     PacketTooLarge(usize),
@@ -0,0 +1,103 @@
+use crate::PacketError;
+
+/// Largest frame accepted before the connection reaches the play state.
+pub const MAX_PRE_PLAY_FRAME: usize = 2 * 1024 * 1024;
+
+/// Splits one length-prefixed frame off the front of `buf`.
+///
+/// Returns the packet body (packet id and payload) together with the total
+/// number of bytes the frame occupies, or `None` if `buf` does not hold a
+/// complete frame yet.
+pub fn split_frame(buf: &[u8], max_len: usize) -> Result<Option<(&[u8], usize)>, PacketError> {
+    let mut len: usize = 0;
+    let mut header = 0;
+
+    loop {
+        let Some(&byte) = buf.get(header) else {
+            return Ok(None);
+        };
+
+        len |= ((byte & 0x7F) as usize) << (7 * header);
+        header += 1;
+
+        if byte & 0x80 == 0 {
+            break;
+        }
+
+        if header >= 3 {
+            return Err(PacketError::PacketTooLarge(len));
+        }
+    }
+
+    if len > max_len {
+        return Err(PacketError::PacketTooLarge(len));
+    }
+
+    match buf.get(header..header + len) {
+        Some(body) => Ok(Some((body, header + len))),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::types::write_varint;
+
+    fn frame(body: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::new();
+        write_varint(body.len() as i32, &mut frame);
+        frame.extend_from_slice(body);
+        frame
+    }
+
+    #[test]
+    fn splits_complete_frames() {
+        let mut buf = frame(&[0x00, 1, 2, 3]);
+        buf.extend_from_slice(&frame(&[0x01]));
+
+        let (body, len) = split_frame(&buf, 16).unwrap().unwrap();
+        assert_eq!((body, len), (&[0x00, 1, 2, 3][..], 5));
+        let (body, len) = split_frame(&buf[len..], 16).unwrap().unwrap();
+        assert_eq!((body, len), (&[0x01][..], 2));
+    }
+
+    #[test]
+    fn waits_for_partial_frames() {
+        let buf = frame(&[7; 300]);
+        // Every prefix, including a split length header.
+        for len in 0..buf.len() {
+            assert_eq!(split_frame(&buf[..len], 1024).unwrap(), None, "{}", len);
+        }
+        assert!(split_frame(&buf, 1024).unwrap().is_some());
+    }
+
+    #[test]
+    fn rejects_oversized_frames() {
+        let buf = frame(&[0; 17]);
+        assert!(matches!(
+            split_frame(&buf, 16),
+            Err(PacketError::PacketTooLarge(17))
+        ));
+        // Refused from the header alone, before the body arrives.
+        assert!(matches!(
+            split_frame(&buf[..1], 16),
+            Err(PacketError::PacketTooLarge(17))
+        ));
+        assert!(split_frame(&buf, 17).unwrap().is_some());
+    }
+
+    #[test]
+    fn rejects_length_headers_over_three_bytes() {
+        let mut header = Vec::new();
+        write_varint(MAX_PRE_PLAY_FRAME as i32 + 1, &mut header);
+        assert!(matches!(
+            split_frame(&header, MAX_PRE_PLAY_FRAME),
+            Err(PacketError::PacketTooLarge(_))
+        ));
+        assert!(matches!(
+            split_frame(&[0x80, 0x80, 0x80, 0x01], usize::MAX),
+            Err(PacketError::PacketTooLarge(_))
+        ));
+    }
+}
@@ -1,7 +1,9 @@
+pub mod frame;
 pub mod packet;
 pub mod packets;
 pub mod types;
 
+pub use frame::*;
 pub use packet::*;
 pub use packets::*;
-pub use types::*;
\ No newline at end of file
+pub use types::*;
@@ -0,0 +1,145 @@
+use std::io::Read;
+
+use crate::PacketError;
+use crate::protocol::types::{read_string, read_varint};
+
+/// Longest host name in a handshake, as the vanilla server reads it.
+const MAX_SERVER_ADDRESS: usize = 255;
+/// Room for the `\0FML\0`, `\0FML2\0` or `\0FML3\0` marker Forge clients
+/// append to the host name.
+const MAX_ADDRESS_MARKER: usize = 8;
+
+/// State the client asks to switch to after the handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NextState {
+    Status,
+    Login,
+    Transfer,
+}
+
+impl TryFrom<i32> for NextState {
+    type Error = PacketError;
+
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(NextState::Status),
+            2 => Ok(NextState::Login),
+            3 => Ok(NextState::Transfer),
+            _ => Err(PacketError::InvalidNextState(value)),
+        }
+    }
+}
+
+/// Serverbound handshake, the first packet of every modern connection.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HandshakePacket {
+    pub protocol_version: i32,
+    /// The host name as sent, with any Forge marker after it.
+    pub server_address: String,
+    pub server_port: u16,
+    pub next_state: NextState,
+}
+
+impl HandshakePacket {
+    pub const PACKET_ID: i32 = 0x00;
+
+    /// Parses the body of a frame (packet id included).
+    pub fn deserialize(mut body: &[u8]) -> Result<Self, PacketError> {
+        let packet_id = read_varint(&mut body)?;
+        if packet_id != Self::PACKET_ID {
+            return Err(PacketError::InvalidPacketId(packet_id));
+        }
+
+        let protocol_version = read_varint(&mut body)?;
+        let server_address = read_string(&mut body, MAX_SERVER_ADDRESS + MAX_ADDRESS_MARKER)?;
+        let host = server_address.split('\0').next().unwrap_or_default();
+        if host.chars().count() > MAX_SERVER_ADDRESS {
+            return Err(PacketError::PacketTooLarge(server_address.len()));
+        }
+
+        let mut port = [0u8; 2];
+        body.read_exact(&mut port)?;
+
+        let next_state = NextState::try_from(read_varint(&mut body)?)?;
+
+        Ok(Self {
+            protocol_version,
+            server_address,
+            server_port: u16::from_be_bytes(port),
+            next_state,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::types::{write_string, write_varint};
+
+    fn body(server_address: &str, next_state: i32) -> Vec<u8> {
+        let mut body = Vec::new();
+        write_varint(HandshakePacket::PACKET_ID, &mut body);
+        write_varint(767, &mut body);
+        write_string(server_address, &mut body);
+        body.extend_from_slice(&25565u16.to_be_bytes());
+        write_varint(next_state, &mut body);
+        body
+    }
+
+    #[test]
+    fn reads_a_handshake() {
+        let packet = HandshakePacket::deserialize(&body("mc.example.com", 2)).unwrap();
+        assert_eq!(
+            packet,
+            HandshakePacket {
+                protocol_version: 767,
+                server_address: "mc.example.com".to_string(),
+                server_port: 25565,
+                next_state: NextState::Login,
+            }
+        );
+    }
+
+    #[test]
+    fn forge_markers_fit_after_the_longest_host() {
+        let host = "a".repeat(MAX_SERVER_ADDRESS);
+        for marker in ["\0FML\0", "\0FML2\0", "\0FML3\0"] {
+            let address = format!("{}{}", host, marker);
+            let packet = HandshakePacket::deserialize(&body(&address, 1)).unwrap();
+            assert_eq!(packet.server_address, address);
+        }
+    }
+
+    #[test]
+    fn longer_hosts_are_rejected() {
+        let host = "a".repeat(MAX_SERVER_ADDRESS + 1);
+        assert!(matches!(
+            HandshakePacket::deserialize(&body(&host, 1)),
+            Err(PacketError::PacketTooLarge(_))
+        ));
+        let address = format!("{}\0FML\0", host);
+        assert!(matches!(
+            HandshakePacket::deserialize(&body(&address, 1)),
+            Err(PacketError::PacketTooLarge(_))
+        ));
+    }
+
+    #[test]
+    fn unknown_next_states_are_rejected() {
+        assert!(matches!(
+            HandshakePacket::deserialize(&body("localhost", 4)),
+            Err(PacketError::InvalidNextState(4))
+        ));
+    }
+
+    #[test]
+    fn truncated_handshakes_are_rejected() {
+        let body = body("localhost", 1);
+        for len in 0..body.len() {
+            assert!(matches!(
+                HandshakePacket::deserialize(&body[..len]),
+                Err(PacketError::Io(_))
+            ));
+        }
+    }
+}
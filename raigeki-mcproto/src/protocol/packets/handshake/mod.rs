@@ -0,0 +1,3 @@
+pub mod intention;
+
+pub use intention::*;
@@ -8,9 +8,7 @@ pub struct DisconnectPacket {
 
 impl DisconnectPacket {
     pub fn new(reason: String) -> Self {
-        Self {
-            reason: reason,
-        }
+        Self { reason }
     }
 
     pub fn serialize(&self) -> Vec<u8> {
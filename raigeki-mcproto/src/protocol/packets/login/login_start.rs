@@ -0,0 +1,25 @@
+use crate::PacketError;
+use crate::protocol::types::{read_string, read_varint};
+
+/// Serverbound Login Start. Only the player name is decoded, the trailing
+/// fields differ between protocol versions.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoginStartPacket {
+    pub name: String,
+}
+
+impl LoginStartPacket {
+    pub const PACKET_ID: i32 = 0x00;
+
+    /// Parses the body of a frame (packet id included).
+    pub fn deserialize(mut body: &[u8]) -> Result<Self, PacketError> {
+        let packet_id = read_varint(&mut body)?;
+        if packet_id != Self::PACKET_ID {
+            return Err(PacketError::InvalidPacketId(packet_id));
+        }
+
+        let name = read_string(&mut body, 16)?;
+
+        Ok(Self { name })
+    }
+}
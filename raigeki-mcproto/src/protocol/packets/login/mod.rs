@@ -1,3 +1,5 @@
 pub mod disconnect;
+pub mod login_start;
 
-pub use disconnect::*;
\ No newline at end of file
+pub use disconnect::*;
+pub use login_start::*;
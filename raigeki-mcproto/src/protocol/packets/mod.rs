@@ -1,5 +1,7 @@
-pub mod play;
+pub mod handshake;
 pub mod login;
+pub mod play;
+pub mod status;
 
 pub trait PacketDirection {
     fn direction() -> PacketDirectionType;
@@ -0,0 +1,36 @@
+/// Kick packet answering a pre-netty (0xFE) server list ping.
+///
+/// Uses the 1.4+ `§1` format, which older clients still show as plain text.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LegacyKickPacket {
+    pub protocol_version: i32,
+    pub version_name: String,
+    pub motd: String,
+    pub online_players: i32,
+    pub max_players: i32,
+}
+
+impl LegacyKickPacket {
+    pub const PACKET_ID: u8 = 0xFF;
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let payload = format!(
+            "§1\0{}\0{}\0{}\0{}\0{}",
+            self.protocol_version,
+            self.version_name,
+            self.motd,
+            self.online_players,
+            self.max_players
+        );
+        let utf16: Vec<u16> = payload.encode_utf16().collect();
+
+        let mut packet = Vec::with_capacity(3 + utf16.len() * 2);
+        packet.push(Self::PACKET_ID);
+        packet.extend_from_slice(&(utf16.len() as u16).to_be_bytes());
+        for unit in utf16 {
+            packet.extend_from_slice(&unit.to_be_bytes());
+        }
+
+        packet
+    }
+}
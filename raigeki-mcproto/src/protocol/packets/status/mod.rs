@@ -0,0 +1,5 @@
+pub mod legacy;
+pub mod response;
+
+pub use legacy::*;
+pub use response::*;
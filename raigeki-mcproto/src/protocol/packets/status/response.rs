@@ -0,0 +1,31 @@
+use crate::protocol::types::write_varint;
+use crate::write_string;
+
+/// Clientbound status response carrying the server list JSON.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatusResponsePacket {
+    pub json: String,
+}
+
+impl StatusResponsePacket {
+    pub fn new(json: String) -> Self {
+        Self { json }
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(5 + self.json.len());
+
+        write_varint(0x00, &mut packet);
+        write_string(&self.json, &mut packet);
+
+        let mut framed = Vec::with_capacity(5 + packet.len());
+        write_varint(packet.len() as i32, &mut framed);
+        framed.extend_from_slice(&packet);
+
+        framed
+    }
+}
+
+/// Packet id of both the serverbound ping request and the clientbound pong,
+/// which carry the same payload.
+pub const PING_PACKET_ID: i32 = 0x01;
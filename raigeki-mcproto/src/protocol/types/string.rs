@@ -1,6 +1,77 @@
-use super::varint::write_varint;
+use std::io::Read;
+
+use super::varint::{read_varint, write_varint};
+use crate::PacketError;
 
 pub fn write_string(s: &str, out: &mut Vec<u8>) {
     write_varint(s.len() as i32, out);
     out.extend_from_slice(s.as_bytes());
-}
\ No newline at end of file
+}
+
+/// Reads a string of at most `max_chars` characters.
+pub fn read_string<R: Read>(reader: &mut R, max_chars: usize) -> Result<String, PacketError> {
+    let len = read_varint(reader)?;
+    if len < 0 || len as usize > max_chars * 4 {
+        return Err(PacketError::PacketTooLarge(len.max(0) as usize));
+    }
+
+    let mut bytes = vec![0; len as usize];
+    reader.read_exact(&mut bytes)?;
+
+    let s = String::from_utf8(bytes)?;
+    if s.chars().count() > max_chars {
+        return Err(PacketError::PacketTooLarge(s.len()));
+    }
+
+    Ok(s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::types::write_varint;
+
+    fn read(buf: &[u8], max_chars: usize) -> Result<String, PacketError> {
+        read_string(&mut &buf[..], max_chars)
+    }
+
+    #[test]
+    fn round_trips() {
+        let mut buf = Vec::new();
+        write_string("Notch", &mut buf);
+        assert_eq!(buf, b"\x05Notch");
+        assert_eq!(read(&buf, 16).unwrap(), "Notch");
+    }
+
+    #[test]
+    fn counts_characters_not_bytes() {
+        let mut buf = Vec::new();
+        write_string("éééé", &mut buf);
+        assert_eq!(read(&buf, 4).unwrap(), "éééé");
+        assert!(matches!(read(&buf, 3), Err(PacketError::PacketTooLarge(8))));
+    }
+
+    #[test]
+    fn rejects_lengths_beyond_four_bytes_a_character() {
+        let mut buf = Vec::new();
+        write_varint(17, &mut buf);
+        assert!(matches!(
+            read(&buf, 4),
+            Err(PacketError::PacketTooLarge(17))
+        ));
+
+        let mut buf = Vec::new();
+        write_varint(-1, &mut buf);
+        assert!(matches!(read(&buf, 4), Err(PacketError::PacketTooLarge(0))));
+    }
+
+    #[test]
+    fn rejects_truncated_and_invalid_strings() {
+        assert!(matches!(read(b"\x05Not", 16), Err(PacketError::Io(_))));
+        assert!(matches!(read(b"", 16), Err(PacketError::Io(_))));
+        assert!(matches!(
+            read(b"\x02\xc3\x28", 16),
+            Err(PacketError::Utf8(_))
+        ));
+    }
+}
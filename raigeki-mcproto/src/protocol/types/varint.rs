@@ -50,4 +50,43 @@ pub fn varint_length(mut value: i32) -> usize {
         }
     }
     length
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips() {
+        for value in [0, 1, 127, 128, 255, 25565, 2097151, i32::MAX, -1, i32::MIN] {
+            let mut buf = Vec::new();
+            write_varint(value, &mut buf);
+            assert_eq!(buf.len(), varint_length(value), "{}", value);
+            assert_eq!(read_varint(&mut &buf[..]).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn known_encodings() {
+        let mut buf = Vec::new();
+        write_varint(300, &mut buf);
+        assert_eq!(buf, [0xac, 0x02]);
+
+        let mut buf = Vec::new();
+        write_varint(-1, &mut buf);
+        assert_eq!(buf, [0xff, 0xff, 0xff, 0xff, 0x0f]);
+    }
+
+    #[test]
+    fn rejects_more_than_five_bytes() {
+        let buf = [0xff, 0xff, 0xff, 0xff, 0xff, 0x01];
+        let err = read_varint(&mut &buf[..]).unwrap_err();
+        assert!(matches!(err, PacketError::Io(e) if e.kind() == std::io::ErrorKind::InvalidData));
+    }
+
+    #[test]
+    fn rejects_truncated_varints() {
+        let err = read_varint(&mut &[0x80, 0x80][..]).unwrap_err();
+        assert!(matches!(err, PacketError::Io(e) if e.kind() == std::io::ErrorKind::UnexpectedEof));
+    }
+}
@@ -0,0 +1,17 @@
+//! Offline (pre-connection) messages of RakNet, the UDP transport of Bedrock
+//! Edition. Everything after the open-connection handshake is opaque to the
+//! proxy.
+
+pub mod offline;
+
+pub use offline::*;
+
+/// Marker every offline message carries to tell it apart from connected
+/// traffic.
+pub const MAGIC: [u8; 16] = [
+    0x00, 0xff, 0xff, 0x00, 0xfe, 0xfe, 0xfe, 0xfe, 0xfd, 0xfd, 0xfd, 0xfd, 0x12, 0x34, 0x56, 0x78,
+];
+
+/// Size of the IP and UDP headers RakNet adds to a datagram when it reports
+/// an MTU.
+pub const UDP_HEADER_SIZE: usize = 28;
@@ -0,0 +1,338 @@
+use super::{MAGIC, UDP_HEADER_SIZE};
+use crate::PacketError;
+
+pub const UNCONNECTED_PING: u8 = 0x01;
+pub const UNCONNECTED_PING_OPEN_CONNECTIONS: u8 = 0x02;
+pub const OPEN_CONNECTION_REQUEST_1: u8 = 0x05;
+pub const OPEN_CONNECTION_REQUEST_2: u8 = 0x07;
+pub const NO_FREE_INCOMING_CONNECTIONS: u8 = 0x14;
+pub const CONNECTION_BANNED: u8 = 0x17;
+pub const UNCONNECTED_PONG: u8 = 0x1c;
+
+/// Client asking for the server list entry.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnconnectedPingPacket {
+    pub time: i64,
+    pub client_guid: i64,
+}
+
+impl UnconnectedPingPacket {
+    pub fn deserialize(datagram: &[u8]) -> Result<Self, PacketError> {
+        let mut reader = Reader::new(datagram);
+        let packet_id = reader.u8()?;
+        if packet_id != UNCONNECTED_PING && packet_id != UNCONNECTED_PING_OPEN_CONNECTIONS {
+            return Err(PacketError::InvalidPacketId(packet_id as i32));
+        }
+
+        let time = reader.i64()?;
+        reader.magic()?;
+        let client_guid = reader.i64()?;
+
+        Ok(Self { time, client_guid })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(33);
+        packet.push(UNCONNECTED_PING);
+        packet.extend_from_slice(&self.time.to_be_bytes());
+        packet.extend_from_slice(&MAGIC);
+        packet.extend_from_slice(&self.client_guid.to_be_bytes());
+        packet
+    }
+}
+
+/// Server list entry, `motd` is the `;` separated `MCPE;...` string.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnconnectedPongPacket {
+    pub time: i64,
+    pub server_guid: i64,
+    pub motd: String,
+}
+
+impl UnconnectedPongPacket {
+    pub fn deserialize(datagram: &[u8]) -> Result<Self, PacketError> {
+        let mut reader = Reader::new(datagram);
+        let packet_id = reader.u8()?;
+        if packet_id != UNCONNECTED_PONG {
+            return Err(PacketError::InvalidPacketId(packet_id as i32));
+        }
+
+        let time = reader.i64()?;
+        let server_guid = reader.i64()?;
+        reader.magic()?;
+        let len = reader.u16()? as usize;
+        let motd = String::from_utf8(reader.take(len)?.to_vec())?;
+
+        Ok(Self {
+            time,
+            server_guid,
+            motd,
+        })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(35 + self.motd.len());
+        packet.push(UNCONNECTED_PONG);
+        packet.extend_from_slice(&self.time.to_be_bytes());
+        packet.extend_from_slice(&self.server_guid.to_be_bytes());
+        packet.extend_from_slice(&MAGIC);
+        packet.extend_from_slice(&(self.motd.len() as u16).to_be_bytes());
+        packet.extend_from_slice(self.motd.as_bytes());
+        packet
+    }
+}
+
+/// First step of the open-connection handshake. The client pads it to the
+/// MTU it wants to probe.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OpenConnectionRequest1Packet {
+    pub protocol_version: u8,
+    pub mtu: usize,
+}
+
+impl OpenConnectionRequest1Packet {
+    pub fn deserialize(datagram: &[u8]) -> Result<Self, PacketError> {
+        let mut reader = Reader::new(datagram);
+        let packet_id = reader.u8()?;
+        if packet_id != OPEN_CONNECTION_REQUEST_1 {
+            return Err(PacketError::InvalidPacketId(packet_id as i32));
+        }
+
+        reader.magic()?;
+        let protocol_version = reader.u8()?;
+
+        Ok(Self {
+            protocol_version,
+            mtu: datagram.len() + UDP_HEADER_SIZE,
+        })
+    }
+}
+
+/// Rejection sent instead of an open-connection reply. The client shows a
+/// generic "banned" or "server full" screen for these.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionRefusedPacket {
+    /// [`CONNECTION_BANNED`] or [`NO_FREE_INCOMING_CONNECTIONS`].
+    pub packet_id: u8,
+    pub server_guid: i64,
+}
+
+impl ConnectionRefusedPacket {
+    pub fn banned(server_guid: i64) -> Self {
+        Self {
+            packet_id: CONNECTION_BANNED,
+            server_guid,
+        }
+    }
+
+    pub fn full(server_guid: i64) -> Self {
+        Self {
+            packet_id: NO_FREE_INCOMING_CONNECTIONS,
+            server_guid,
+        }
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(25);
+        packet.push(self.packet_id);
+        packet.extend_from_slice(&MAGIC);
+        packet.extend_from_slice(&self.server_guid.to_be_bytes());
+        packet
+    }
+}
+
+/// Big-endian cursor over a datagram.
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], PacketError> {
+        if self.buf.len() < len {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+        let (head, tail) = self.buf.split_at(len);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, PacketError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, PacketError> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn i64(&mut self) -> Result<i64, PacketError> {
+        Ok(i64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn magic(&mut self) -> Result<(), PacketError> {
+        if self.take(MAGIC.len())? != MAGIC {
+            return Err(PacketError::InvalidMagic);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_eof(result: Result<impl std::fmt::Debug, PacketError>) -> bool {
+        matches!(result, Err(PacketError::Io(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof)
+    }
+
+    fn pong() -> UnconnectedPongPacket {
+        UnconnectedPongPacket {
+            time: 1_700_000_000_000,
+            server_guid: -42,
+            motd: "MCPE;raigeki;712;1.21.20;3;100;42;Bedrock level;Survival;1;19132;19133;"
+                .to_string(),
+        }
+    }
+
+    #[test]
+    fn ping_round_trips() {
+        let ping = UnconnectedPingPacket {
+            time: 123_456,
+            client_guid: i64::MIN,
+        };
+        let datagram = ping.serialize();
+        assert_eq!(datagram.len(), 33);
+        assert_eq!(UnconnectedPingPacket::deserialize(&datagram).unwrap(), ping);
+
+        // Pings asking only for servers with open slots read the same.
+        let mut open = datagram.clone();
+        open[0] = UNCONNECTED_PING_OPEN_CONNECTIONS;
+        assert_eq!(UnconnectedPingPacket::deserialize(&open).unwrap(), ping);
+    }
+
+    #[test]
+    fn pong_round_trips() {
+        let pong = pong();
+        let datagram = pong.serialize();
+        assert_eq!(datagram.len(), 35 + pong.motd.len());
+        assert_eq!(UnconnectedPongPacket::deserialize(&datagram).unwrap(), pong);
+    }
+
+    #[test]
+    fn open_connection_request_reports_the_probed_mtu() {
+        let mut datagram = vec![OPEN_CONNECTION_REQUEST_1];
+        datagram.extend_from_slice(&MAGIC);
+        datagram.push(11);
+        datagram.resize(1464, 0);
+
+        let request = OpenConnectionRequest1Packet::deserialize(&datagram).unwrap();
+        assert_eq!(request.protocol_version, 11);
+        assert_eq!(request.mtu, 1492);
+    }
+
+    #[test]
+    fn refusals_carry_the_magic_and_guid() {
+        let datagram = ConnectionRefusedPacket::banned(7).serialize();
+        assert_eq!(datagram[0], CONNECTION_BANNED);
+        assert_eq!(&datagram[1..17], &MAGIC);
+        assert_eq!(&datagram[17..], &7i64.to_be_bytes());
+        assert_eq!(
+            ConnectionRefusedPacket::full(7).serialize()[0],
+            NO_FREE_INCOMING_CONNECTIONS
+        );
+    }
+
+    #[test]
+    fn wrong_magic_is_rejected() {
+        let mut ping = UnconnectedPingPacket {
+            time: 1,
+            client_guid: 2,
+        }
+        .serialize();
+        ping[9] ^= 0xff;
+        assert!(matches!(
+            UnconnectedPingPacket::deserialize(&ping),
+            Err(PacketError::InvalidMagic)
+        ));
+
+        let mut pong = pong().serialize();
+        pong[30] ^= 0xff;
+        assert!(matches!(
+            UnconnectedPongPacket::deserialize(&pong),
+            Err(PacketError::InvalidMagic)
+        ));
+
+        let mut request = vec![OPEN_CONNECTION_REQUEST_1];
+        request.extend_from_slice(&[0; 17]);
+        assert!(matches!(
+            OpenConnectionRequest1Packet::deserialize(&request),
+            Err(PacketError::InvalidMagic)
+        ));
+    }
+
+    #[test]
+    fn other_packets_are_rejected() {
+        let pong = pong().serialize();
+        assert!(matches!(
+            UnconnectedPingPacket::deserialize(&pong),
+            Err(PacketError::InvalidPacketId(0x1c))
+        ));
+        let ping = UnconnectedPingPacket {
+            time: 1,
+            client_guid: 2,
+        }
+        .serialize();
+        assert!(matches!(
+            UnconnectedPongPacket::deserialize(&ping),
+            Err(PacketError::InvalidPacketId(0x01))
+        ));
+    }
+
+    #[test]
+    fn truncated_datagrams_are_rejected() {
+        let ping = UnconnectedPingPacket {
+            time: 1,
+            client_guid: 2,
+        }
+        .serialize();
+        for len in 0..ping.len() {
+            assert!(
+                is_eof(UnconnectedPingPacket::deserialize(&ping[..len])),
+                "{}",
+                len
+            );
+        }
+
+        let pong = pong().serialize();
+        for len in 0..pong.len() {
+            assert!(
+                is_eof(UnconnectedPongPacket::deserialize(&pong[..len])),
+                "{}",
+                len
+            );
+        }
+
+        let mut request = vec![OPEN_CONNECTION_REQUEST_1];
+        request.extend_from_slice(&MAGIC);
+        assert!(is_eof(OpenConnectionRequest1Packet::deserialize(&request)));
+    }
+
+    #[test]
+    fn pong_motd_must_be_utf8() {
+        let mut pong = UnconnectedPongPacket {
+            time: 1,
+            server_guid: 2,
+            motd: "ab".to_string(),
+        }
+        .serialize();
+        let len = pong.len();
+        pong[len - 1] = 0xff;
+        assert!(matches!(
+            UnconnectedPongPacket::deserialize(&pong),
+            Err(PacketError::Utf8(_))
+        ));
+    }
+}
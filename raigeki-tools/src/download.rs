@@ -1,27 +1,542 @@
-use std::{fs::File, io::copy, path::Path, time::Duration};
+//! Downloads that land whole or not at all.
+//!
+//! A response is streamed into a file next to the target and only moved
+//! over it once it arrived in full, matched its checksum and, for archives,
+//! had the wanted file taken out. The `ETag` and `Last-Modified` of the
+//! last download are kept in a `.meta` file beside the target, so an
+//! unchanged upstream answers with a `304` instead of the whole body.
+//!
+//! A transfer cut off midway is kept, and the next attempt asks only for the
+//! rest with `Range`. `If-Range` makes an upstream that changed in between
+//! send the whole body again.
 
-use log::{error, info};
+use std::{
+    fs::{self, File},
+    io::{self, Read},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use flate2::read::GzDecoder;
+use log::{info, warn};
 use raigeki_error::Error;
-use reqwest::blocking::Client;
+use reqwest::{
+    header::{
+        CONTENT_RANGE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE, LAST_MODIFIED, RANGE,
+    },
+    Client, Response, StatusCode,
+};
+use ring::digest::{Context, SHA256};
+use tokio::{
+    fs::{File as AsyncFile, OpenOptions},
+    io::{AsyncReadExt, AsyncWriteExt},
+    task::spawn_blocking,
+    time::sleep,
+};
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+#[derive(Debug, Clone)]
+pub struct DownloadSettings {
+    /// Tried in order until one answers, the primary URL then its mirrors.
+    pub urls: Vec<String>,
+    /// Expected SHA-256 of the response, hex encoded.
+    pub sha256: Option<String>,
+    /// A `sha256sum` style file holding the expected SHA-256, as published
+    /// next to the MaxMind archives. Every URL must serve the same bytes.
+    pub checksum_url: Option<String>,
+    /// File to take out of a `.tar.gz` response. Responses that are not
+    /// gzip are kept as they are.
+    pub extract: Option<String>,
+    /// Extra rounds over the URLs after the first one failed.
+    pub retries: u32,
+    /// Wait before the first retry, doubled for every next one.
+    pub backoff: Duration,
+    pub timeout: Duration,
+}
+
+impl DownloadSettings {
+    pub fn new(urls: Vec<String>) -> Self {
+        DownloadSettings {
+            urls,
+            sha256: None,
+            checksum_url: None,
+            extract: None,
+            retries: 3,
+            backoff: Duration::from_secs(2),
+            timeout: Duration::from_secs(300),
+        }
+    }
+}
+
+/// What a download left behind.
+pub enum Fetched {
+    /// The upstream still serves the copy at the target.
+    NotModified,
+    Downloaded(Staged),
+}
+
+/// A complete, verified download waiting to be moved over its target. It is
+/// removed if dropped before [`Staged::commit`].
+pub struct Staged {
+    path: PathBuf,
+    target: PathBuf,
+    validators: Validators,
+    committed: bool,
+}
+
+impl Staged {
+    /// The downloaded file, to be checked before it is committed.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Moves the download over the target.
+    pub fn commit(mut self) -> Result<(), Error> {
+        fs::rename(&self.path, &self.target)?;
+        self.committed = true;
+        // Without validators the next download is a full one, which is fine.
+        if let Err(e) = self.validators.save(&self.target) {
+            warn!(
+                "Failed to save validators of {}: {}",
+                self.target.display(),
+                e
+            );
+        }
+        info!("File downloaded to {}", self.target.display());
+        Ok(())
+    }
+}
+
+impl Drop for Staged {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Response headers that let the next request be a conditional one.
+#[derive(Debug, Clone, Default)]
+struct Validators {
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+impl Validators {
+    fn load(target: &Path) -> Self {
+        let mut validators = Validators::default();
+        // They only hold for the file they came with.
+        if !target.exists() {
+            return validators;
+        }
+
+        let text = fs::read_to_string(sibling(target, ".meta")).unwrap_or_default();
+        for line in text.lines() {
+            match line.split_once(": ") {
+                Some(("etag", value)) => validators.etag = Some(value.to_string()),
+                Some(("last-modified", value)) => {
+                    validators.last_modified = Some(value.to_string())
+                }
+                _ => {}
+            }
+        }
+        validators
+    }
+
+    fn save(&self, target: &Path) -> io::Result<()> {
+        let mut text = String::new();
+        if let Some(etag) = &self.etag {
+            text.push_str(&format!("etag: {}\n", etag));
+        }
+        if let Some(last_modified) = &self.last_modified {
+            text.push_str(&format!("last-modified: {}\n", last_modified));
+        }
+        fs::write(sibling(target, ".meta"), text)
+    }
+
+    /// What `If-Range` can carry: a strong `ETag`, or else `Last-Modified`.
+    fn if_range(&self) -> Option<&str> {
+        self.etag
+            .as_deref()
+            .filter(|etag| !etag.starts_with("W/"))
+            .or(self.last_modified.as_deref())
+    }
+}
 
-pub fn download(addr: &str, path: &str) -> Result<(), Error> {
-    let client = Client::builder()
-        .timeout(Duration::from_secs(300))
-        .build()?;
-    
-    let response = client.get(addr).send()?;
+/// The start of a body an earlier transfer left behind.
+struct Partial {
+    len: u64,
+    /// `If-Range` validator of the response it came from.
+    validator: String,
+}
+
+impl Partial {
+    /// The partial body at `body`, if there is one that can be resumed.
+    fn load(body: &Path) -> Option<Self> {
+        let len = fs::metadata(body).ok()?.len();
+        let validator = fs::read_to_string(sibling(body, ".meta")).ok()?;
+        (len > 0 && !validator.is_empty()).then_some(Partial { len, validator })
+    }
 
-    if response.status().is_success() {
-        let mut file = File::create(Path::new(path))?;
+    fn discard(body: &Path) {
+        let _ = fs::remove_file(body);
+        let _ = fs::remove_file(sibling(body, ".meta"));
+    }
+}
 
-        let content = response.bytes()?;
-        copy(&mut content.as_ref(), &mut file)?;
+/// First byte of a `206` response, from `Content-Range: bytes FIRST-LAST/SIZE`.
+fn range_start(response: &Response) -> Option<u64> {
+    let value = response.headers().get(CONTENT_RANGE)?.to_str().ok()?;
+    value
+        .strip_prefix("bytes ")?
+        .split('-')
+        .next()?
+        .parse()
+        .ok()
+}
+
+/// `path` with `suffix` appended to its file name.
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// `url` without its query, which may hold a license key.
+fn redacted(url: &str) -> &str {
+    url.split('?').next().unwrap_or_default()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Downloads the first URL of `settings` that answers into a file next to
+/// `target`, trying every URL again with a growing wait if none does.
+pub async fn download(settings: &DownloadSettings, target: &Path) -> Result<Fetched, Error> {
+    let client = Client::builder().timeout(settings.timeout).build()?;
+
+    let mut backoff = settings.backoff;
+    let mut attempt = 0;
+    loop {
+        let mut last_error = None;
+        for url in &settings.urls {
+            match download_from(&client, settings, url, target).await {
+                Ok(fetched) => return Ok(fetched),
+                Err(e) => {
+                    warn!("Failed to download {}: {}", redacted(url), e);
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        let e = last_error.unwrap_or_else(|| {
+            Error::InternalError(format!("no URL to download {} from", target.display()))
+        });
+        if attempt >= settings.retries || settings.urls.is_empty() {
+            return Err(e);
+        }
+        attempt += 1;
+        sleep(backoff).await;
+        backoff = backoff.saturating_mul(2);
+    }
+}
 
-        info!("File downloaded to {}", path);
+async fn download_from(
+    client: &Client,
+    settings: &DownloadSettings,
+    url: &str,
+    target: &Path,
+) -> Result<Fetched, Error> {
+    let body = sibling(target, ".download");
+    let partial = Partial::load(&body);
+    let validators = Validators::load(target);
+    let mut request = client.get(url);
+    if let Some(etag) = &validators.etag {
+        request = request.header(IF_NONE_MATCH, etag);
+    }
+    if let Some(last_modified) = &validators.last_modified {
+        request = request.header(IF_MODIFIED_SINCE, last_modified);
+    }
+    if let Some(partial) = &partial {
+        request = request
+            .header(RANGE, format!("bytes={}-", partial.len))
+            .header(IF_RANGE, &partial.validator);
+    }
+
+    let mut response = request.send().await.map_err(reqwest::Error::without_url)?;
+    if response.status() == StatusCode::NOT_MODIFIED {
+        info!("{} is not modified since the last download", redacted(url));
+        Partial::discard(&body);
+        return Ok(Fetched::NotModified);
+    }
+    let resumed = match (&partial, response.status()) {
+        (Some(partial), StatusCode::PARTIAL_CONTENT)
+            if range_start(&response) == Some(partial.len) =>
+        {
+            info!("Resuming {} at byte {}", redacted(url), partial.len);
+            true
+        }
+        (_, status) if status.is_success() && status != StatusCode::PARTIAL_CONTENT => false,
+        (_, status) => {
+            // A range the upstream can't or won't serve is not asked for again.
+            if matches!(
+                status,
+                StatusCode::PARTIAL_CONTENT | StatusCode::RANGE_NOT_SATISFIABLE
+            ) {
+                Partial::discard(&body);
+            }
+            return Err(Error::ReqwestUnexpectedStatusCodeError(status));
+        }
+    };
+
+    let header = |name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+    };
+    let validators = Validators {
+        etag: header(ETAG),
+        last_modified: header(LAST_MODIFIED),
+    };
+    let expected = match (&settings.sha256, &settings.checksum_url) {
+        (Some(sha256), _) => Some(sha256.trim().to_lowercase()),
+        (None, Some(checksum_url)) => Some(fetch_checksum(client, checksum_url).await?),
+        (None, None) => None,
+    };
+
+    let mut digest = Context::new(&SHA256);
+    let mut file = if resumed {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(&body)
+            .await?;
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let read = file.read(&mut buf).await?;
+            if read == 0 {
+                break;
+            }
+            digest.update(&buf[..read]);
+        }
+        file
     } else {
-        error!("Failed to download file: {}", response.status());
+        Partial::discard(&body);
+        // Without a validator a cut off body can't be resumed, and the next
+        // attempt starts over.
+        if let Some(validator) = validators.if_range() {
+            fs::write(sibling(&body, ".meta"), validator)?;
+        }
+        AsyncFile::create(&body).await?
+    };
+
+    // A failed read leaves the body for the next attempt to resume.
+    while let Some(chunk) = response
+        .chunk()
+        .await
+        .map_err(reqwest::Error::without_url)?
+    {
+        digest.update(&chunk);
+        file.write_all(&chunk).await?;
+    }
+    file.sync_all().await?;
+    drop(file);
+    let _ = fs::remove_file(sibling(&body, ".meta"));
+
+    // From here on a failure discards the body.
+    let mut staged = Staged {
+        path: body.clone(),
+        target: target.to_path_buf(),
+        validators,
+        committed: false,
+    };
+
+    let actual = to_hex(digest.finish().as_ref());
+    if let Some(expected) = expected {
+        if expected != actual {
+            return Err(Error::ChecksumMismatch(expected, actual));
+        }
+    }
+
+    if let Some(member) = settings.extract.clone() {
+        let extracted = sibling(target, ".part");
+        let from = body.clone();
+        let to = extracted.clone();
+        let found = spawn_blocking(move || extract(&from, &member, &to))
+            .await
+            .unwrap_or_else(|e| Err(Error::InternalError(e.to_string())))?;
+        if found {
+            let _ = fs::remove_file(&body);
+            staged.path = extracted;
+        }
+    }
+
+    Ok(Fetched::Downloaded(staged))
+}
+
+/// The digest in a `sha256sum` style file, the first word of it.
+async fn fetch_checksum(client: &Client, url: &str) -> Result<String, Error> {
+    let response = client
+        .get(url)
+        .send()
+        .await
+        .map_err(reqwest::Error::without_url)?;
+    if !response.status().is_success() {
         return Err(Error::ReqwestUnexpectedStatusCodeError(response.status()));
     }
 
-    Ok(())
+    let text = response.text().await.map_err(reqwest::Error::without_url)?;
+    let digest = text.split_whitespace().next().unwrap_or_default();
+    if digest.len() != 64 || !digest.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return Err(Error::InternalError(format!(
+            "{} does not hold a SHA-256 digest",
+            redacted(url)
+        )));
+    }
+    Ok(digest.to_lowercase())
+}
+
+/// Writes the file named `member` in the `.tar.gz` at `archive` to `to`.
+/// Returns false, writing nothing, if `archive` is not gzip.
+fn extract(archive: &Path, member: &str, to: &Path) -> Result<bool, Error> {
+    let mut file = File::open(archive)?;
+    let mut magic = [0; 2];
+    if file.read_exact(&mut magic).is_err() || magic != GZIP_MAGIC {
+        return Ok(false);
+    }
+
+    let mut tar = GzDecoder::new(File::open(archive)?);
+    let mut header = [0; 512];
+    loop {
+        tar.read_exact(&mut header)?;
+        // The archive ends with zero blocks.
+        if header.iter().all(|&byte| byte == 0) {
+            break;
+        }
+
+        let size = tar_size(&header)?;
+        let padded = size.div_ceil(512) * 512;
+        let name = tar_name(&header);
+        // Regular files only, `0` or the old NUL type.
+        let regular = header[156] == b'0' || header[156] == 0;
+        if regular && Path::new(&name).file_name() == Some(member.as_ref()) {
+            let mut out = File::create(to)?;
+            let copied = io::copy(&mut (&mut tar).take(size), &mut out)?;
+            if copied != size {
+                return Err(Error::InternalError(format!(
+                    "{} is truncated in {}",
+                    member,
+                    archive.display()
+                )));
+            }
+            out.sync_all()?;
+            return Ok(true);
+        }
+        io::copy(&mut (&mut tar).take(padded), &mut io::sink())?;
+    }
+
+    Err(Error::InternalError(format!(
+        "{} has no {}",
+        archive.display(),
+        member
+    )))
+}
+
+/// Path of a tar entry, with the ustar prefix if there is one.
+fn tar_name(header: &[u8; 512]) -> String {
+    let field = |bytes: &[u8]| {
+        let end = bytes
+            .iter()
+            .position(|&byte| byte == 0)
+            .unwrap_or(bytes.len());
+        String::from_utf8_lossy(&bytes[..end]).into_owned()
+    };
+
+    let name = field(&header[..100]);
+    let prefix = if &header[257..262] == b"ustar" {
+        field(&header[345..500])
+    } else {
+        String::new()
+    };
+    if prefix.is_empty() {
+        name
+    } else {
+        format!("{}/{}", prefix, name)
+    }
+}
+
+/// Size of a tar entry, stored as octal text.
+fn tar_size(header: &[u8; 512]) -> Result<u64, Error> {
+    let text = String::from_utf8_lossy(&header[124..136]);
+    let text = text.trim_matches(|c: char| c == '\0' || c == ' ');
+    u64::from_str_radix(text, 8)
+        .map_err(|_| Error::InternalError(format!("invalid tar entry size {:?}", text)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, BufReader};
+    use tokio::net::TcpListener;
+
+    const BODY: &[u8] = b"0123456789";
+
+    fn sha256(bytes: &[u8]) -> String {
+        to_hex(ring::digest::digest(&SHA256, bytes).as_ref())
+    }
+
+    /// Answers each connection with the next of `responses`, returning the
+    /// request heads it got.
+    async fn serve(listener: TcpListener, responses: Vec<Vec<u8>>) -> Vec<String> {
+        let mut requests = Vec::new();
+        for response in responses {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            let mut head = String::new();
+            while stream.read_line(&mut head).await.unwrap() > 2 {}
+            stream.get_mut().write_all(&response).await.unwrap();
+            stream.get_mut().shutdown().await.unwrap();
+            requests.push(head.to_lowercase());
+        }
+        requests
+    }
+
+    #[tokio::test]
+    async fn cut_off_downloads_resume() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/db", listener.local_addr().unwrap());
+        // The first response ends halfway through its body.
+        let cut_off =
+            b"HTTP/1.1 200 OK\r\ncontent-length: 10\r\netag: \"v1\"\r\n\r\n01234".to_vec();
+        let rest = b"HTTP/1.1 206 Partial Content\r\ncontent-length: 5\r\n\
+            content-range: bytes 5-9/10\r\netag: \"v1\"\r\n\r\n56789"
+            .to_vec();
+        let server = tokio::spawn(serve(listener, vec![cut_off, rest]));
+
+        let dir = std::env::temp_dir().join(format!("raigeki-download-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let target = dir.join("db");
+        let mut settings = DownloadSettings::new(vec![url]);
+        settings.sha256 = Some(sha256(BODY));
+        settings.retries = 1;
+        settings.backoff = Duration::from_millis(10);
+
+        let fetched = download(&settings, &target).await.unwrap();
+        let Fetched::Downloaded(staged) = fetched else {
+            panic!("nothing downloaded");
+        };
+        staged.commit().unwrap();
+        let content = fs::read(&target).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+        assert_eq!(content, BODY);
+
+        let requests = server.await.unwrap();
+        assert!(!requests[0].contains("range:"));
+        assert!(requests[1].contains("range: bytes=5-"));
+        assert!(requests[1].contains("if-range: \"v1\""));
+    }
 }
@@ -0,0 +1,158 @@
+//! Throughput of the session data path: the old per-chunk `select!` loop
+//! against the buffered relay and the splice relay.
+//!
+//! Every iteration pushes `PAYLOAD` bytes from a client socket through the
+//! proxy pair of sockets into a backend that discards them, all over
+//! loopback TCP.
+
+use std::future::pending;
+use std::time::Duration;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use prometheus::{CounterVec, IntCounter, Opts};
+use raigeki::relay::{self, RelayOptions};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::Runtime;
+use tokio::select;
+
+const PAYLOAD: usize = 64 * 1024 * 1024;
+
+#[derive(Clone, Copy)]
+enum Path {
+    Legacy,
+    Buffered,
+    #[cfg(target_os = "linux")]
+    Splice,
+}
+
+/// The pre-redesign loop: 1 KiB buffers, a flush after every read and a
+/// labelled counter bump per chunk.
+async fn legacy(
+    client: &mut TcpStream,
+    backend: &mut TcpStream,
+    per_ip: &CounterVec,
+    total: &IntCounter,
+) {
+    let mut buf_client = vec![0; 1024];
+    let mut buf_backend = vec![0; 1024];
+
+    loop {
+        select! {
+            result = client.read(&mut buf_client) => match result {
+                Ok(n) if n > 0 => {
+                    backend.write_all(&buf_client[0..n]).await.unwrap();
+                    backend.flush().await.unwrap();
+                    total.inc_by(n as u64);
+                    per_ip.with_label_values(&["127.0.0.1"]).inc();
+                }
+                _ => return,
+            },
+            result = backend.read(&mut buf_backend) => match result {
+                Ok(n) if n > 0 => {
+                    client.write_all(&buf_backend[0..n]).await.unwrap();
+                    client.flush().await.unwrap();
+                }
+                _ => return,
+            },
+        }
+    }
+}
+
+async fn pair(listener: &TcpListener) -> (TcpStream, TcpStream) {
+    let addr = listener.local_addr().unwrap();
+    let (connected, accepted) = tokio::join!(TcpStream::connect(addr), listener.accept());
+    (connected.unwrap(), accepted.unwrap().0)
+}
+
+async fn run(path: Path, front: &TcpListener, back: &TcpListener) {
+    let (mut client, mut proxy_client) = pair(front).await;
+    let (mut proxy_backend, mut backend) = pair(back).await;
+
+    let proxy = tokio::spawn(async move {
+        match path {
+            Path::Legacy => {
+                let per_ip =
+                    CounterVec::new(Opts::new("request_per_ip", "bench"), &["ip"]).unwrap();
+                let total = IntCounter::new("incoming_bytes_total", "bench").unwrap();
+                legacy(&mut proxy_client, &mut proxy_backend, &per_ip, &total).await;
+            }
+            Path::Buffered => {
+                relay::relay(
+                    &mut proxy_client,
+                    &mut proxy_backend,
+                    &RelayOptions::default(),
+                    &mut (),
+                    pending::<()>(),
+                )
+                .await
+                .unwrap();
+            }
+            #[cfg(target_os = "linux")]
+            Path::Splice => {
+                use std::os::fd::AsRawFd;
+
+                relay::splice::relay(
+                    proxy_client.as_raw_fd(),
+                    proxy_backend.as_raw_fd(),
+                    &RelayOptions::default(),
+                    &mut (),
+                    pending::<()>(),
+                )
+                .await
+                .unwrap();
+            }
+        }
+    });
+
+    let sink = tokio::spawn(async move {
+        let mut buf = vec![0; 256 * 1024];
+        let mut received = 0;
+        while received < PAYLOAD {
+            match backend.read(&mut buf).await.unwrap() {
+                0 => break,
+                n => received += n,
+            }
+        }
+        assert_eq!(received, PAYLOAD);
+    });
+
+    let chunk = vec![0xAB; 256 * 1024];
+    for _ in 0..PAYLOAD / chunk.len() {
+        client.write_all(&chunk).await.unwrap();
+    }
+
+    sink.await.unwrap();
+    drop(client);
+    proxy.await.unwrap();
+}
+
+fn bench_relay(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let (front, back) = rt.block_on(async {
+        (
+            TcpListener::bind("127.0.0.1:0").await.unwrap(),
+            TcpListener::bind("127.0.0.1:0").await.unwrap(),
+        )
+    });
+
+    let mut group = c.benchmark_group("relay");
+    group.throughput(Throughput::Bytes(PAYLOAD as u64));
+    group.sample_size(10);
+    group.measurement_time(Duration::from_secs(10));
+
+    let mut paths = vec![("legacy", Path::Legacy), ("buffered", Path::Buffered)];
+    #[cfg(target_os = "linux")]
+    paths.push(("splice", Path::Splice));
+
+    for (name, path) in paths {
+        group.bench_with_input(BenchmarkId::from_parameter(name), &path, |b, &path| {
+            b.iter(|| rt.block_on(run(path, &front, &back)));
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_relay);
+criterion_main!(benches);
@@ -1 +1,5 @@
-pub mod pi;
\ No newline at end of file
+pub mod net;
+pub mod pi;
+pub mod prefix;
+pub mod relay;
+pub mod topk;
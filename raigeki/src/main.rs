@@ -16,14 +16,27 @@
 static GLOBAL: jemallocator::Jemalloc = jemallocator::Jemalloc;
 
 use anyhow::{Context, Result};
-use log::info;
+use log::{info, warn};
 use pingora::prelude::Opt;
 use pingora::protocols::TcpKeepalive;
 use pingora::server::Server;
-use pingora::services::background::background_service;
+use pingora::services::background::{background_service, GenBackgroundService};
 use pingora::services::{listening::Service as ListeningService, Service};
 
+use service::admin::{admin_service, AdminApp};
+use service::admission::Admission;
+use service::ban_store::{self, SnapshotBanStore};
+use service::bedrock::BedrockService;
+use service::blocklist::Blocklists;
+use service::cluster::Cluster;
+use service::events;
+use service::feeds::Feeds;
 use service::geoip::download_ddbm;
+use service::heavy_hitters::HeavyHitters;
+use service::honeypot::{honeypot_service, HoneypotApp};
+use service::limits::ConnectionTracker;
+use service::shaping::Shaper;
+use service::whitelist::Whitelist;
 
 use std::net::SocketAddr;
 use std::sync::Arc;
@@ -32,25 +45,67 @@ use std::time::Duration;
 mod service;
 mod settings;
 
+/// pingora's grace period when `grace_period_seconds` is not configured.
+const DEFAULT_GRACE_PERIOD: u64 = 60 * 5;
+
 fn main() -> Result<()> {
     env_logger::init();
 
     info!("init service");
 
     let mut server = Server::new(Some(Opt::parse_args())).context("init server")?;
-    let settings = settings::Settings::new();
-    let memcache_client = memcache::connect(settings.memcached_addrs).context("init memcached")?;
+    let mut settings = settings::Settings::new();
 
-    if settings.auto_mmdb {
-        download_ddbm(&settings.mmdb_asn, &settings.mmdb_city).context("download MMDB")?;
+    // Sessions must be cut off with a message before pingora tears the
+    // runtimes down at the end of its grace period.
+    let grace_period = Duration::from_secs(
+        server
+            .configuration
+            .grace_period_seconds
+            .unwrap_or(DEFAULT_GRACE_PERIOD),
+    );
+    if settings.shutdown.drain >= grace_period {
+        let drain = grace_period.saturating_sub(Duration::from_secs(1));
+        warn!(
+            "SHUTDOWN_DRAIN_PERIOD does not fit into grace_period_seconds, using {}s",
+            drain.as_secs()
+        );
+        settings.shutdown.drain = drain;
     }
 
-    let geoip_service = Arc::new(service::geoip::GeoIPService::new(
-        settings.mmdb_asn,
-        settings.mmdb_city,
-        settings.blocked_asn,
-        settings.blocked_country,
+    events::init(settings.events.sink.clone(), settings.events.queue);
+
+    let snapshots = Arc::new(SnapshotBanStore::new(
+        ban_store::connect(&settings.ban_store)?,
+        settings.ban_store.snapshot.clone(),
     ));
+    let ban_store = Arc::new(Cluster::new(snapshots.clone(), settings.cluster.clone()));
+    let strikes = Arc::new(settings.strikes.clone());
+    let whitelist = Arc::new(Whitelist::new(
+        settings.whitelist.clone(),
+        ban_store.clone(),
+    ));
+    let blocklists = Arc::new(Blocklists::new(settings.blocklists.clone()));
+    let feeds = Arc::new(Feeds::new(settings.feeds.clone()));
+
+    if settings.auto_mmdb {
+        // The files from an earlier run still do, if there are any.
+        if let Err(e) = download_ddbm(
+            &settings.mmdb_asn,
+            &settings.mmdb_city,
+            &settings.mmdb_refresh,
+        ) {
+            warn!("Failed to download MMDB, using the current files: {}", e);
+        }
+    }
+
+    let geoip_service = service::geoip::GeoIPService::new(
+        &settings.mmdb_asn,
+        &settings.mmdb_city,
+        Vec::new(),
+        Vec::new(),
+    )
+    .context("open MMDB")?;
 
     let mut options = pingora::listeners::TcpSocketOptions::default();
     options.tcp_fastopen = Some(10);
@@ -61,30 +116,173 @@ fn main() -> Result<()> {
         user_timeout: Duration::from_secs(10),
     });
 
-    let forward_app = service::forward::ForwardApp::new(
-        format!("{}:{}", settings.outbound_ip, settings.outbound_port)
+    let connection_tracker = ConnectionTracker::new(settings.limits);
+    let shaper = Shaper::new(settings.bandwidth);
+    let heavy_hitters = Arc::new(HeavyHitters::new(
+        settings.heavy_hitters,
+        settings.limits.subnet_prefix,
+    ));
+
+    let mut services: Vec<Box<dyn Service>> = Vec::new();
+
+    for listener in &settings.listeners {
+        let outbound = format!("{}:{}", listener.outbound_ip, listener.outbound_port);
+        let mut backends = vec![outbound
             .parse::<SocketAddr>()
-            .context("Failed to parse outbound address")?,
-        geoip_service,
-        settings.rate_limit,
-        settings.connect_rate_limit,
-        memcache_client,
-        settings.haproxy,
-    );
+            .with_context(|| format!("Failed to parse outbound address {}", outbound))?];
+        for addr in &listener.outbound_fallback_addrs {
+            backends.push(
+                addr.parse::<SocketAddr>()
+                    .with_context(|| format!("Failed to parse fallback address {}", addr))?,
+            );
+        }
 
-    let mut forward_service = service::forward::forward_service(forward_app);
-    forward_service.add_tcp(&format!("{}:{}", settings.l4_ip, settings.l4_port));
+        let forward_app = service::forward::ForwardApp::new(
+            listener,
+            backends,
+            Admission::new(
+                Arc::new(geoip_service.with_blacklists(
+                    listener.blocked_asn.clone(),
+                    listener.blocked_country.clone(),
+                )),
+                ban_store.clone(),
+                strikes.clone(),
+                whitelist.clone(),
+                blocklists.clone(),
+                feeds.clone(),
+            ),
+            connection_tracker.clone(),
+            shaper.clone(),
+            heavy_hitters.clone(),
+            &settings,
+        );
+
+        let mut forward_service = service::forward::forward_service(forward_app);
+        forward_service.add_tcp_with_settings(
+            &format!("{}:{}", listener.l4_ip, listener.l4_port),
+            listener.socket_options.clone(),
+        );
+        info!(
+            "listener {} on {}:{}",
+            listener.name, listener.l4_ip, listener.l4_port
+        );
+
+        services.push(Box::new(forward_service));
+    }
+
+    if let Some(bedrock) = settings.bedrock.take() {
+        let backend = bedrock
+            .outbound_addr
+            .parse::<SocketAddr>()
+            .with_context(|| {
+                format!("Failed to parse Bedrock address {}", bedrock.outbound_addr)
+            })?;
+        let admission = Admission::new(
+            Arc::new(
+                geoip_service
+                    .with_blacklists(bedrock.blocked_asn.clone(), bedrock.blocked_country.clone()),
+            ),
+            ban_store.clone(),
+            strikes.clone(),
+            whitelist.clone(),
+            blocklists.clone(),
+            feeds.clone(),
+        );
+
+        services.push(Box::new(background_service(
+            "bedrock",
+            BedrockService::new(bedrock, backend, admission),
+        )));
+    }
+
+    if !settings.honeypot.ports.is_empty() {
+        let mut honeypot = settings.honeypot.clone();
+        honeypot.ports.retain(|port| {
+            let taken = settings
+                .listeners
+                .iter()
+                .any(|listener| listener.l4_port == *port);
+            if taken {
+                warn!("Honeypot port {} is used by a listener, skipping", port);
+            }
+            !taken
+        });
+
+        let ip = honeypot.ip.clone();
+        let ports = honeypot.ports.clone();
+        let admission = Admission::new(
+            Arc::new(geoip_service.with_blacklists(Vec::new(), Vec::new())),
+            ban_store.clone(),
+            strikes.clone(),
+            whitelist.clone(),
+            blocklists.clone(),
+            feeds.clone(),
+        );
+        let mut honeypot_service =
+            honeypot_service(HoneypotApp::new(honeypot, admission, settings.timeouts));
+        for port in &ports {
+            honeypot_service.add_tcp(&format!("{}:{}", ip, port));
+        }
+        info!("honeypot on {} ports {:?}", ip, ports);
+        services.push(Box::new(honeypot_service));
+    }
 
     let mut prometheus_service_http = ListeningService::prometheus_http_service();
-    prometheus_service_http.add_tcp_with_settings("0.0.0.0:6150", options);
+    prometheus_service_http.add_tcp_with_settings(&settings.prometheus_addr, options);
 
     let background_service = background_service("metrics", service::stats::ExportService::new());
 
-    let services: Vec<Box<dyn Service>> = vec![
-        Box::new(forward_service),
-        Box::new(prometheus_service_http),
-        Box::new(background_service),
-    ];
+    services.push(Box::new(prometheus_service_http));
+    services.push(Box::new(background_service));
+    services.push(Box::new(GenBackgroundService::new(
+        "BG geoip".to_string(),
+        Arc::new(geoip_service.refresher(settings.mmdb_refresh.clone())),
+    )));
+    services.push(Box::new(GenBackgroundService::new(
+        "BG heavy hitters".to_string(),
+        heavy_hitters.clone(),
+    )));
+    services.push(Box::new(GenBackgroundService::new(
+        "BG ban snapshots".to_string(),
+        snapshots.clone(),
+    )));
+    services.push(Box::new(GenBackgroundService::new(
+        "BG cluster".to_string(),
+        ban_store.clone(),
+    )));
+    services.push(Box::new(GenBackgroundService::new(
+        "BG whitelist".to_string(),
+        whitelist.clone(),
+    )));
+    services.push(Box::new(GenBackgroundService::new(
+        "BG blocklists".to_string(),
+        blocklists.clone(),
+    )));
+    services.push(Box::new(GenBackgroundService::new(
+        "BG feeds".to_string(),
+        feeds.clone(),
+    )));
+
+    if let Some(addr) = &settings.admin.addr {
+        let mut admin_service = admin_service(AdminApp::new(
+            settings.admin.token.clone(),
+            heavy_hitters.clone(),
+            ban_store.clone(),
+            snapshots.clone(),
+            whitelist.clone(),
+            blocklists.clone(),
+            feeds.clone(),
+        ));
+        admin_service.add_tcp(addr);
+        info!("admin endpoint on {}", addr);
+        if settings.admin.token.is_none() {
+            warn!(
+                "ADMIN_TOKEN not set, anyone who reaches {} can read the bans, changes are refused",
+                addr
+            );
+        }
+        services.push(Box::new(admin_service));
+    }
 
     info!("service started");
 
@@ -0,0 +1,42 @@
+//! Address helpers shared by the connection filters.
+
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+
+/// Maps IPv4-mapped IPv6 addresses (`::ffff:a.b.c.d`) back to plain IPv4, so
+/// a dual-stack listener keys every client the same way.
+pub fn normalize(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        IpAddr::V4(_) => ip,
+    }
+}
+
+/// Prefix lengths used to group addresses into subnets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SubnetPrefix {
+    v4: u8,
+    v6: u8,
+}
+
+impl SubnetPrefix {
+    /// Builds a prefix, clamping the lengths to the address widths.
+    pub fn new(v4: u8, v6: u8) -> Self {
+        SubnetPrefix {
+            v4: v4.min(32),
+            v6: v6.min(128),
+        }
+    }
+
+    /// The network `ip` belongs to.
+    pub fn subnet(&self, ip: IpAddr) -> IpNet {
+        let len = match ip {
+            IpAddr::V4(_) => self.v4,
+            IpAddr::V6(_) => self.v6,
+        };
+
+        // The lengths are clamped in `new`, so this cannot fail.
+        IpNet::new(ip, len).unwrap().trunc()
+    }
+}
@@ -0,0 +1,247 @@
+use raigeki_error::{Error, Timeout};
+use raigeki_mcproto::handshake::{HandshakePacket, NextState};
+use raigeki_mcproto::login::LoginStartPacket;
+use raigeki_mcproto::{split_frame, PacketError, MAX_PRE_PLAY_FRAME};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::time::{timeout_at, Instant};
+
+use crate::relay::BUF_SIZE;
+
+/// First byte of the pre-netty server list ping.
+const LEGACY_PING: u8 = 0xFE;
+
+/// Points in time by which each stage of the opening exchange must complete.
+#[derive(Debug, Clone, Copy)]
+pub struct Deadlines {
+    pub first_byte: Instant,
+    pub handshake: Instant,
+    pub login_start: Instant,
+}
+
+/// What the client sent before the session is handed to the relay.
+#[derive(Debug)]
+pub struct Opening {
+    /// `None` for a legacy server list ping.
+    pub handshake: Option<HandshakePacket>,
+    pub login_start: Option<LoginStartPacket>,
+    /// Every byte read from the client so far, to be replayed to the backend.
+    pub buffered: Vec<u8>,
+}
+
+/// Reads the handshake and, for login attempts, the Login Start packet.
+///
+/// Reads always offer at least [`BUF_SIZE`] bytes of spare room, so pingora's
+/// stream buffer is bypassed and nothing is left behind in userspace once
+/// this returns.
+pub async fn read_opening<R>(io: &mut R, deadlines: &Deadlines) -> Result<Opening, Error>
+where
+    R: AsyncRead + Unpin + ?Sized,
+{
+    let mut buffered = Vec::new();
+
+    fill(io, &mut buffered, deadlines.first_byte, Timeout::FirstByte).await?;
+
+    if buffered[0] == LEGACY_PING {
+        return Ok(Opening {
+            handshake: None,
+            login_start: None,
+            buffered,
+        });
+    }
+
+    let (handshake, consumed) = loop {
+        if let Some((body, len)) = split_frame(&buffered, MAX_PRE_PLAY_FRAME).map_err(violation)? {
+            break (HandshakePacket::deserialize(body).map_err(violation)?, len);
+        }
+        fill(io, &mut buffered, deadlines.handshake, Timeout::Handshake).await?;
+    };
+
+    if handshake.next_state == NextState::Status {
+        return Ok(Opening {
+            handshake: Some(handshake),
+            login_start: None,
+            buffered,
+        });
+    }
+
+    let login_start = loop {
+        if let Some((body, _)) =
+            split_frame(&buffered[consumed..], MAX_PRE_PLAY_FRAME).map_err(violation)?
+        {
+            break LoginStartPacket::deserialize(body).map_err(violation)?;
+        }
+        fill(
+            io,
+            &mut buffered,
+            deadlines.login_start,
+            Timeout::LoginStart,
+        )
+        .await?;
+    };
+
+    Ok(Opening {
+        handshake: Some(handshake),
+        login_start: Some(login_start),
+        buffered,
+    })
+}
+
+async fn fill<R>(
+    io: &mut R,
+    buf: &mut Vec<u8>,
+    deadline: Instant,
+    stage: Timeout,
+) -> Result<(), Error>
+where
+    R: AsyncRead + Unpin + ?Sized,
+{
+    buf.reserve(BUF_SIZE);
+    match timeout_at(deadline, io.read_buf(buf)).await {
+        Err(_) => Err(Error::Timeout(stage)),
+        Ok(Ok(0)) => Err(Error::InvalidConnection),
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(e)) => Err(Error::IOError(e)),
+    }
+}
+
+fn violation(e: PacketError) -> Error {
+    Error::ProtocolViolation(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use std::time::Duration;
+
+    use raigeki_mcproto::{write_string, write_varint};
+    use tokio::io::{duplex, AsyncWriteExt, ReadBuf};
+
+    use super::*;
+
+    /// Hands out its bytes one at a time.
+    struct Trickle(Vec<u8>);
+
+    impl AsyncRead for Trickle {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            if !self.0.is_empty() {
+                buf.put_slice(&[self.0.remove(0)]);
+            }
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn deadlines(after: Duration) -> Deadlines {
+        let at = Instant::now() + after;
+        Deadlines {
+            first_byte: at,
+            handshake: at,
+            login_start: at,
+        }
+    }
+
+    fn frame(body: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::new();
+        write_varint(body.len() as i32, &mut frame);
+        frame.extend_from_slice(body);
+        frame
+    }
+
+    fn handshake(next_state: i32) -> Vec<u8> {
+        let mut body = vec![0x00];
+        write_varint(767, &mut body);
+        write_string("localhost", &mut body);
+        body.extend_from_slice(&25565u16.to_be_bytes());
+        write_varint(next_state, &mut body);
+        frame(&body)
+    }
+
+    fn login_start(name: &str) -> Vec<u8> {
+        let mut body = vec![0x00];
+        write_string(name, &mut body);
+        body.extend_from_slice(&[0; 16]);
+        frame(&body)
+    }
+
+    #[tokio::test]
+    async fn reads_a_login_a_byte_at_a_time() {
+        let mut sent = handshake(2);
+        sent.extend_from_slice(&login_start("Notch"));
+
+        let mut io = Trickle(sent.clone());
+        let opening = read_opening(&mut io, &deadlines(Duration::from_secs(5)))
+            .await
+            .unwrap();
+        assert_eq!(opening.handshake.unwrap().next_state, NextState::Login);
+        assert_eq!(opening.login_start.unwrap().name, "Notch");
+        assert_eq!(opening.buffered, sent);
+    }
+
+    #[tokio::test]
+    async fn status_requests_stop_after_the_handshake() {
+        let mut sent = handshake(1);
+        // Status Request, left for the backend.
+        sent.extend_from_slice(&[0x01, 0x00]);
+
+        let opening = read_opening(&mut &sent[..], &deadlines(Duration::from_secs(5)))
+            .await
+            .unwrap();
+        assert_eq!(opening.handshake.unwrap().next_state, NextState::Status);
+        assert!(opening.login_start.is_none());
+        assert_eq!(opening.buffered, sent);
+    }
+
+    #[tokio::test]
+    async fn passes_legacy_pings_through() {
+        let sent = [LEGACY_PING, 0x01, 0xfa];
+        let opening = read_opening(&mut &sent[..], &deadlines(Duration::from_secs(5)))
+            .await
+            .unwrap();
+        assert!(opening.handshake.is_none());
+        assert!(opening.login_start.is_none());
+        assert_eq!(opening.buffered, sent);
+    }
+
+    #[tokio::test]
+    async fn refuses_oversized_frames_from_the_header() {
+        let mut sent = Vec::new();
+        write_varint(MAX_PRE_PLAY_FRAME as i32 + 1, &mut sent);
+        let result = read_opening(&mut &sent[..], &deadlines(Duration::from_secs(5))).await;
+        assert!(matches!(result, Err(Error::ProtocolViolation(_))));
+    }
+
+    #[tokio::test]
+    async fn refuses_invalid_handshakes() {
+        let result = read_opening(&mut &handshake(9)[..], &deadlines(Duration::from_secs(5))).await;
+        assert!(matches!(result, Err(Error::ProtocolViolation(_))));
+    }
+
+    #[tokio::test]
+    async fn closed_connections_are_invalid() {
+        let sent = &handshake(2)[..4];
+        let result = read_opening(&mut &sent[..], &deadlines(Duration::from_secs(5))).await;
+        assert!(matches!(result, Err(Error::InvalidConnection)));
+    }
+
+    #[tokio::test]
+    async fn stalled_clients_time_out_at_each_stage() {
+        // Kept open, so the reads wait instead of seeing the end.
+        let (_client, mut server) = duplex(64);
+        let result = read_opening(&mut server, &deadlines(Duration::from_millis(50))).await;
+        assert!(matches!(result, Err(Error::Timeout(Timeout::FirstByte))));
+
+        let (mut client, mut server) = duplex(64);
+        client.write_all(&handshake(2)[..3]).await.unwrap();
+        let result = read_opening(&mut server, &deadlines(Duration::from_millis(50))).await;
+        assert!(matches!(result, Err(Error::Timeout(Timeout::Handshake))));
+
+        let (mut client, mut server) = duplex(64);
+        client.write_all(&handshake(2)).await.unwrap();
+        let result = read_opening(&mut server, &deadlines(Duration::from_millis(50))).await;
+        assert!(matches!(result, Err(Error::Timeout(Timeout::LoginStart))));
+    }
+}
@@ -0,0 +1,263 @@
+//! IP prefix matching with a binary trie.
+
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+
+/// Marks a missing child. The root is never a child, so index 0 is free.
+const NONE: u32 = 0;
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Node {
+    children: [u32; 2],
+    /// A prefix ends here, so everything below is covered.
+    terminal: bool,
+}
+
+/// Bit trie over one address family, addresses are left-aligned in a u128.
+#[derive(Debug, Clone)]
+struct Trie {
+    nodes: Vec<Node>,
+    /// Prefixes kept.
+    len: usize,
+}
+
+impl Trie {
+    fn new() -> Self {
+        Trie {
+            nodes: vec![Node::default()],
+            len: 0,
+        }
+    }
+
+    /// Does nothing if the prefix is already covered.
+    fn insert(&mut self, bits: u128, len: u8) {
+        let mut at = 0;
+        for depth in 0..len {
+            if self.nodes[at].terminal {
+                return;
+            }
+            let bit = bit(bits, depth);
+            let next = self.nodes[at].children[bit];
+            at = if next == NONE {
+                self.nodes.push(Node::default());
+                let index = self.nodes.len() - 1;
+                self.nodes[at].children[bit] = index as u32;
+                index
+            } else {
+                next as usize
+            };
+        }
+
+        if self.nodes[at].terminal {
+            return;
+        }
+        self.nodes[at].terminal = true;
+        // Longer prefixes under this one are covered now.
+        let covered = self.nodes[at].children.map(|child| self.count(child));
+        self.nodes[at].children = [NONE; 2];
+        self.len = self.len + 1 - covered.iter().sum::<usize>();
+    }
+
+    /// Prefixes ending at or below `at`.
+    fn count(&self, at: u32) -> usize {
+        if at == NONE {
+            return 0;
+        }
+        let node = &self.nodes[at as usize];
+        if node.terminal {
+            return 1;
+        }
+        node.children.iter().map(|&child| self.count(child)).sum()
+    }
+
+    /// Length of the prefix covering `bits`, if any.
+    fn lookup(&self, bits: u128, width: u8) -> Option<u8> {
+        let mut at = 0;
+        for depth in 0..=width {
+            let node = &self.nodes[at];
+            if node.terminal {
+                return Some(depth);
+            }
+            if depth == width {
+                break;
+            }
+            match node.children[bit(bits, depth)] {
+                NONE => return None,
+                next => at = next as usize,
+            }
+        }
+        None
+    }
+}
+
+fn bit(bits: u128, depth: u8) -> usize {
+    ((bits >> (127 - depth)) & 1) as usize
+}
+
+fn bits(addr: IpAddr) -> (u128, u8) {
+    match addr {
+        IpAddr::V4(v4) => ((u32::from(v4) as u128) << 96, 32),
+        IpAddr::V6(v6) => (u128::from(v6), 128),
+    }
+}
+
+/// A set of IPv4 and IPv6 networks answering which one covers an address.
+///
+/// Networks covered by a shorter one are dropped on insert, so overlapping
+/// lists cost no more than their union.
+#[derive(Debug, Clone)]
+pub struct PrefixSet {
+    v4: Trie,
+    v6: Trie,
+}
+
+impl Default for PrefixSet {
+    fn default() -> Self {
+        PrefixSet {
+            v4: Trie::new(),
+            v6: Trie::new(),
+        }
+    }
+}
+
+impl PrefixSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Networks kept, not counting those covered by others.
+    pub fn len(&self) -> usize {
+        self.v4.len + self.v6.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn insert(&mut self, net: IpNet) {
+        let (bits, _) = bits(net.network());
+        match net {
+            IpNet::V4(_) => self.v4.insert(bits, net.prefix_len()),
+            IpNet::V6(_) => self.v6.insert(bits, net.prefix_len()),
+        }
+    }
+
+    /// The network covering `addr`, if any. IPv4-mapped IPv6 addresses, as
+    /// dual-stack sockets report IPv4 peers, are looked up as IPv4.
+    pub fn lookup(&self, addr: IpAddr) -> Option<IpNet> {
+        let addr = addr.to_canonical();
+        let (bits, width) = bits(addr);
+        let trie = match addr {
+            IpAddr::V4(_) => &self.v4,
+            IpAddr::V6(_) => &self.v6,
+        };
+        let len = trie.lookup(bits, width)?;
+        // `len` never exceeds the address width.
+        Some(IpNet::new(addr, len).unwrap().trunc())
+    }
+}
+
+impl FromIterator<IpNet> for PrefixSet {
+    /// Inserts the shortest prefixes first, so covered ones never get nodes.
+    fn from_iter<I: IntoIterator<Item = IpNet>>(iter: I) -> Self {
+        let mut nets: Vec<IpNet> = iter.into_iter().collect();
+        nets.sort_by_key(IpNet::prefix_len);
+
+        let mut set = PrefixSet::new();
+        for net in nets {
+            set.insert(net);
+        }
+        set
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prefixes(nets: &[&str]) -> PrefixSet {
+        nets.iter().map(|net| net.parse().unwrap()).collect()
+    }
+
+    fn lookup(set: &PrefixSet, addr: &str) -> Option<String> {
+        set.lookup(addr.parse().unwrap()).map(|net| net.to_string())
+    }
+
+    #[test]
+    fn finds_the_covering_network() {
+        let set = prefixes(&["192.0.2.0/24", "198.51.100.7/32", "2001:db8::/32"]);
+        assert_eq!(lookup(&set, "192.0.2.200").as_deref(), Some("192.0.2.0/24"));
+        assert_eq!(
+            lookup(&set, "198.51.100.7").as_deref(),
+            Some("198.51.100.7/32")
+        );
+        assert_eq!(
+            lookup(&set, "2001:db8:1::1").as_deref(),
+            Some("2001:db8::/32")
+        );
+        assert_eq!(lookup(&set, "192.0.3.1"), None);
+        assert_eq!(lookup(&set, "198.51.100.8"), None);
+        assert_eq!(lookup(&set, "2001:db9::1"), None);
+    }
+
+    #[test]
+    fn families_are_kept_apart() {
+        // 192.0.2.0 and ::c000:200 share their low bits.
+        assert_eq!(lookup(&prefixes(&["192.0.2.0/24"]), "::c000:201"), None);
+        assert_eq!(lookup(&prefixes(&["::/0"]), "192.0.2.1"), None);
+    }
+
+    #[test]
+    fn default_routes_cover_their_family() {
+        let set = prefixes(&["0.0.0.0/0"]);
+        assert_eq!(set.len(), 1);
+        assert_eq!(lookup(&set, "203.0.113.9").as_deref(), Some("0.0.0.0/0"));
+        assert_eq!(
+            lookup(&set, "255.255.255.255").as_deref(),
+            Some("0.0.0.0/0")
+        );
+        assert_eq!(lookup(&set, "2001:db8::1"), None);
+        assert_eq!(
+            lookup(&prefixes(&["::/0"]), "2001:db8::1").as_deref(),
+            Some("::/0")
+        );
+    }
+
+    #[test]
+    fn mapped_addresses_match_ipv4_networks() {
+        let set = prefixes(&["192.0.2.0/24"]);
+        assert_eq!(
+            lookup(&set, "::ffff:192.0.2.1").as_deref(),
+            Some("192.0.2.0/24")
+        );
+        assert_eq!(lookup(&set, "::ffff:192.0.3.1"), None);
+    }
+
+    #[test]
+    fn covered_networks_are_dropped_in_any_order() {
+        let mut shorter_first = PrefixSet::new();
+        for net in ["10.0.0.0/8", "10.1.0.0/16", "10.1.2.3/32"] {
+            shorter_first.insert(net.parse().unwrap());
+        }
+        let mut longer_first = PrefixSet::new();
+        for net in ["10.1.2.3/32", "10.1.0.0/16", "10.0.0.0/8"] {
+            longer_first.insert(net.parse().unwrap());
+        }
+
+        for set in [&shorter_first, &longer_first] {
+            assert_eq!(set.len(), 1);
+            assert_eq!(lookup(set, "10.1.2.3").as_deref(), Some("10.0.0.0/8"));
+            assert_eq!(lookup(set, "10.200.0.1").as_deref(), Some("10.0.0.0/8"));
+            assert_eq!(lookup(set, "11.0.0.1"), None);
+        }
+    }
+
+    #[test]
+    fn duplicates_count_once() {
+        let set = prefixes(&["192.0.2.0/24", "192.0.2.0/24", "192.0.2.77/24"]);
+        assert_eq!(set.len(), 1);
+        assert!(!set.is_empty());
+        assert!(PrefixSet::new().is_empty());
+    }
+}
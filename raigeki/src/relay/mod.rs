@@ -0,0 +1,293 @@
+//! Bidirectional byte relay between a client and its backend.
+//!
+//! Both directions share one task and are driven from a single poll loop, so a
+//! session costs one wakeup per readiness event instead of a `select!` round
+//! trip per chunk. Reads go into large reusable buffers, writes are flushed
+//! only when the reader runs dry, and EOF on one side is propagated as a
+//! half-close to the other side.
+
+#[cfg(target_os = "linux")]
+pub mod splice;
+
+use std::future::{poll_fn, Future};
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{sleep, sleep_until, Instant, Sleep};
+
+/// Size of each relay buffer.
+///
+/// Must not be smaller than pingora's 64 KiB stream read buffer: reads of at
+/// least that size bypass the `BufStream` entirely, which keeps the kernel
+/// socket as the only place unread client bytes can live. The splice path
+/// relies on that.
+pub const BUF_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Client to backend.
+    Upstream,
+    /// Backend to client.
+    Downstream,
+}
+
+/// Decision returned by a [`RelayHook`] after every transferred chunk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Continue,
+    /// Forward the chunk, then pause reading in this direction.
+    Throttle(Duration),
+    /// Drop the chunk and tear the session down.
+    Abort,
+}
+
+/// Per-session observer of relayed traffic.
+pub trait RelayHook {
+    fn on_transfer(&mut self, direction: Direction, bytes: usize) -> Verdict;
+}
+
+/// No-op hook, useful for benchmarks.
+impl RelayHook for () {
+    fn on_transfer(&mut self, _direction: Direction, _bytes: usize) -> Verdict {
+        Verdict::Continue
+    }
+}
+
+/// Session-wide limits enforced by the relay.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RelayOptions {
+    /// End the session after this long without a byte in either direction.
+    pub idle_timeout: Option<Duration>,
+    /// End the session at this point no matter what.
+    pub deadline: Option<Instant>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelayEnd {
+    /// Both sides reached EOF.
+    Closed,
+    /// The hook returned [`Verdict::Abort`].
+    Aborted,
+    /// The stop future resolved.
+    Stopped,
+    /// No bytes moved for [`RelayOptions::idle_timeout`].
+    IdleTimeout,
+    /// [`RelayOptions::deadline`] passed.
+    Expired,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Flow {
+    Done,
+    Aborted,
+}
+
+struct Transfer {
+    buf: Box<[u8]>,
+    pos: usize,
+    cap: usize,
+    read_done: bool,
+    need_flush: bool,
+    throttle: Option<Pin<Box<Sleep>>>,
+}
+
+impl Transfer {
+    fn new() -> Self {
+        Transfer {
+            buf: vec![0; BUF_SIZE].into_boxed_slice(),
+            pos: 0,
+            cap: 0,
+            read_done: false,
+            need_flush: false,
+            throttle: None,
+        }
+    }
+
+    fn poll_copy<R, W, H>(
+        &mut self,
+        cx: &mut Context<'_>,
+        direction: Direction,
+        hook: &mut H,
+        progressed: &mut bool,
+        mut reader: Pin<&mut R>,
+        mut writer: Pin<&mut W>,
+    ) -> Poll<io::Result<Flow>>
+    where
+        R: AsyncRead + ?Sized,
+        W: AsyncWrite + ?Sized,
+        H: RelayHook + ?Sized,
+    {
+        loop {
+            if self.pos == self.cap && !self.read_done {
+                if let Some(throttle) = self.throttle.as_mut() {
+                    if throttle.as_mut().poll(cx).is_pending() {
+                        return self.poll_idle_flush(cx, writer);
+                    }
+                    self.throttle = None;
+                }
+
+                let mut read_buf = ReadBuf::new(&mut self.buf);
+                match reader.as_mut().poll_read(cx, &mut read_buf) {
+                    Poll::Pending => return self.poll_idle_flush(cx, writer),
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                    Poll::Ready(Ok(())) => {}
+                }
+
+                let n = read_buf.filled().len();
+                if n == 0 {
+                    self.read_done = true;
+                } else {
+                    *progressed = true;
+                    match hook.on_transfer(direction, n) {
+                        Verdict::Continue => {}
+                        Verdict::Throttle(delay) => self.throttle = Some(Box::pin(sleep(delay))),
+                        Verdict::Abort => return Poll::Ready(Ok(Flow::Aborted)),
+                    }
+                    self.pos = 0;
+                    self.cap = n;
+                }
+            }
+
+            while self.pos < self.cap {
+                let n = ready!(writer
+                    .as_mut()
+                    .poll_write(cx, &self.buf[self.pos..self.cap]))?;
+                if n == 0 {
+                    return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+                }
+                self.pos += n;
+                self.need_flush = true;
+            }
+
+            if self.read_done {
+                ready!(writer.as_mut().poll_flush(cx))?;
+                ready!(writer.as_mut().poll_shutdown(cx))?;
+                return Poll::Ready(Ok(Flow::Done));
+            }
+        }
+    }
+
+    fn poll_idle_flush<W>(
+        &mut self,
+        cx: &mut Context<'_>,
+        writer: Pin<&mut W>,
+    ) -> Poll<io::Result<Flow>>
+    where
+        W: AsyncWrite + ?Sized,
+    {
+        if self.need_flush {
+            ready!(writer.poll_flush(cx))?;
+            self.need_flush = false;
+        }
+        Poll::Pending
+    }
+}
+
+/// Drives both legs of a session until they finish, one of them is aborted,
+/// a limit from `options` is hit or `stop` resolves.
+async fn drive<F, S>(options: &RelayOptions, stop: S, mut poll_leg: F) -> io::Result<RelayEnd>
+where
+    F: FnMut(&mut Context<'_>, Direction, &mut bool) -> Poll<io::Result<Flow>>,
+    S: Future,
+{
+    let mut stop = std::pin::pin!(stop);
+    // The idle timer is only re-armed when it fires, from the time of the last
+    // transfer, to keep timer wheel updates off the per-chunk path.
+    let mut idle = options
+        .idle_timeout
+        .map(|timeout| (timeout, Instant::now(), Box::pin(sleep(timeout))));
+    let mut deadline = options.deadline.map(|at| Box::pin(sleep_until(at)));
+    let mut upstream_done = false;
+    let mut downstream_done = false;
+
+    poll_fn(|cx| {
+        if stop.as_mut().poll(cx).is_ready() {
+            return Poll::Ready(Ok(RelayEnd::Stopped));
+        }
+
+        let mut progressed = false;
+
+        for (direction, done) in [
+            (Direction::Upstream, &mut upstream_done),
+            (Direction::Downstream, &mut downstream_done),
+        ] {
+            if *done {
+                continue;
+            }
+            match poll_leg(cx, direction, &mut progressed) {
+                Poll::Ready(Ok(Flow::Done)) => *done = true,
+                Poll::Ready(Ok(Flow::Aborted)) => return Poll::Ready(Ok(RelayEnd::Aborted)),
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => {}
+            }
+        }
+
+        if upstream_done && downstream_done {
+            return Poll::Ready(Ok(RelayEnd::Closed));
+        }
+
+        if let Some((timeout, last_transfer, timer)) = idle.as_mut() {
+            if progressed {
+                *last_transfer = Instant::now();
+            }
+            while timer.as_mut().poll(cx).is_ready() {
+                let expires = *last_transfer + *timeout;
+                if expires <= Instant::now() {
+                    return Poll::Ready(Ok(RelayEnd::IdleTimeout));
+                }
+                timer.as_mut().reset(expires);
+            }
+        }
+
+        if let Some(timer) = deadline.as_mut() {
+            if timer.as_mut().poll(cx).is_ready() {
+                return Poll::Ready(Ok(RelayEnd::Expired));
+            }
+        }
+
+        Poll::Pending
+    })
+    .await
+}
+
+/// Copies bytes in both directions between `client` and `backend` through
+/// userspace buffers.
+pub async fn relay<C, B, H, S>(
+    client: &mut C,
+    backend: &mut B,
+    options: &RelayOptions,
+    hook: &mut H,
+    stop: S,
+) -> io::Result<RelayEnd>
+where
+    C: AsyncRead + AsyncWrite + Unpin + ?Sized,
+    B: AsyncRead + AsyncWrite + Unpin + ?Sized,
+    H: RelayHook + ?Sized,
+    S: Future,
+{
+    let mut upstream = Transfer::new();
+    let mut downstream = Transfer::new();
+
+    drive(options, stop, |cx, direction, progressed| match direction {
+        Direction::Upstream => upstream.poll_copy(
+            cx,
+            direction,
+            hook,
+            progressed,
+            Pin::new(&mut *client),
+            Pin::new(&mut *backend),
+        ),
+        Direction::Downstream => downstream.poll_copy(
+            cx,
+            direction,
+            hook,
+            progressed,
+            Pin::new(&mut *backend),
+            Pin::new(&mut *client),
+        ),
+    })
+    .await
+}
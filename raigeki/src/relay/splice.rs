@@ -0,0 +1,177 @@
+//! Zero-copy relay built on `splice(2)`.
+//!
+//! Bytes move socket → pipe → socket inside the kernel and never touch a
+//! userspace buffer. Only usable once the proxy no longer needs to look at
+//! the payload and both ends are plain TCP sockets with nothing buffered in
+//! userspace.
+
+use std::future::Future;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use tokio::io::unix::AsyncFd;
+use tokio::time::{sleep, Sleep};
+
+use super::{drive, Direction, Flow, RelayEnd, RelayHook, RelayOptions, Verdict, BUF_SIZE};
+
+/// One direction of a spliced session: a kernel pipe sitting between the
+/// source and the destination socket.
+struct SpliceTransfer {
+    pipe_rd: OwnedFd,
+    pipe_wr: OwnedFd,
+    in_pipe: usize,
+    read_done: bool,
+    throttle: Option<Pin<Box<Sleep>>>,
+}
+
+impl SpliceTransfer {
+    fn new() -> io::Result<Self> {
+        let mut fds = [0; 2];
+        // SAFETY: `fds` is a valid two element array for pipe2 to fill.
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: pipe2 succeeded, both descriptors are fresh and owned by us.
+        let (pipe_rd, pipe_wr) =
+            unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+
+        Ok(SpliceTransfer {
+            pipe_rd,
+            pipe_wr,
+            in_pipe: 0,
+            read_done: false,
+            throttle: None,
+        })
+    }
+
+    fn poll_splice<H>(
+        &mut self,
+        cx: &mut Context<'_>,
+        direction: Direction,
+        hook: &mut H,
+        progressed: &mut bool,
+        src: &AsyncFd<OwnedFd>,
+        dst: &AsyncFd<OwnedFd>,
+    ) -> Poll<io::Result<Flow>>
+    where
+        H: RelayHook + ?Sized,
+    {
+        loop {
+            if self.in_pipe == 0 && !self.read_done {
+                if let Some(throttle) = self.throttle.as_mut() {
+                    ready!(throttle.as_mut().poll(cx));
+                    self.throttle = None;
+                }
+
+                let mut guard = ready!(src.poll_read_ready(cx))?;
+                let n = match guard
+                    .try_io(|fd| splice(fd.as_raw_fd(), self.pipe_wr.as_raw_fd(), BUF_SIZE))
+                {
+                    Ok(result) => result?,
+                    Err(_would_block) => continue,
+                };
+
+                if n == 0 {
+                    self.read_done = true;
+                } else {
+                    *progressed = true;
+                    match hook.on_transfer(direction, n) {
+                        Verdict::Continue => {}
+                        Verdict::Throttle(delay) => self.throttle = Some(Box::pin(sleep(delay))),
+                        Verdict::Abort => return Poll::Ready(Ok(Flow::Aborted)),
+                    }
+                    self.in_pipe = n;
+                }
+            }
+
+            while self.in_pipe > 0 {
+                let mut guard = ready!(dst.poll_write_ready(cx))?;
+                match guard
+                    .try_io(|fd| splice(self.pipe_rd.as_raw_fd(), fd.as_raw_fd(), self.in_pipe))
+                {
+                    Ok(Ok(0)) => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                    Ok(Ok(n)) => self.in_pipe -= n,
+                    Ok(Err(e)) => return Poll::Ready(Err(e)),
+                    Err(_would_block) => continue,
+                }
+            }
+
+            if self.read_done {
+                // SAFETY: plain syscall on a descriptor we keep open.
+                if unsafe { libc::shutdown(dst.as_raw_fd(), libc::SHUT_WR) } < 0 {
+                    let err = io::Error::last_os_error();
+                    if err.kind() != io::ErrorKind::NotConnected {
+                        return Poll::Ready(Err(err));
+                    }
+                }
+                return Poll::Ready(Ok(Flow::Done));
+            }
+        }
+    }
+}
+
+fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+    // SAFETY: both descriptors are open for the duration of the call and null
+    // offsets are valid for sockets and pipes.
+    let n = unsafe {
+        libc::splice(
+            from,
+            std::ptr::null_mut(),
+            to,
+            std::ptr::null_mut(),
+            len,
+            libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+        )
+    };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(n as usize)
+}
+
+/// Registers a duplicate of `fd` with the reactor, leaving the original
+/// registration of its owner untouched.
+fn register(fd: RawFd) -> io::Result<AsyncFd<OwnedFd>> {
+    // SAFETY: the caller guarantees `fd` stays open while the relay runs.
+    let dup = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) };
+    if dup < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: `dup` is a fresh descriptor owned by us and handed over to the
+    // `AsyncFd`, which is the only one closing it.
+    unsafe { Ok(AsyncFd::register(OwnedFd::from_raw_fd(dup))?) }
+}
+
+/// Relays bytes in both directions between two TCP sockets with `splice(2)`.
+///
+/// The caller must have flushed any userspace write buffers of both sockets
+/// and must not hold unread bytes in userspace read buffers, otherwise they
+/// are silently skipped.
+pub async fn relay<H, S>(
+    client: RawFd,
+    backend: RawFd,
+    options: &RelayOptions,
+    hook: &mut H,
+    stop: S,
+) -> io::Result<RelayEnd>
+where
+    H: RelayHook + ?Sized,
+    S: Future,
+{
+    let client = register(client)?;
+    let backend = register(backend)?;
+    let mut upstream = SpliceTransfer::new()?;
+    let mut downstream = SpliceTransfer::new()?;
+
+    drive(options, stop, |cx, direction, progressed| match direction {
+        Direction::Upstream => {
+            upstream.poll_splice(cx, direction, hook, progressed, &client, &backend)
+        }
+        Direction::Downstream => {
+            downstream.poll_splice(cx, direction, hook, progressed, &backend, &client)
+        }
+    })
+    .await
+}
@@ -0,0 +1,286 @@
+//! Admin HTTP endpoint for inspecting the proxy at runtime.
+
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use http::{header, Method, Response, StatusCode};
+use ipnet::IpNet;
+use pingora::apps::http_app::{HttpServer, ServeHttp};
+use pingora::protocols::http::ServerSession;
+use pingora::services::listening::Service;
+use ring::hmac;
+use ring::rand::SystemRandom;
+use serde_json::{json, Value};
+
+use crate::service::ban_store::{import, unix_now, BanEntry, BanStore, SnapshotBanStore};
+use crate::service::blocklist::Blocklists;
+use crate::service::feeds::Feeds;
+use crate::service::heavy_hitters::HeavyHitters;
+use crate::service::whitelist::{Whitelist, WhitelistEntry};
+
+/// Largest snapshot `POST /snapshot` takes.
+const MAX_IMPORT_BODY: usize = 64 << 20;
+
+/// The admin token, kept as a MAC under a random key so that checking a
+/// guess takes the same time whatever it shares with the token.
+struct Token {
+    key: hmac::Key,
+    tag: hmac::Tag,
+}
+
+impl Token {
+    fn new(token: &str) -> Self {
+        let key = hmac::Key::generate(hmac::HMAC_SHA256, &SystemRandom::new()).unwrap();
+        let tag = hmac::sign(&key, token.as_bytes());
+        Token { key, tag }
+    }
+
+    fn matches(&self, guess: &str) -> bool {
+        hmac::verify(&self.key, guess.as_bytes(), self.tag.as_ref()).is_ok()
+    }
+}
+
+pub struct AdminApp {
+    /// Bearer token required on every request, if set. Without one the
+    /// endpoint is read only.
+    token: Option<Token>,
+    heavy_hitters: Arc<HeavyHitters>,
+    ban_store: Arc<dyn BanStore>,
+    snapshots: Arc<SnapshotBanStore>,
+    whitelist: Arc<Whitelist>,
+    blocklists: Arc<Blocklists>,
+    feeds: Arc<Feeds>,
+}
+
+impl AdminApp {
+    pub fn new(
+        token: Option<String>,
+        heavy_hitters: Arc<HeavyHitters>,
+        ban_store: Arc<dyn BanStore>,
+        snapshots: Arc<SnapshotBanStore>,
+        whitelist: Arc<Whitelist>,
+        blocklists: Arc<Blocklists>,
+        feeds: Arc<Feeds>,
+    ) -> Self {
+        AdminApp {
+            token: token.as_deref().map(Token::new),
+            heavy_hitters,
+            ban_store,
+            snapshots,
+            whitelist,
+            blocklists,
+            feeds,
+        }
+    }
+
+    fn authorized(&self, session: &ServerSession) -> bool {
+        let Some(token) = &self.token else {
+            return true;
+        };
+
+        session
+            .get_header(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|value| token.matches(value))
+    }
+
+    /// `GET /heavy-hitters[?dimension=ip|subnet|asn|country][&limit=N]`
+    fn heavy_hitters(&self, query: &[(&str, &str)]) -> Response<Vec<u8>> {
+        let dimension = param(query, "dimension");
+        let limit = match param(query, "limit").map(str::parse) {
+            Some(Ok(limit)) => Some(limit),
+            Some(Err(_)) => return error(StatusCode::BAD_REQUEST, "invalid limit"),
+            None => None,
+        };
+
+        match self.heavy_hitters.report(dimension, limit) {
+            Some(report) => respond(StatusCode::OK, report),
+            None => error(StatusCode::BAD_REQUEST, "unknown dimension"),
+        }
+    }
+
+    /// `GET /bans?code=APPEAL_CODE` or `GET /bans?key=ADDRESS_OR_SUBNET`
+    async fn bans(&self, query: &[(&str, &str)]) -> Response<Vec<u8>> {
+        let found = match (param(query, "code"), param(query, "key")) {
+            (Some(code), _) => self.ban_store.appeal(code).await,
+            (None, Some(key)) => self
+                .ban_store
+                .entry(key)
+                .await
+                .map(|entry| entry.map(|entry| (key.to_string(), entry))),
+            (None, None) => return error(StatusCode::BAD_REQUEST, "code or key required"),
+        };
+
+        match found {
+            Ok(Some((key, entry))) => respond(StatusCode::OK, ban_report(&key, &entry)),
+            Ok(None) => error(StatusCode::NOT_FOUND, "no such ban"),
+            Err(e) => error(StatusCode::BAD_GATEWAY, &e.to_string()),
+        }
+    }
+
+    /// `GET /whitelist`,
+    /// `POST /whitelist?net=CIDR[&ttl=SECONDS][&note=TEXT]`,
+    /// `DELETE /whitelist?net=CIDR`
+    async fn whitelist(&self, method: &Method, query: &[(&str, &str)]) -> Response<Vec<u8>> {
+        if *method == Method::GET {
+            return respond(StatusCode::OK, self.whitelist.report());
+        }
+
+        let net = match param(query, "net").map(parse_net) {
+            Some(Some(net)) => net,
+            Some(None) => return error(StatusCode::BAD_REQUEST, "invalid net"),
+            None => return error(StatusCode::BAD_REQUEST, "net required"),
+        };
+
+        let result = match *method {
+            Method::POST => {
+                let ttl = match param(query, "ttl").map(str::parse::<u64>) {
+                    Some(Ok(ttl)) => Some(Duration::from_secs(ttl)),
+                    Some(Err(_)) => return error(StatusCode::BAD_REQUEST, "invalid ttl"),
+                    None => None,
+                };
+                let entry = WhitelistEntry {
+                    net,
+                    expires_at: ttl.map_or(0, |ttl| unix_now() + ttl.as_secs()),
+                    note: param(query, "note").map(str::to_string),
+                };
+                self.whitelist.add(entry).await.map(|_| true)
+            }
+            Method::DELETE => self.whitelist.remove(net).await,
+            _ => return error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+        };
+
+        match result {
+            Ok(true) => respond(StatusCode::OK, self.whitelist.report()),
+            Ok(false) => error(StatusCode::NOT_FOUND, "no such entry"),
+            Err(e) => error(StatusCode::BAD_GATEWAY, &e.to_string()),
+        }
+    }
+
+    /// `GET /snapshot` exports the ban store records as JSON lines,
+    /// `POST /snapshot` imports them from the body. Imported records are
+    /// shared with the cluster like any other write.
+    async fn snapshot(&self, method: &Method, session: &mut ServerSession) -> Response<Vec<u8>> {
+        match *method {
+            Method::GET => {
+                let body = match self.snapshots.export().await {
+                    Ok(lines) => lines.into_bytes(),
+                    Err(e) => return error(StatusCode::BAD_GATEWAY, &e.to_string()),
+                };
+                Response::builder()
+                    .status(StatusCode::OK)
+                    .header(header::CONTENT_TYPE, "application/x-ndjson")
+                    .header(header::CONTENT_LENGTH, body.len())
+                    .body(body)
+                    .unwrap()
+            }
+            Method::POST => {
+                let mut body = Vec::new();
+                loop {
+                    match session.read_request_body().await {
+                        Ok(Some(chunk)) => {
+                            if body.len() + chunk.len() > MAX_IMPORT_BODY {
+                                return error(StatusCode::PAYLOAD_TOO_LARGE, "snapshot too large");
+                            }
+                            body.extend_from_slice(&chunk);
+                        }
+                        Ok(None) => break,
+                        Err(e) => return error(StatusCode::BAD_REQUEST, &e.to_string()),
+                    }
+                }
+                let Ok(lines) = String::from_utf8(body) else {
+                    return error(StatusCode::BAD_REQUEST, "body is not UTF-8");
+                };
+
+                match import(&*self.ban_store, &lines).await {
+                    Ok(report) => respond(StatusCode::OK, json!(report)),
+                    Err(e) => error(StatusCode::BAD_GATEWAY, &e.to_string()),
+                }
+            }
+            _ => error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+        }
+    }
+}
+
+#[async_trait]
+impl ServeHttp for AdminApp {
+    async fn response(&self, session: &mut ServerSession) -> Response<Vec<u8>> {
+        if !self.authorized(session) {
+            return error(StatusCode::UNAUTHORIZED, "unauthorized");
+        }
+
+        let method = session.req_header().method.clone();
+        if method != Method::GET && self.token.is_none() {
+            return error(StatusCode::FORBIDDEN, "changes require ADMIN_TOKEN");
+        }
+        let uri = &session.req_header().uri;
+        let query: Vec<(&str, &str)> = uri
+            .query()
+            .unwrap_or_default()
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .collect();
+
+        match uri.path() {
+            "/heavy-hitters" => self.heavy_hitters(&query),
+            "/bans" => self.bans(&query).await,
+            "/whitelist" => self.whitelist(&method, &query).await,
+            "/blocklists" => respond(StatusCode::OK, self.blocklists.report()),
+            "/feeds" => respond(StatusCode::OK, self.feeds.report()),
+            "/snapshot" => self.snapshot(&method, session).await,
+            _ => error(StatusCode::NOT_FOUND, "not found"),
+        }
+    }
+}
+
+pub fn admin_service(app: AdminApp) -> Service<HttpServer<AdminApp>> {
+    Service::new("Raigeki Admin HTTP".to_string(), HttpServer::new_app(app))
+}
+
+fn param<'a>(query: &[(&str, &'a str)], name: &str) -> Option<&'a str> {
+    query
+        .iter()
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| *value)
+}
+
+/// A CIDR range or a single address. Query values arrive percent-encoded,
+/// so the prefix slash may come as `%2F`.
+fn parse_net(value: &str) -> Option<IpNet> {
+    let value = value.replace("%2F", "/").replace("%2f", "/");
+    value
+        .parse()
+        .or_else(|_| value.parse::<IpAddr>().map(IpNet::from))
+        .ok()
+}
+
+fn ban_report(key: &str, entry: &BanEntry) -> Value {
+    json!({
+        "key": key,
+        "status": entry.status.as_str(),
+        "strikes": entry.strikes,
+        "reason": entry.reason.map(|reason| reason.as_str()),
+        "rule": entry.rule,
+        "banned_at": entry.banned_at,
+        "expires_at": entry.expires_at,
+        "remaining_secs": entry.expires_at.saturating_sub(unix_now()),
+        "appeal_code": entry.appeal_code,
+    })
+}
+
+fn respond(status: StatusCode, body: Value) -> Response<Vec<u8>> {
+    let body = body.to_string().into_bytes();
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(header::CONTENT_LENGTH, body.len())
+        .body(body)
+        .unwrap()
+}
+
+fn error(status: StatusCode, message: &str) -> Response<Vec<u8>> {
+    respond(status, json!({ "error": message }))
+}
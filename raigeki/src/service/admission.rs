@@ -0,0 +1,339 @@
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use ipnet::IpNet;
+use log::{debug, error, info, warn};
+use once_cell::sync::Lazy;
+use raigeki_error::{Error, Reason};
+
+use crate::service::ban_store::{unix_now, BanEntry, BanStatus, BanStore, Strikes};
+use crate::service::blocklist::Blocklists;
+use crate::service::cluster::PEERS_UNDER_ATTACK;
+use crate::service::events::matched_rule;
+use crate::service::feeds::Feeds;
+use crate::service::forward::DDOS_MODE;
+use crate::service::geoip::GeoIPService;
+use crate::service::limits::SubnetRateLimit;
+use crate::service::whitelist::Whitelist;
+
+/// Keys with a ban being written, across all listeners.
+static PENDING_BANS: Lazy<Mutex<HashSet<String>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+/// A ban of `key` under way, forgotten on drop.
+struct PendingBan {
+    key: String,
+}
+
+impl PendingBan {
+    /// `None` if a ban of `key` is already under way.
+    fn start(key: &str) -> Option<Self> {
+        PENDING_BANS
+            .lock()
+            .unwrap()
+            .insert(key.to_string())
+            .then(|| PendingBan {
+                key: key.to_string(),
+            })
+    }
+}
+
+impl Drop for PendingBan {
+    fn drop(&mut self) {
+        PENDING_BANS.lock().unwrap().remove(&self.key);
+    }
+}
+
+/// Ban lengths for repeat offenders.
+#[derive(Debug, Clone)]
+pub struct StrikeSettings {
+    /// Ban length for each strike, the last one repeats.
+    pub ladder: Vec<Duration>,
+    /// Time without a ban after which one strike is forgiven.
+    pub decay: Duration,
+}
+
+impl StrikeSettings {
+    fn ban_ttl(&self, level: u32) -> Duration {
+        let rung = (level.max(1) - 1) as usize;
+        self.ladder
+            .get(rung)
+            .or(self.ladder.last())
+            .copied()
+            .unwrap_or(Duration::from_secs(60 * 60))
+    }
+}
+
+/// Ban store and GeoIP checks applied to every new client, whatever the
+/// protocol it speaks.
+pub struct Admission {
+    geoip_service: Arc<GeoIPService>,
+    ban_store: Arc<dyn BanStore>,
+    strikes: Arc<StrikeSettings>,
+    whitelist: Arc<Whitelist>,
+    blocklists: Arc<Blocklists>,
+    feeds: Arc<Feeds>,
+}
+
+impl Admission {
+    pub fn new(
+        geoip_service: Arc<GeoIPService>,
+        ban_store: Arc<dyn BanStore>,
+        strikes: Arc<StrikeSettings>,
+        whitelist: Arc<Whitelist>,
+        blocklists: Arc<Blocklists>,
+        feeds: Arc<Feeds>,
+    ) -> Self {
+        Admission {
+            geoip_service,
+            ban_store,
+            strikes,
+            whitelist,
+            blocklists,
+            feeds,
+        }
+    }
+
+    /// Checks the ban store, the blocklists, the feeds and the GeoIP rules
+    /// for a new client, returning why it was let through.
+    pub async fn check(
+        &self,
+        incoming_addr: IpAddr,
+        subnets: &[(IpNet, SubnetRateLimit)],
+    ) -> Result<Reason, Error> {
+        if self.whitelist.contains(incoming_addr) {
+            debug!("Address {} accepted; whitelisted", incoming_addr);
+            return Ok(Reason::Whitelisted);
+        }
+
+        let ip_key = incoming_addr.to_string();
+        let subnet_keys: Vec<String> = subnets
+            .iter()
+            .map(|(subnet, _)| subnet.to_string())
+            .collect();
+
+        let mut keys = vec![ip_key.as_str()];
+        keys.extend(subnet_keys.iter().map(String::as_str));
+
+        let entries = self.ban_store.entries(&keys).await?;
+        let ip_entry = entries.get(&ip_key);
+        let ip_status = ip_entry.map(|entry| entry.status);
+
+        if ip_status == Some(BanStatus::IpBlocked) {
+            warn!("Address {} reject from cache; IP banned", incoming_addr);
+            let notice = ip_entry.and_then(BanEntry::notice);
+            return Err(Error::IpBlockedInCache(incoming_addr, notice));
+        }
+
+        if ip_status == Some(BanStatus::IpWhiteList) {
+            info!(
+                "Address {} accepted from cache; IP whitelisted",
+                incoming_addr
+            );
+            return Ok(Reason::Whitelisted);
+        }
+
+        if ip_status == Some(BanStatus::ScannerBlocked) {
+            warn!(
+                "Address {} reject from cache; scanner banned",
+                incoming_addr
+            );
+            let notice = ip_entry.and_then(BanEntry::notice);
+            return Err(Error::ScannerBlockedInCache(incoming_addr, notice));
+        }
+
+        if let Some((subnet, entry)) = subnet_keys.iter().find_map(|key| {
+            entries
+                .get(key)
+                .filter(|entry| entry.status == BanStatus::IpBlocked)
+                .map(|entry| (key, entry))
+        }) {
+            warn!(
+                "Address {} reject from cache; subnet {} banned",
+                incoming_addr, subnet
+            );
+            return Err(Error::SubnetBlockedInCache(
+                incoming_addr,
+                subnet.clone(),
+                entry.notice(),
+            ));
+        }
+
+        // IP feeds count as lists of their own.
+        if let Some((list, net)) = self
+            .blocklists
+            .lookup(incoming_addr)
+            .map(|(list, net)| (list.to_string(), net))
+            .or_else(|| self.feeds.lookup(incoming_addr))
+        {
+            warn!(
+                "Address {} reject by blocklist {}; {} listed",
+                incoming_addr, list, net
+            );
+            return Err(Error::Blocklisted(incoming_addr, list, net.to_string()));
+        }
+
+        // A failed lookup counts as a match, with the ASN unknown.
+        if let Some(asn) = match self
+            .geoip_service
+            .in_asn_blacklist(incoming_addr, |asn| self.feeds.asn_listed(asn))
+        {
+            Ok(asn) => asn.map(Some),
+            Err(_) => Some(None),
+        } {
+            warn!(
+                "Address {} reject by asn; Please disable VPN",
+                incoming_addr
+            );
+            let e = Error::AsnBlocked(incoming_addr, asn);
+            self.ban(&ip_key, Reason::Asn, matched_rule(&e));
+            return Err(e);
+        }
+
+        // An attack on one node puts the whole cluster on guard.
+        if DDOS_MODE.get() == 0 && PEERS_UNDER_ATTACK.get() == 0 {
+            return Ok(Reason::Passed);
+        }
+
+        if let Some(country) = match self.geoip_service.in_country_blacklist(incoming_addr) {
+            Ok(country) => country.map(Some),
+            Err(_) => Some(None),
+        } {
+            warn!("Address {} reject by country", incoming_addr);
+            let e = Error::CountryBlocked(incoming_addr, country);
+            self.ban(&ip_key, Reason::Country, matched_rule(&e));
+            return Err(e);
+        }
+
+        Ok(Reason::Passed)
+    }
+
+    pub fn geoip(&self) -> &GeoIPService {
+        &self.geoip_service
+    }
+
+    pub fn whitelist(&self) -> &Whitelist {
+        &self.whitelist
+    }
+
+    /// Bans an address that touched a honeypot for `ttl` seconds, unless it
+    /// is whitelisted. `rule` names the decoy. Returns whether this hit
+    /// banned it.
+    ///
+    /// A sweeping scanner hits every decoy port, so an address already
+    /// banned as a scanner, or being banned, is left alone: its ban keeps
+    /// the appeal code it was shown and the store gets one write.
+    pub async fn ban_scanner(&self, addr: IpAddr, ttl: u32, rule: String) -> bool {
+        if self.whitelist.contains(addr) {
+            return false;
+        }
+
+        let key = addr.to_string();
+        let Some(_pending) = PendingBan::start(&key) else {
+            debug!("Ban of {} already under way", key);
+            return false;
+        };
+        let status = match self.ban_store.entry(&key).await {
+            Ok(entry) => entry.map(|entry| entry.status),
+            Err(e) => {
+                error!("Failed to look up {}: {}", key, e);
+                None
+            }
+        };
+        if matches!(
+            status,
+            Some(BanStatus::IpWhiteList | BanStatus::ScannerBlocked)
+        ) {
+            return false;
+        }
+
+        let entry = BanEntry::ban(
+            BanStatus::ScannerBlocked,
+            Reason::Scanner,
+            rule,
+            Duration::from_secs(ttl.into()),
+        );
+        if let Err(e) = self.ban_store.set_entry(&key, &entry, ttl).await {
+            error!("Failed to ban {}: {}", key, e);
+            return false;
+        }
+        true
+    }
+
+    /// Bans an address or a subnet, `key` is its textual form, for as long
+    /// as its strike history calls for. `rule` is what matched, as in the
+    /// audit events. The writes run in the background so callers outside
+    /// async code can ban too.
+    ///
+    /// Rejections while a ban of the same key is still being written are
+    /// part of the same burst and add no strike, so a flood costs one task
+    /// per offender and a strike count is only updated by one task at a
+    /// time. Nodes of a cluster can still race, the later write wins.
+    pub fn ban(&self, key: &str, reason: Reason, rule: String) {
+        let Some(pending) = PendingBan::start(key) else {
+            debug!("Ban of {} already under way", key);
+            return;
+        };
+        let ban_store = self.ban_store.clone();
+        let strikes = self.strikes.clone();
+        tokio::spawn(async move {
+            let key = &pending.key;
+            if let Err(e) = strike(ban_store.as_ref(), &strikes, key, reason, rule).await {
+                error!("Failed to ban {}: {}", key, e);
+            }
+        });
+    }
+}
+
+/// Adds a strike to `key` and bans it for the length of its new level.
+async fn strike(
+    ban_store: &dyn BanStore,
+    settings: &StrikeSettings,
+    key: &str,
+    reason: Reason,
+    rule: String,
+) -> Result<(), Error> {
+    let now = unix_now();
+    let level = match ban_store.strikes(key).await? {
+        Some(strikes) => strikes.decayed(now, settings.decay),
+        None => 0,
+    } + 1;
+
+    // Kept until every strike has been forgiven.
+    let history_ttl = settings.decay.as_secs().saturating_mul(level.into());
+    ban_store
+        .set_strikes(key, &Strikes { level, last: now }, ttl_secs(history_ttl))
+        .await?;
+
+    let ttl = settings.ban_ttl(level);
+    let entry = BanEntry {
+        strikes: level,
+        ..BanEntry::ban(BanStatus::IpBlocked, reason, rule, ttl)
+    };
+    ban_store
+        .set_entry(key, &entry, ttl_secs(ttl.as_secs()))
+        .await?;
+    info!("Banned {} for {}s; strike {}", key, ttl.as_secs(), level);
+    Ok(())
+}
+
+fn ttl_secs(secs: u64) -> u32 {
+    u32::try_from(secs).unwrap_or(u32::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn one_ban_per_key_at_a_time() {
+        let first = PendingBan::start("192.0.2.1").unwrap();
+        assert!(PendingBan::start("192.0.2.1").is_none());
+        let other = PendingBan::start("192.0.2.2").unwrap();
+
+        drop(first);
+        assert!(PendingBan::start("192.0.2.1").is_some());
+        drop(other);
+    }
+}
@@ -0,0 +1,310 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use log::{error, warn};
+use once_cell::sync::Lazy;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use raigeki_error::Error;
+use tokio::time::timeout;
+
+use super::{
+    check_ttl, BanStore, CacheSettings, FailurePolicy, SnapshotRecord, STRIKES_PREFIX,
+    WHITELIST_KEY,
+};
+
+static BAN_CACHE_LOOKUPS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "ban_cache_lookups_total",
+        "Ban store keys looked up in the local cache",
+        &["result"]
+    )
+    .unwrap()
+});
+
+static BAN_STORE_FAILURES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "ban_store_failures_total",
+        "Ban store calls that failed or timed out",
+        &["op"]
+    )
+    .unwrap()
+});
+
+/// Short-lived local copy of a remote store, so a flood from a few
+/// addresses doesn't turn into a remote lookup per connection.
+///
+/// Absent keys are cached too, for a shorter time. Writes land in the cache
+/// right away and reach the remote store in the background. A full cache
+/// makes room by dropping the entries closest to expiry.
+///
+/// Strike counters and the whitelist bypass the cache: they are read,
+/// changed and written back, and a stale copy would lose what other nodes
+/// added. Their writes are awaited, and a failed read is an error whatever
+/// the failure policy, which only covers the lookups letting clients in.
+pub struct CachedBanStore {
+    inner: Arc<dyn BanStore>,
+    settings: CacheSettings,
+    entries: Mutex<Entries>,
+}
+
+#[derive(Default)]
+struct Entries {
+    map: HashMap<String, (Option<String>, Instant)>,
+    /// Cached keys ordered by expiry.
+    by_expiry: BTreeSet<(Instant, String)>,
+}
+
+impl Entries {
+    fn insert(&mut self, key: &str, value: Option<String>, expires_at: Instant) {
+        if let Some((_, old)) = self.map.insert(key.to_string(), (value, expires_at)) {
+            self.by_expiry.remove(&(old, key.to_string()));
+        }
+        self.by_expiry.insert((expires_at, key.to_string()));
+    }
+
+    /// Drops expired entries, then the soonest to expire until fewer than
+    /// `capacity` are left.
+    fn make_room(&mut self, now: Instant, capacity: usize) {
+        while let Some((expires_at, key)) = self.by_expiry.first().cloned() {
+            if expires_at > now && self.map.len() < capacity {
+                break;
+            }
+            self.by_expiry.pop_first();
+            self.map.remove(&key);
+        }
+    }
+}
+
+/// Whether values of `key` may be served from the cache.
+fn cacheable(key: &str) -> bool {
+    !key.starts_with(STRIKES_PREFIX) && key != WHITELIST_KEY
+}
+
+impl CachedBanStore {
+    pub fn new(inner: Arc<dyn BanStore>, settings: CacheSettings) -> Self {
+        CachedBanStore {
+            inner,
+            settings,
+            entries: Mutex::new(Entries::default()),
+        }
+    }
+
+    fn remember(&self, key: &str, value: Option<String>, ttl: Duration) {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        if !entries.map.contains_key(key) {
+            entries.make_room(now, self.settings.capacity);
+        }
+        entries.insert(key, value, now + ttl);
+    }
+
+    /// Applies the failure policy to a lookup the remote store couldn't
+    /// answer, `values` holds what the cache already knew.
+    fn failed(
+        &self,
+        e: Error,
+        values: HashMap<String, String>,
+    ) -> Result<HashMap<String, String>, Error> {
+        BAN_STORE_FAILURES.with_label_values(&["get"]).inc();
+        match self.settings.failure_policy {
+            FailurePolicy::Open => {
+                warn!("Ban store lookup failed, letting through: {}", e);
+                Ok(values)
+            }
+            FailurePolicy::Closed => Err(e),
+        }
+    }
+}
+
+#[async_trait]
+impl BanStore for CachedBanStore {
+    async fn get_many(&self, keys: &[&str]) -> Result<HashMap<String, String>, Error> {
+        let mut values = HashMap::new();
+        let mut missed = Vec::new();
+        let mut bypassed = 0;
+        {
+            let now = Instant::now();
+            let entries = self.entries.lock().unwrap();
+            for key in keys {
+                if !cacheable(key) {
+                    bypassed += 1;
+                    missed.push(*key);
+                    continue;
+                }
+                match entries.map.get(*key) {
+                    Some((value, expires_at)) if *expires_at > now => {
+                        if let Some(value) = value {
+                            values.insert(key.to_string(), value.clone());
+                        }
+                    }
+                    _ => missed.push(*key),
+                }
+            }
+        }
+
+        BAN_CACHE_LOOKUPS
+            .with_label_values(&["hit"])
+            .inc_by((keys.len() - missed.len()) as u64);
+        if missed.is_empty() {
+            return Ok(values);
+        }
+        BAN_CACHE_LOOKUPS
+            .with_label_values(&["miss"])
+            .inc_by((missed.len() - bypassed) as u64);
+        BAN_CACHE_LOOKUPS
+            .with_label_values(&["bypass"])
+            .inc_by(bypassed as u64);
+
+        let fetched = match timeout(self.settings.timeout, self.inner.get_many(&missed)).await {
+            Ok(Ok(fetched)) => fetched,
+            Ok(Err(e)) if bypassed > 0 => return Err(e),
+            Ok(Err(e)) => return self.failed(e, values),
+            Err(_) if bypassed > 0 => return Err(timed_out(self.settings.timeout)),
+            Err(_) => return self.failed(timed_out(self.settings.timeout), values),
+        };
+
+        for key in missed.into_iter().filter(|key| cacheable(key)) {
+            match fetched.get(key) {
+                Some(value) => self.remember(key, Some(value.clone()), self.settings.ttl),
+                None => self.remember(key, None, self.settings.negative_ttl),
+            }
+        }
+        values.extend(fetched);
+        Ok(values)
+    }
+
+    async fn set(&self, key: &str, value: &str, ttl: u32) -> Result<(), Error> {
+        // Checked here too, the write below only fails in the background.
+        check_ttl(ttl)?;
+        if !cacheable(key) {
+            return match timeout(self.settings.timeout, self.inner.set(key, value, ttl)).await {
+                Ok(result) => result,
+                Err(_) => Err(timed_out(self.settings.timeout)),
+            }
+            .inspect_err(|_| BAN_STORE_FAILURES.with_label_values(&["set"]).inc());
+        }
+
+        let cached_for = self.settings.ttl.min(Duration::from_secs(ttl.into()));
+        self.remember(key, Some(value.to_string()), cached_for);
+
+        let inner = self.inner.clone();
+        let key = key.to_string();
+        let value = value.to_string();
+        let limit = self.settings.timeout;
+        tokio::spawn(async move {
+            let result = match timeout(limit, inner.set(&key, &value, ttl)).await {
+                Ok(result) => result,
+                Err(_) => Err(timed_out(limit)),
+            };
+            if let Err(e) = result {
+                BAN_STORE_FAILURES.with_label_values(&["set"]).inc();
+                error!("Failed to store {}: {}", key, e);
+            }
+        });
+        Ok(())
+    }
+
+    async fn dump(&self) -> Result<Option<Vec<SnapshotRecord>>, Error> {
+        self.inner.dump().await
+    }
+}
+
+fn timed_out(limit: Duration) -> Error {
+    Error::BanStoreError(format!("timed out after {}ms", limit.as_millis()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::ban_store::{MemoryBanStore, Strikes};
+
+    /// A store that is always down.
+    struct Down;
+
+    #[async_trait]
+    impl BanStore for Down {
+        async fn get_many(&self, _: &[&str]) -> Result<HashMap<String, String>, Error> {
+            Err(Error::BanStoreError("down".to_string()))
+        }
+
+        async fn set(&self, _: &str, _: &str, _: u32) -> Result<(), Error> {
+            Err(Error::BanStoreError("down".to_string()))
+        }
+    }
+
+    fn settings(capacity: usize, failure_policy: FailurePolicy) -> CacheSettings {
+        CacheSettings {
+            ttl: Duration::from_secs(60),
+            negative_ttl: Duration::from_secs(60),
+            capacity,
+            timeout: Duration::from_secs(1),
+            failure_policy,
+        }
+    }
+
+    fn cache(capacity: usize) -> CachedBanStore {
+        let settings = settings(capacity, FailurePolicy::Closed);
+        CachedBanStore::new(Arc::new(MemoryBanStore::new()), settings)
+    }
+
+    fn cached(cache: &CachedBanStore, key: &str) -> bool {
+        cache.entries.lock().unwrap().map.contains_key(key)
+    }
+
+    #[test]
+    fn full_caches_drop_the_soonest_to_expire() {
+        let cache = cache(2);
+        cache.remember("a", None, Duration::from_secs(30));
+        cache.remember("b", None, Duration::from_secs(10));
+        cache.remember("c", None, Duration::from_secs(20));
+        assert!(cached(&cache, "a") && cached(&cache, "c"));
+        assert!(!cached(&cache, "b"));
+
+        // Refreshing a cached key needs no room.
+        cache.remember("c", None, Duration::from_secs(40));
+        assert!(cached(&cache, "a") && cached(&cache, "c"));
+
+        cache.remember("d", None, Duration::from_secs(50));
+        assert!(cached(&cache, "c") && cached(&cache, "d"));
+        let entries = cache.entries.lock().unwrap();
+        assert_eq!(entries.map.len(), entries.by_expiry.len());
+    }
+
+    #[test]
+    fn expired_entries_go_first() {
+        let cache = cache(2);
+        cache.remember("expired", None, Duration::ZERO);
+        cache.remember("a", None, Duration::from_secs(10));
+        cache.remember("b", None, Duration::from_secs(30));
+        assert!(cached(&cache, "a") && cached(&cache, "b"));
+        assert!(!cached(&cache, "expired"));
+    }
+
+    #[tokio::test]
+    async fn strikes_bypass_the_cache() {
+        let cache = cache(10);
+        let strikes = Strikes { level: 2, last: 1 };
+        cache
+            .set_strikes("ip:192.0.2.1", &strikes, 60)
+            .await
+            .unwrap();
+        assert_eq!(cache.strikes("ip:192.0.2.1").await.unwrap(), Some(strikes));
+        assert!(cache.entries.lock().unwrap().map.is_empty());
+
+        // Written straight through, so another reader sees it at once.
+        let inner = cache.inner.clone();
+        assert_eq!(inner.strikes("ip:192.0.2.1").await.unwrap(), Some(strikes));
+    }
+
+    #[tokio::test]
+    async fn whitelist_changes_fail_when_the_store_is_down() {
+        let cache = CachedBanStore::new(Arc::new(Down), settings(10, FailurePolicy::Open));
+        // Lookups letting clients in fail open...
+        assert!(cache.get_many(&["ip:192.0.2.1"]).await.unwrap().is_empty());
+        // ...but the whitelist is not read as empty, nor written blind.
+        assert!(cache.get_many(&[WHITELIST_KEY]).await.is_err());
+        assert!(cache.set(WHITELIST_KEY, "[]", 60).await.is_err());
+    }
+}
@@ -0,0 +1,61 @@
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use raigeki_error::Error;
+use tokio::task::spawn_blocking;
+
+use super::{check_ttl, BanStore};
+
+/// Longest relative expiry memcached accepts, longer ones are read as unix
+/// timestamps.
+const MAX_RELATIVE_TTL: u32 = 30 * 24 * 60 * 60;
+
+/// Bans shared through memcached.
+///
+/// The memcache client is blocking, so every call runs on the blocking pool
+/// instead of a worker thread.
+pub struct MemcachedBanStore {
+    client: memcache::Client,
+}
+
+impl MemcachedBanStore {
+    pub fn new(client: memcache::Client) -> Self {
+        MemcachedBanStore { client }
+    }
+}
+
+#[async_trait]
+impl BanStore for MemcachedBanStore {
+    async fn get_many(&self, keys: &[&str]) -> Result<HashMap<String, String>, Error> {
+        let client = self.client.clone();
+        let keys: Vec<String> = keys.iter().map(|key| key.to_string()).collect();
+        let values = spawn_blocking(move || {
+            let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+            client.gets(&keys)
+        })
+        .await
+        .map_err(|e| Error::InternalError(e.to_string()))??;
+        Ok(values)
+    }
+
+    async fn set(&self, key: &str, value: &str, ttl: u32) -> Result<(), Error> {
+        check_ttl(ttl)?;
+        let client = self.client.clone();
+        let key = key.to_string();
+        let value = value.to_string();
+        let ttl = if ttl > MAX_RELATIVE_TTL {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            u32::try_from(now + u64::from(ttl)).unwrap_or(u32::MAX)
+        } else {
+            ttl
+        };
+        spawn_blocking(move || client.set(&key, value.as_str(), ttl))
+            .await
+            .map_err(|e| Error::InternalError(e.to_string()))??;
+        Ok(())
+    }
+}
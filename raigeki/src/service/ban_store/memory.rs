@@ -0,0 +1,96 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use raigeki_error::Error;
+
+use super::{check_ttl, unix_now, BanStore, SnapshotRecord};
+
+/// Writes between two sweeps of expired entries.
+const SWEEP_EVERY: usize = 1024;
+
+#[derive(Default)]
+struct Entries {
+    map: HashMap<String, (String, Instant)>,
+    writes: usize,
+}
+
+/// Bans kept in this process only, lost on restart.
+#[derive(Default)]
+pub struct MemoryBanStore {
+    entries: Mutex<Entries>,
+}
+
+impl MemoryBanStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl BanStore for MemoryBanStore {
+    async fn get_many(&self, keys: &[&str]) -> Result<HashMap<String, String>, Error> {
+        let now = Instant::now();
+        let entries = self.entries.lock().unwrap();
+        Ok(keys
+            .iter()
+            .filter_map(|key| match entries.map.get(*key) {
+                Some((value, expires_at)) if *expires_at > now => {
+                    Some((key.to_string(), value.clone()))
+                }
+                _ => None,
+            })
+            .collect())
+    }
+
+    async fn set(&self, key: &str, value: &str, ttl: u32) -> Result<(), Error> {
+        check_ttl(ttl)?;
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+
+        entries.writes += 1;
+        if entries.writes.is_multiple_of(SWEEP_EVERY) {
+            entries.map.retain(|_, (_, expires_at)| *expires_at > now);
+        }
+
+        let expires_at = now + Duration::from_secs(ttl.into());
+        entries
+            .map
+            .insert(key.to_string(), (value.to_string(), expires_at));
+        Ok(())
+    }
+
+    async fn dump(&self) -> Result<Option<Vec<SnapshotRecord>>, Error> {
+        let now = Instant::now();
+        let unix = unix_now();
+        let entries = self.entries.lock().unwrap();
+        Ok(Some(
+            entries
+                .map
+                .iter()
+                .filter(|(_, (_, expires_at))| *expires_at > now)
+                .map(|(key, (value, expires_at))| SnapshotRecord {
+                    key: key.clone(),
+                    value: value.clone(),
+                    expires_at: unix + (*expires_at - now).as_secs().max(1),
+                })
+                .collect(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn zero_ttls_are_refused() {
+        let store = MemoryBanStore::new();
+        assert!(store.set("ip:192.0.2.1", "1", 0).await.is_err());
+        assert!(store.get_many(&["ip:192.0.2.1"]).await.unwrap().is_empty());
+
+        store.set("ip:192.0.2.1", "1", 1).await.unwrap();
+        assert_eq!(store.get_many(&["ip:192.0.2.1"]).await.unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,356 @@
+//! Where bans and whitelist entries are kept.
+//!
+//! The filtering code only sees [`BanStore`]; which backend sits behind it is
+//! picked with `BAN_STORE`.
+
+use std::collections::HashMap;
+use std::hash::{BuildHasher, RandomState};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use async_trait::async_trait;
+use raigeki_error::{BanNotice, Error, Reason};
+use serde::{Deserialize, Serialize};
+
+mod cache;
+mod memcached;
+mod memory;
+mod redis;
+mod snapshot;
+
+pub use self::cache::CachedBanStore;
+pub use self::memcached::MemcachedBanStore;
+pub use self::memory::MemoryBanStore;
+pub use self::redis::RedisBanStore;
+pub use self::snapshot::{import, SnapshotBanStore, SnapshotRecord, SnapshotSettings};
+
+/// Appeal code characters, Crockford's base32 so codes read back over the
+/// phone or from a screenshot survive.
+const APPEAL_ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+const APPEAL_CODE_LEN: usize = 8;
+
+/// What the store knows about an address or a subnet. The codes are what
+/// memcached has always held, so existing entries keep working.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(into = "i16", try_from = "i16")]
+#[repr(i16)]
+pub enum BanStatus {
+    IpBlocked = 1,
+    IpWhiteList = 2,
+    /// Banned for touching a honeypot listener.
+    ScannerBlocked = 3,
+}
+
+impl From<BanStatus> for i16 {
+    fn from(status: BanStatus) -> Self {
+        status as i16
+    }
+}
+
+impl BanStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BanStatus::IpBlocked => "banned",
+            BanStatus::IpWhiteList => "whitelisted",
+            BanStatus::ScannerBlocked => "scanner",
+        }
+    }
+}
+
+impl TryFrom<i16> for BanStatus {
+    type Error = String;
+
+    fn try_from(code: i16) -> Result<Self, Self::Error> {
+        match code {
+            1 => Ok(BanStatus::IpBlocked),
+            2 => Ok(BanStatus::IpWhiteList),
+            3 => Ok(BanStatus::ScannerBlocked),
+            _ => Err(format!("unknown ban status: {}", code)),
+        }
+    }
+}
+
+/// A ban or whitelist entry. Entries written by older versions, or by hand,
+/// only have a status.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BanEntry {
+    pub status: BanStatus,
+    /// Strike level the ban was handed out at, zero for entries that don't
+    /// count strikes.
+    #[serde(default)]
+    pub strikes: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<Reason>,
+    /// The rule that matched, as in the audit events.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rule: Option<String>,
+    /// Unix time the entry was written, in seconds.
+    #[serde(default)]
+    pub banned_at: u64,
+    /// Unix time the entry ends, in seconds.
+    #[serde(default)]
+    pub expires_at: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub appeal_code: Option<String>,
+}
+
+impl BanEntry {
+    pub fn new(status: BanStatus) -> Self {
+        BanEntry {
+            status,
+            strikes: 0,
+            reason: None,
+            rule: None,
+            banned_at: 0,
+            expires_at: 0,
+            appeal_code: None,
+        }
+    }
+
+    /// A ban for `reason` starting now and lasting `ttl`, with a fresh
+    /// appeal code.
+    pub fn ban(status: BanStatus, reason: Reason, rule: String, ttl: Duration) -> Self {
+        let now = unix_now();
+        BanEntry {
+            reason: Some(reason),
+            rule: Some(rule),
+            banned_at: now,
+            expires_at: now + ttl.as_secs(),
+            appeal_code: Some(appeal_code()),
+            ..BanEntry::new(status)
+        }
+    }
+
+    /// What the banned client is told, for entries that know their expiry.
+    pub fn notice(&self) -> Option<BanNotice> {
+        let appeal_code = self.appeal_code.clone()?;
+        (self.expires_at > 0).then_some(BanNotice {
+            expires_at: self.expires_at,
+            appeal_code,
+        })
+    }
+
+    /// Reads an entry, or a bare status code as older versions wrote them.
+    pub fn decode(value: &str) -> Option<Self> {
+        match value.trim().parse::<i16>() {
+            Ok(code) => BanStatus::try_from(code).ok().map(BanEntry::new),
+            Err(_) => serde_json::from_str(value).ok(),
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+/// Bans handed out to an address or a subnet, forgiven one at a time as it
+/// behaves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Strikes {
+    pub level: u32,
+    /// Unix time of the last strike, in seconds.
+    pub last: u64,
+}
+
+impl Strikes {
+    /// Level left at `now` once a strike is forgiven every `decay`.
+    pub fn decayed(&self, now: u64, decay: Duration) -> u32 {
+        let periods = now.saturating_sub(self.last) / decay.as_secs().max(1);
+        self.level
+            .saturating_sub(u32::try_from(periods).unwrap_or(u32::MAX))
+    }
+}
+
+/// Strike counters live under this prefix.
+const STRIKES_PREFIX: &str = "strikes:";
+/// The dynamic whitelist entries, all under one key.
+pub const WHITELIST_KEY: &str = "whitelist";
+
+fn strikes_key(key: &str) -> String {
+    format!("{}{}", STRIKES_PREFIX, key)
+}
+
+fn appeal_key(code: &str) -> String {
+    format!("appeal:{}", code)
+}
+
+fn appeal_code() -> String {
+    let mut bits = RandomState::new().hash_one(SystemTime::now());
+    (0..APPEAL_CODE_LEN)
+        .map(|_| {
+            let c = APPEAL_ALPHABET[(bits % 32) as usize] as char;
+            bits /= 32;
+            c
+        })
+        .collect()
+}
+
+/// Refuses the zero TTL backends disagree on.
+fn check_ttl(ttl: u32) -> Result<(), Error> {
+    if ttl == 0 {
+        return Err(Error::BanStoreError("zero ttl".to_string()));
+    }
+    Ok(())
+}
+
+/// Current unix time, in seconds.
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Backends only move opaque values around; records are encoded on top.
+#[async_trait]
+pub trait BanStore: Send + Sync {
+    /// Values of `keys`, keys without a value are left out.
+    async fn get_many(&self, keys: &[&str]) -> Result<HashMap<String, String>, Error>;
+
+    /// Stores `value` under `key` for `ttl` seconds. Every backend refuses a
+    /// zero `ttl`, which memcached would keep forever and the others drop at
+    /// once or reject.
+    async fn set(&self, key: &str, value: &str, ttl: u32) -> Result<(), Error>;
+
+    /// Every live value, `None` for stores that can't list their keys.
+    async fn dump(&self) -> Result<Option<Vec<SnapshotRecord>>, Error> {
+        Ok(None)
+    }
+
+    /// Entries of `keys`, keys without a readable entry are left out.
+    async fn entries(&self, keys: &[&str]) -> Result<HashMap<String, BanEntry>, Error> {
+        Ok(self
+            .get_many(keys)
+            .await?
+            .into_iter()
+            .filter_map(|(key, value)| BanEntry::decode(&value).map(|entry| (key, entry)))
+            .collect())
+    }
+
+    async fn entry(&self, key: &str) -> Result<Option<BanEntry>, Error> {
+        Ok(self.entries(&[key]).await?.remove(key))
+    }
+
+    /// Stores `entry` under `key`, and its appeal code so the entry can be
+    /// found by it.
+    async fn set_entry(&self, key: &str, entry: &BanEntry, ttl: u32) -> Result<(), Error> {
+        if let Some(code) = &entry.appeal_code {
+            self.set(&appeal_key(code), key, ttl).await?;
+        }
+        self.set(key, &entry.encode(), ttl).await
+    }
+
+    /// The key banned under `code`, with its entry.
+    async fn appeal(&self, code: &str) -> Result<Option<(String, BanEntry)>, Error> {
+        let code = code.to_uppercase();
+        let index = appeal_key(&code);
+        let Some(key) = self.get_many(&[&index]).await?.remove(&index) else {
+            return Ok(None);
+        };
+        // The index outlives a ban that was replaced, so the entry has to
+        // still carry the code.
+        Ok(self
+            .entry(&key)
+            .await?
+            .filter(|entry| entry.appeal_code.as_deref() == Some(code.as_str()))
+            .map(|entry| (key, entry)))
+    }
+
+    async fn strikes(&self, key: &str) -> Result<Option<Strikes>, Error> {
+        let key = strikes_key(key);
+        Ok(self
+            .get_many(&[&key])
+            .await?
+            .remove(&key)
+            .and_then(|value| serde_json::from_str(&value).ok()))
+    }
+
+    async fn set_strikes(&self, key: &str, strikes: &Strikes, ttl: u32) -> Result<(), Error> {
+        let value = serde_json::to_string(strikes).unwrap();
+        self.set(&strikes_key(key), &value, ttl).await
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BanStoreKind {
+    Memcached,
+    /// Process-local, for single node setups.
+    Memory,
+    Redis,
+}
+
+impl FromStr for BanStoreKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "memcached" => Ok(BanStoreKind::Memcached),
+            "memory" => Ok(BanStoreKind::Memory),
+            "redis" => Ok(BanStoreKind::Redis),
+            _ => Err(format!("unknown ban store: {}", s)),
+        }
+    }
+}
+
+/// What a lookup the remote store can't answer in time turns into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailurePolicy {
+    /// Treat the store as empty and go on with the other checks. The
+    /// default, players are not turned away because the store is slow.
+    Open,
+    /// Turn the client away, opted into with `BAN_STORE_FAILURE_POLICY=closed`.
+    Closed,
+}
+
+impl FromStr for FailurePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "open" => Ok(FailurePolicy::Open),
+            "closed" => Ok(FailurePolicy::Closed),
+            _ => Err(format!("unknown failure policy: {}", s)),
+        }
+    }
+}
+
+/// Local cache in front of memcached and redis.
+#[derive(Debug, Clone, Copy)]
+pub struct CacheSettings {
+    /// How long a found entry is trusted.
+    pub ttl: Duration,
+    /// How long a missing entry is trusted.
+    pub negative_ttl: Duration,
+    /// Keys cached at once.
+    pub capacity: usize,
+    /// Longest wait for the remote store.
+    pub timeout: Duration,
+    pub failure_policy: FailurePolicy,
+}
+
+#[derive(Debug, Clone)]
+pub struct BanStoreSettings {
+    pub kind: BanStoreKind,
+    pub memcached_addrs: Vec<String>,
+    pub redis_url: String,
+    pub cache: CacheSettings,
+    pub snapshot: SnapshotSettings,
+}
+
+/// Builds the configured store, remote ones behind the local cache.
+pub fn connect(settings: &BanStoreSettings) -> anyhow::Result<Arc<dyn BanStore>> {
+    let remote: Arc<dyn BanStore> = match settings.kind {
+        BanStoreKind::Memcached => {
+            let client =
+                memcache::connect(settings.memcached_addrs.clone()).context("init memcached")?;
+            Arc::new(MemcachedBanStore::new(client))
+        }
+        BanStoreKind::Memory => return Ok(Arc::new(MemoryBanStore::new())),
+        BanStoreKind::Redis => {
+            Arc::new(RedisBanStore::new(&settings.redis_url).context("init redis")?)
+        }
+    };
+    Ok(Arc::new(CachedBanStore::new(remote, settings.cache)))
+}
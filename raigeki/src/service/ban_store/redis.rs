@@ -0,0 +1,205 @@
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use raigeki_error::Error;
+use redis::aio::ConnectionManager;
+use redis::{Client, RedisError};
+use tokio::sync::OnceCell;
+
+use super::{check_ttl, unix_now, BanStore, SnapshotRecord};
+
+/// Keys asked for per `SCAN` step of a dump.
+const SCAN_COUNT: usize = 1000;
+
+/// Bans shared through redis. A dump lists every string key with an
+/// expiry, so the database is best kept for raigeki alone.
+pub struct RedisBanStore {
+    client: Client,
+    /// Opened on first use, the manager reconnects by itself afterwards.
+    connection: OnceCell<ConnectionManager>,
+}
+
+impl RedisBanStore {
+    pub fn new(url: &str) -> Result<Self, Error> {
+        Ok(RedisBanStore {
+            client: Client::open(url).map_err(redis_error)?,
+            connection: OnceCell::new(),
+        })
+    }
+
+    async fn connection(&self) -> Result<ConnectionManager, Error> {
+        self.connection
+            .get_or_try_init(|| self.client.get_connection_manager())
+            .await
+            .cloned()
+            .map_err(redis_error)
+    }
+}
+
+fn redis_error(e: RedisError) -> Error {
+    Error::BanStoreError(format!("redis: {}", e))
+}
+
+#[async_trait]
+impl BanStore for RedisBanStore {
+    async fn get_many(&self, keys: &[&str]) -> Result<HashMap<String, String>, Error> {
+        let mut connection = self.connection().await?;
+        let values: Vec<Option<String>> = redis::cmd("MGET")
+            .arg(keys)
+            .query_async(&mut connection)
+            .await
+            .map_err(redis_error)?;
+
+        Ok(keys
+            .iter()
+            .zip(values)
+            .filter_map(|(key, value)| value.map(|value| (key.to_string(), value)))
+            .collect())
+    }
+
+    async fn set(&self, key: &str, value: &str, ttl: u32) -> Result<(), Error> {
+        check_ttl(ttl)?;
+        let mut connection = self.connection().await?;
+        redis::cmd("SET")
+            .arg(key)
+            .arg(value)
+            .arg("EX")
+            .arg(ttl)
+            .query_async(&mut connection)
+            .await
+            .map_err(redis_error)
+    }
+
+    async fn dump(&self) -> Result<Option<Vec<SnapshotRecord>>, Error> {
+        let mut connection = self.connection().await?;
+        let mut records = Vec::new();
+        let mut cursor: u64 = 0;
+        loop {
+            let (next, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+                .arg(cursor)
+                .arg("COUNT")
+                .arg(SCAN_COUNT)
+                .query_async(&mut connection)
+                .await
+                .map_err(redis_error)?;
+
+            if !keys.is_empty() {
+                // Keys of other types read as missing.
+                let values: Vec<Option<String>> = redis::cmd("MGET")
+                    .arg(&keys)
+                    .query_async(&mut connection)
+                    .await
+                    .map_err(redis_error)?;
+                let mut ttls = redis::pipe();
+                for key in &keys {
+                    ttls.cmd("TTL").arg(key);
+                }
+                let ttls: Vec<i64> = ttls
+                    .query_async(&mut connection)
+                    .await
+                    .map_err(redis_error)?;
+
+                let now = unix_now();
+                for ((key, value), ttl) in keys.into_iter().zip(values).zip(ttls) {
+                    // Negative for keys that expired or never do.
+                    if let (Some(value), Ok(ttl @ 1..)) = (value, u64::try_from(ttl)) {
+                        records.push(SnapshotRecord {
+                            key,
+                            value,
+                            expires_at: now + ttl,
+                        });
+                    }
+                }
+            }
+
+            if next == 0 {
+                return Ok(Some(records));
+            }
+            cursor = next;
+        }
+    }
+}
+
+/// Run against a scratch server with
+/// `REDIS_URL=redis://127.0.0.1/ cargo test -- --ignored redis`.
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use raigeki_error::Reason;
+
+    use super::*;
+    use crate::service::ban_store::{BanEntry, BanStatus};
+
+    fn store() -> RedisBanStore {
+        let url = std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1/".to_string());
+        RedisBanStore::new(&url).unwrap()
+    }
+
+    /// A key no other run uses.
+    fn key(name: &str) -> String {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        format!("raigeki-test:{}:{}", nanos, name)
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a redis server at REDIS_URL"]
+    async fn get_many_leaves_out_missing_keys() {
+        let store = store();
+        let (a, b, missing) = (key("a"), key("b"), key("missing"));
+        store.set(&a, "1", 60).await.unwrap();
+        store.set(&b, "2", 60).await.unwrap();
+
+        let values = store.get_many(&[&a, &missing, &b]).await.unwrap();
+        assert_eq!(values.len(), 2);
+        assert_eq!(values[&a], "1");
+        assert_eq!(values[&b], "2");
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a redis server at REDIS_URL"]
+    async fn set_values_expire_after_their_ttl() {
+        let store = store();
+        let key = key("ttl");
+        store.set(&key, "1", 1).await.unwrap();
+
+        let dumped = store.dump().await.unwrap().unwrap();
+        let record = dumped.iter().find(|record| record.key == key).unwrap();
+        assert_eq!(record.value, "1");
+        assert!(record.expires_at <= unix_now() + 1);
+
+        tokio::time::sleep(Duration::from_millis(2100)).await;
+        assert!(store.get_many(&[&key]).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a redis server at REDIS_URL"]
+    async fn entries_are_found_by_their_appeal_code() {
+        let store = store();
+        let key = key("ban");
+        let entry = BanEntry::ban(
+            BanStatus::IpBlocked,
+            Reason::RateLimit,
+            "ip_rate_limit".to_string(),
+            Duration::from_secs(60),
+        );
+        store.set_entry(&key, &entry, 60).await.unwrap();
+
+        assert_eq!(store.entry(&key).await.unwrap(), Some(entry.clone()));
+        let code = entry.appeal_code.clone().unwrap().to_lowercase();
+        assert_eq!(
+            store.appeal(&code).await.unwrap(),
+            Some((key.clone(), entry))
+        );
+
+        // A replaced ban no longer answers to the old code.
+        store
+            .set_entry(&key, &BanEntry::new(BanStatus::IpWhiteList), 60)
+            .await
+            .unwrap();
+        assert_eq!(store.appeal(&code).await.unwrap(), None);
+    }
+}
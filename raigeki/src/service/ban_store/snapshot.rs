@@ -0,0 +1,279 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use log::{error, info, warn};
+use pingora::{server::ShutdownWatch, services::background::BackgroundService};
+use raigeki_error::Error;
+use serde::{Deserialize, Serialize};
+use tokio::task::spawn_blocking;
+use tokio::time::interval;
+
+use super::{unix_now, BanStore};
+
+#[derive(Debug, Clone)]
+pub struct SnapshotSettings {
+    /// File the snapshots are written to, `None` disables them.
+    pub path: Option<PathBuf>,
+    pub interval: Duration,
+}
+
+/// One stored value, a line of a snapshot. Values are kept as the store
+/// holds them, so bans, strike counters and whitelist entries all fit.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotRecord {
+    pub key: String,
+    pub value: String,
+    /// Unix time the value ends, in seconds.
+    pub expires_at: u64,
+}
+
+/// What an import did with its records.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct ImportReport {
+    pub imported: usize,
+    pub expired: usize,
+    pub invalid: usize,
+}
+
+/// Writes the store to disk and replays it on startup.
+///
+/// Snapshots are JSON lines of [`SnapshotRecord`], written next to the
+/// target and renamed over it, so a crash mid-write leaves the previous one.
+/// The same lines are what the admin endpoint exports and imports.
+///
+/// Stores that can list their keys, redis and the memory store, are dumped
+/// whole. Memcached can't, so for it a snapshot is only a journal of the
+/// values written through this process since it started: bans other nodes
+/// wrote, or that were written before a restart and not restored, are not
+/// in it.
+pub struct SnapshotBanStore {
+    inner: Arc<dyn BanStore>,
+    settings: SnapshotSettings,
+    records: Mutex<HashMap<String, (String, u64)>>,
+}
+
+impl SnapshotBanStore {
+    pub fn new(inner: Arc<dyn BanStore>, settings: SnapshotSettings) -> Self {
+        SnapshotBanStore {
+            inner,
+            settings,
+            records: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Live values of the store, or of the journal if the store can't list
+    /// them, one JSON record per line.
+    pub async fn export(&self) -> Result<String, Error> {
+        let records = match self.inner.dump().await? {
+            Some(records) => records,
+            None => {
+                self.prune();
+                self.records
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .map(|(key, (value, expires_at))| SnapshotRecord {
+                        key: key.clone(),
+                        value: value.clone(),
+                        expires_at: *expires_at,
+                    })
+                    .collect()
+            }
+        };
+
+        let mut lines = String::new();
+        for record in records {
+            lines.push_str(&serde_json::to_string(&record).unwrap());
+            lines.push('\n');
+        }
+        Ok(lines)
+    }
+
+    /// Forgets journaled values that expired.
+    fn prune(&self) {
+        let now = unix_now();
+        self.records
+            .lock()
+            .unwrap()
+            .retain(|_, (_, expires_at)| *expires_at > now);
+    }
+
+    /// Replays the last snapshot into the store.
+    async fn restore(&self, path: &Path) {
+        let lines = match fs::read_to_string(path) {
+            Ok(lines) => lines,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return,
+            Err(e) => {
+                error!("Failed to read snapshot {}: {}", path.display(), e);
+                return;
+            }
+        };
+
+        match import(self, &lines).await {
+            Ok(report) => info!(
+                "Restored {} records from {}, dropped {} expired and {} invalid",
+                report.imported,
+                path.display(),
+                report.expired,
+                report.invalid
+            ),
+            Err(e) => error!("Failed to restore snapshot {}: {}", path.display(), e),
+        }
+    }
+
+    async fn save(&self, path: &Path) {
+        let lines = match self.export().await {
+            Ok(lines) => lines,
+            Err(e) => {
+                error!("Failed to dump the store to {}: {}", path.display(), e);
+                return;
+            }
+        };
+        let target = path.to_path_buf();
+        match spawn_blocking(move || write_atomic(&target, lines.as_bytes())).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => error!("Failed to write snapshot {}: {}", path.display(), e),
+            Err(e) => error!("Failed to write snapshot {}: {}", path.display(), e),
+        }
+    }
+}
+
+/// Writes the records of `lines` to `store`, skipping expired ones.
+pub async fn import(store: &dyn BanStore, lines: &str) -> Result<ImportReport, Error> {
+    let now = unix_now();
+    let mut report = ImportReport::default();
+    for line in lines.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        let record: SnapshotRecord = match serde_json::from_str(line) {
+            Ok(record) => record,
+            Err(e) => {
+                if report.invalid == 0 {
+                    warn!("Invalid snapshot record, skipping: {}", e);
+                }
+                report.invalid += 1;
+                continue;
+            }
+        };
+        if record.expires_at <= now {
+            report.expired += 1;
+            continue;
+        }
+
+        let ttl = u32::try_from(record.expires_at - now).unwrap_or(u32::MAX);
+        store.set(&record.key, &record.value, ttl).await?;
+        report.imported += 1;
+    }
+    Ok(report)
+}
+
+/// Replaces `path` with `contents` so that readers, and a restart after a
+/// crash, see either the old file or the new one.
+fn write_atomic(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+
+    let mut file = File::create(&tmp)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+
+    // Make the rename itself durable.
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl BanStore for SnapshotBanStore {
+    async fn get_many(&self, keys: &[&str]) -> Result<HashMap<String, String>, Error> {
+        self.inner.get_many(keys).await
+    }
+
+    async fn set(&self, key: &str, value: &str, ttl: u32) -> Result<(), Error> {
+        self.inner.set(key, value, ttl).await?;
+        let expires_at = unix_now() + u64::from(ttl);
+        self.records
+            .lock()
+            .unwrap()
+            .insert(key.to_string(), (value.to_string(), expires_at));
+        Ok(())
+    }
+
+    async fn dump(&self) -> Result<Option<Vec<SnapshotRecord>>, Error> {
+        self.inner.dump().await
+    }
+}
+
+/// Restores the last snapshot, then prunes the journal and writes a
+/// snapshot every interval, and a last one on shutdown.
+#[async_trait]
+impl BackgroundService for SnapshotBanStore {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let path = self.settings.path.clone();
+        if let Some(path) = &path {
+            self.restore(path).await;
+        }
+
+        let mut period = interval(self.settings.interval);
+        // The first tick fires right away, there is nothing new to save yet.
+        period.tick().await;
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => break,
+                _ = period.tick() => {
+                    // Stores that dump themselves never read the journal, so
+                    // it is pruned here whatever the snapshot comes from.
+                    self.prune();
+                    if let Some(path) = &path {
+                        self.save(path).await;
+                    }
+                }
+            }
+        }
+        if let Some(path) = &path {
+            self.save(path).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::ban_store::MemoryBanStore;
+
+    #[tokio::test]
+    async fn expired_records_are_pruned_while_snapshotting() {
+        let path = std::env::temp_dir().join(format!("raigeki-snapshot-{}", std::process::id()));
+        let store = SnapshotBanStore::new(
+            Arc::new(MemoryBanStore::new()),
+            SnapshotSettings {
+                path: Some(path.clone()),
+                interval: Duration::from_millis(10),
+            },
+        );
+        store.set("ip:192.0.2.1", "1", 60).await.unwrap();
+        store.set("ip:192.0.2.2", "1", 60).await.unwrap();
+        store.set("ip:192.0.2.3", "1", 60).await.unwrap();
+        // Let two of them run out.
+        for key in ["ip:192.0.2.1", "ip:192.0.2.2"] {
+            store.records.lock().unwrap().get_mut(key).unwrap().1 = unix_now() - 1;
+        }
+
+        let (stop, shutdown) = tokio::sync::watch::channel(false);
+        tokio::join!(store.start(shutdown), async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            stop.send(true).unwrap();
+        });
+        let _ = fs::remove_file(&path);
+
+        let records = store.records.lock().unwrap();
+        assert_eq!(records.len(), 1);
+        assert!(records.contains_key("ip:192.0.2.3"));
+    }
+}
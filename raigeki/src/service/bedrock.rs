@@ -0,0 +1,576 @@
+//! Bedrock Edition proxy: RakNet over UDP.
+//!
+//! Datagrams from unknown sources are offline RakNet messages: pings are
+//! answered from the backend's cached MOTD and an open-connection request
+//! creates a NAT entry once the client passes admission. Every later datagram
+//! of that source is relayed as is through the entry's own upstream socket
+//! until it idles out.
+//!
+//! Admission of offline messages runs in its own task, so a slow ban store
+//! never holds up relaying for the live sessions. At most
+//! [`MAX_PENDING_ADMISSIONS`] run at once and one per source address, the
+//! rest are dropped like any lost datagram.
+
+use std::collections::{HashMap, HashSet};
+use std::hash::{BuildHasher, RandomState};
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use ipnet::IpNet;
+use log::{debug, error, info, warn};
+use once_cell::sync::Lazy;
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+use pingora_limits::rate::Rate;
+use prometheus::{register_int_counter_vec, register_int_gauge, IntCounterVec, IntGauge};
+use raigeki::net::normalize;
+use raigeki_error::{Error, Reason};
+use raigeki_mcproto::raknet::{
+    ConnectionRefusedPacket, OpenConnectionRequest1Packet, UnconnectedPingPacket,
+    UnconnectedPongPacket, OPEN_CONNECTION_REQUEST_1, UNCONNECTED_PING,
+    UNCONNECTED_PING_OPEN_CONNECTIONS,
+};
+use tokio::net::UdpSocket;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinHandle;
+use tokio::time::{interval, timeout};
+
+use crate::service::admission::Admission;
+use crate::service::events::SessionEvents;
+use crate::service::limits::SubnetRateLimit;
+use crate::settings::BedrockSettings;
+
+static BEDROCK_SESSIONS: Lazy<IntGauge> =
+    Lazy::new(|| register_int_gauge!("bedrock_sessions", "Live Bedrock NAT entries").unwrap());
+
+static BEDROCK_REJECTIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "bedrock_rejections_total",
+        "Bedrock pings and connection attempts that were refused",
+        &["reason"]
+    )
+    .unwrap()
+});
+
+/// Larger than any datagram RakNet sends, MTU probes included.
+const MAX_DATAGRAM: usize = 2048;
+
+/// How long the backend may take to answer a MOTD ping.
+const MOTD_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How often idle NAT entries are looked for.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Window of the ping, connection and packet rate limiters.
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Offline messages being admitted at once.
+const MAX_PENDING_ADMISSIONS: usize = 1024;
+
+struct Motd {
+    server_guid: i64,
+    text: String,
+}
+
+/// One client mapped to its own upstream socket.
+struct NatEntry {
+    upstream: Arc<UdpSocket>,
+    /// Milliseconds since the service started, see [`Bedrock::now`].
+    last_seen: Arc<AtomicU64>,
+    downstream: JoinHandle<()>,
+    /// Bytes the backend sent back, counted by the downstream task.
+    bytes_out: Arc<AtomicU64>,
+    events: SessionEvents,
+    subnets: Vec<(IpNet, SubnetRateLimit)>,
+}
+
+impl Drop for NatEntry {
+    fn drop(&mut self) {
+        self.downstream.abort();
+        self.events
+            .transferred(0, self.bytes_out.load(Ordering::Relaxed));
+        BEDROCK_SESSIONS.dec();
+    }
+}
+
+/// An offline message of `src` being admitted. Frees its slot on drop.
+struct PendingAdmission {
+    bedrock: Arc<Bedrock>,
+    src: SocketAddr,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Drop for PendingAdmission {
+    fn drop(&mut self) {
+        self.bedrock.pending.lock().unwrap().remove(&self.src);
+    }
+}
+
+pub struct BedrockService {
+    bedrock: Arc<Bedrock>,
+}
+
+impl BedrockService {
+    pub fn new(settings: BedrockSettings, backend: SocketAddr, admission: Admission) -> Self {
+        BedrockService {
+            bedrock: Arc::new(Bedrock::new(settings, backend, admission)),
+        }
+    }
+}
+
+struct Bedrock {
+    settings: BedrockSettings,
+    backend: SocketAddr,
+    admission: Admission,
+    /// Identifies the proxy in its own RakNet messages.
+    guid: i64,
+    started: Instant,
+    motd: RwLock<Motd>,
+    sessions: Mutex<HashMap<SocketAddr, NatEntry>>,
+    /// Sources with an offline message being admitted.
+    pending: Mutex<HashSet<SocketAddr>>,
+    admissions: Arc<Semaphore>,
+    ping_rate_limiter: Rate,
+    connect_rate_limiter: Rate,
+    subnet_connect_rate_limiter: Rate,
+    packet_rate_limiter: Rate,
+    subnet_packet_rate_limiter: Rate,
+}
+
+impl Bedrock {
+    fn new(settings: BedrockSettings, backend: SocketAddr, admission: Admission) -> Self {
+        let guid = RandomState::new().hash_one(std::process::id()) as i64;
+        let motd = Motd {
+            server_guid: guid,
+            text: format!(
+                "MCPE;{};0;0.0.0;0;0;{};Raigeki;Survival;1;{};{};",
+                settings.motd, guid, settings.l4_port, settings.l4_port
+            ),
+        };
+
+        Bedrock {
+            settings,
+            backend,
+            admission,
+            guid,
+            started: Instant::now(),
+            motd: RwLock::new(motd),
+            sessions: Mutex::new(HashMap::new()),
+            pending: Mutex::new(HashSet::new()),
+            admissions: Arc::new(Semaphore::new(MAX_PENDING_ADMISSIONS)),
+            ping_rate_limiter: Rate::new(RATE_WINDOW),
+            connect_rate_limiter: Rate::new(RATE_WINDOW),
+            subnet_connect_rate_limiter: Rate::new(RATE_WINDOW),
+            packet_rate_limiter: Rate::new(RATE_WINDOW),
+            subnet_packet_rate_limiter: Rate::new(RATE_WINDOW),
+        }
+    }
+
+    fn now(&self) -> u64 {
+        self.started.elapsed().as_millis() as u64
+    }
+
+    /// The subnets of `addr` that carry their own rate limits.
+    fn subnets(&self, addr: IpAddr) -> Vec<(IpNet, SubnetRateLimit)> {
+        self.settings
+            .subnet_rate_limits
+            .iter()
+            .filter_map(|limit| limit.subnet(addr).map(|subnet| (subnet, *limit)))
+            .collect()
+    }
+
+    /// The ban a datagram from `addr` earns by going over a packet rate
+    /// limit: the banned key, reason, rule and close cause.
+    fn packet_rate_exceeded(
+        &self,
+        addr: IpAddr,
+        subnets: &[(IpNet, SubnetRateLimit)],
+    ) -> Option<(String, Reason, String, &'static str)> {
+        if self.packet_rate_limiter.observe(&addr, 1) > self.settings.packet_rate_limit {
+            warn!("Bedrock address {} exceed max packet rate", addr);
+            let key = addr.to_string();
+            let rule = format!("ip:{}", key);
+            return Some((key, Reason::RateLimit, rule, "rate_limit_ban"));
+        }
+
+        for (subnet, limit) in subnets {
+            if limit.rate_limit > 0
+                && self.subnet_packet_rate_limiter.observe(subnet, 1) > limit.rate_limit
+            {
+                warn!(
+                    "Bedrock subnet {} exceed max packet rate; last address {}",
+                    subnet, addr
+                );
+                let key = subnet.to_string();
+                let rule = format!("subnet:{}", key);
+                return Some((key, Reason::SubnetRateLimit, rule, "subnet_rate_limit_ban"));
+            }
+        }
+        None
+    }
+
+    async fn serve(self: &Arc<Self>, listener: Arc<UdpSocket>, mut shutdown: ShutdownWatch) {
+        let mut buf = vec![0; MAX_DATAGRAM];
+
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => break,
+                result = listener.recv_from(&mut buf) => match result {
+                    Ok((n, src)) => self.handle(&listener, &buf[..n], src).await,
+                    Err(e) => debug!("Bedrock listener receive failed: {}", e),
+                },
+            }
+        }
+
+        let mut sessions = self.sessions.lock().unwrap();
+        for entry in sessions.values_mut() {
+            entry.events.close("shutdown");
+        }
+        sessions.clear();
+    }
+
+    async fn handle(self: &Arc<Self>, listener: &Arc<UdpSocket>, datagram: &[u8], src: SocketAddr) {
+        let addr = normalize(src.ip());
+        let now = self.now();
+
+        let relayed = self.sessions.lock().unwrap().get_mut(&src).map(|entry| {
+            entry.last_seen.store(now, Ordering::Relaxed);
+            entry.events.transferred(datagram.len() as u64, 0);
+            (
+                Arc::clone(&entry.upstream),
+                self.packet_rate_exceeded(addr, &entry.subnets),
+            )
+        });
+
+        match relayed {
+            Some((_, Some((key, reason, rule, cause)))) => {
+                self.admission.ban(&key, reason, rule);
+                if let Some(mut entry) = self.sessions.lock().unwrap().remove(&src) {
+                    entry.events.close(cause);
+                }
+            }
+            Some((upstream, None)) => {
+                let _ = upstream.send(datagram).await;
+            }
+            None => self.admit(listener, datagram, src, addr),
+        }
+    }
+
+    /// Handles an offline message in a task of its own.
+    fn admit(
+        self: &Arc<Self>,
+        listener: &Arc<UdpSocket>,
+        datagram: &[u8],
+        src: SocketAddr,
+        addr: IpAddr,
+    ) {
+        let kind = datagram.first().copied();
+        if !matches!(
+            kind,
+            Some(UNCONNECTED_PING | UNCONNECTED_PING_OPEN_CONNECTIONS | OPEN_CONNECTION_REQUEST_1)
+        ) {
+            // Anything else from an unknown source belongs to no session.
+            return;
+        }
+
+        let Ok(permit) = Arc::clone(&self.admissions).try_acquire_owned() else {
+            BEDROCK_REJECTIONS.with_label_values(&["busy"]).inc();
+            return;
+        };
+        // The client retransmits, there is no need to admit it twice.
+        if !self.pending.lock().unwrap().insert(src) {
+            return;
+        }
+        let pending = PendingAdmission {
+            bedrock: Arc::clone(self),
+            src,
+            _permit: permit,
+        };
+
+        let listener = Arc::clone(listener);
+        let datagram = datagram.to_vec();
+        tokio::spawn(async move {
+            let bedrock = &pending.bedrock;
+            if kind == Some(OPEN_CONNECTION_REQUEST_1) {
+                bedrock.open(&listener, &datagram, src, addr).await;
+            } else {
+                bedrock.pong(&listener, &datagram, src, addr).await;
+            }
+        });
+    }
+
+    async fn pong(&self, listener: &UdpSocket, datagram: &[u8], src: SocketAddr, addr: IpAddr) {
+        if self.ping_rate_limiter.observe(&addr, 1) > self.settings.ping_rate_limit {
+            BEDROCK_REJECTIONS.with_label_values(&["ping_rate"]).inc();
+            return;
+        }
+
+        let Ok(ping) = UnconnectedPingPacket::deserialize(datagram) else {
+            return;
+        };
+
+        if let Err(e) = self.admission.check(addr, &self.subnets(addr)).await {
+            debug!("Bedrock ping from {} refused: {}", addr, e);
+            BEDROCK_REJECTIONS.with_label_values(&["admission"]).inc();
+            return;
+        }
+
+        let pong = {
+            let motd = self.motd.read().unwrap();
+            UnconnectedPongPacket {
+                time: ping.time,
+                server_guid: motd.server_guid,
+                motd: motd.text.clone(),
+            }
+        };
+        let _ = listener.send_to(&pong.serialize(), src).await;
+    }
+
+    async fn open(
+        &self,
+        listener: &Arc<UdpSocket>,
+        datagram: &[u8],
+        src: SocketAddr,
+        addr: IpAddr,
+    ) {
+        let Ok(request) = OpenConnectionRequest1Packet::deserialize(datagram) else {
+            return;
+        };
+        debug!(
+            "Bedrock open connection from {}; protocol={}, mtu={}",
+            src, request.protocol_version, request.mtu
+        );
+        let mut events = SessionEvents::accept("bedrock", "bedrock", src);
+
+        if self.connect_rate_limiter.observe(&addr, 1) > self.settings.connect_rate_limit {
+            warn!("Bedrock connection rate limit exceeded for {}", addr);
+            events.reject(Reason::RateLimit, format!("ip:{}", addr));
+            BEDROCK_REJECTIONS
+                .with_label_values(&["connect_rate"])
+                .inc();
+            return;
+        }
+
+        let subnets = self.subnets(addr);
+        for (subnet, limit) in &subnets {
+            if limit.connect_limit > 0
+                && self.subnet_connect_rate_limiter.observe(subnet, 1) > limit.connect_limit
+            {
+                warn!(
+                    "Bedrock connection rate limit exceeded for {} from subnet {}",
+                    addr, subnet
+                );
+                events.reject(Reason::SubnetRateLimit, format!("subnet:{}", subnet));
+                BEDROCK_REJECTIONS
+                    .with_label_values(&["subnet_connect_rate"])
+                    .inc();
+                return;
+            }
+        }
+
+        let admitted = match self.admission.check(addr, &subnets).await {
+            Ok(reason) => reason,
+            Err(e) => {
+                warn!("Bedrock session {} failed validation: {:?}", events.id(), e);
+                events.reject_error(&e);
+                BEDROCK_REJECTIONS.with_label_values(&["admission"]).inc();
+                let refusal = ConnectionRefusedPacket::banned(self.guid);
+                let _ = listener.send_to(&refusal.serialize(), src).await;
+                return;
+            }
+        };
+
+        if self.sessions.lock().unwrap().len() >= self.settings.max_sessions {
+            warn!("Bedrock address {} reject; session limit reached", addr);
+            events.reject(Reason::SessionLimit, "max_sessions");
+            BEDROCK_REJECTIONS.with_label_values(&["full"]).inc();
+            let refusal = ConnectionRefusedPacket::full(self.guid);
+            let _ = listener.send_to(&refusal.serialize(), src).await;
+            return;
+        }
+
+        let upstream = match connect(self.backend).await {
+            Ok(upstream) => Arc::new(upstream),
+            Err(e) => {
+                error!("Bedrock address {} dropped; {}", addr, e);
+                events.reject_error(&e);
+                return;
+            }
+        };
+        events.allow(admitted, format!("ip:{}", addr));
+        events.backend(self.backend);
+        events.transferred(datagram.len() as u64, 0);
+
+        let last_seen = Arc::new(AtomicU64::new(self.now()));
+        let bytes_out = Arc::new(AtomicU64::new(0));
+        let downstream = tokio::spawn(relay_downstream(
+            Arc::clone(listener),
+            Arc::clone(&upstream),
+            src,
+            Arc::clone(&last_seen),
+            Arc::clone(&bytes_out),
+            self.started,
+        ));
+
+        BEDROCK_SESSIONS.inc();
+        self.sessions.lock().unwrap().insert(
+            src,
+            NatEntry {
+                upstream: Arc::clone(&upstream),
+                last_seen,
+                downstream,
+                bytes_out,
+                events,
+                subnets,
+            },
+        );
+
+        let _ = upstream.send(datagram).await;
+    }
+
+    async fn refresh_motd(&self) -> Result<(), Error> {
+        let socket = connect(self.backend).await?;
+        let ping = UnconnectedPingPacket {
+            time: self.now() as i64,
+            client_guid: self.guid,
+        };
+        socket.send(&ping.serialize()).await?;
+
+        let mut buf = vec![0; MAX_DATAGRAM];
+        let n = timeout(MOTD_TIMEOUT, socket.recv(&mut buf))
+            .await
+            .map_err(|_| Error::BackendConnectTimeout(self.backend))??;
+        let pong = UnconnectedPongPacket::deserialize(&buf[..n])
+            .map_err(|e| Error::ProtocolViolation(e.to_string()))?;
+
+        *self.motd.write().unwrap() = Motd {
+            server_guid: pong.server_guid,
+            text: self.rewrite_ports(&pong.motd),
+        };
+
+        Ok(())
+    }
+
+    /// Points the IPv4 and IPv6 port fields of a MOTD at the proxy instead of
+    /// the backend.
+    fn rewrite_ports(&self, motd: &str) -> String {
+        let port = self.settings.l4_port.to_string();
+        let mut fields: Vec<&str> = motd.split(';').collect();
+        for index in [10, 11] {
+            if let Some(field) = fields.get_mut(index) {
+                *field = &port;
+            }
+        }
+        fields.join(";")
+    }
+
+    async fn refresh_motd_periodically(&self, mut shutdown: ShutdownWatch) {
+        let mut period = interval(self.settings.motd_refresh);
+
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => break,
+                _ = period.tick() => {
+                    if let Err(e) = self.refresh_motd().await {
+                        debug!("Bedrock MOTD refresh failed: {}", e);
+                    }
+                }
+            }
+        }
+    }
+
+    async fn expire_sessions(&self, mut shutdown: ShutdownWatch) {
+        let mut period = interval(SWEEP_INTERVAL);
+        let idle_timeout = self.settings.idle_timeout.as_millis() as u64;
+
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => break,
+                _ = period.tick() => {
+                    let now = self.now();
+                    self.sessions.lock().unwrap().retain(|_, entry| {
+                        let idle = now.saturating_sub(entry.last_seen.load(Ordering::Relaxed));
+                        if idle < idle_timeout {
+                            return true;
+                        }
+                        entry.events.close("idle_timeout");
+                        false
+                    });
+                }
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl BackgroundService for BedrockService {
+    async fn start(&self, shutdown: ShutdownWatch) {
+        self.bedrock.start(shutdown).await
+    }
+}
+
+impl Bedrock {
+    async fn start(self: &Arc<Self>, shutdown: ShutdownWatch) {
+        let bind = format!("{}:{}", self.settings.l4_ip, self.settings.l4_port);
+        let listener = match UdpSocket::bind(&bind).await {
+            Ok(listener) => Arc::new(listener),
+            Err(e) => {
+                error!("Failed to bind Bedrock listener {}: {}", bind, e);
+                return;
+            }
+        };
+        info!("Bedrock listener on {}", bind);
+
+        tokio::join!(
+            self.serve(listener, shutdown.clone()),
+            self.refresh_motd_periodically(shutdown.clone()),
+            self.expire_sessions(shutdown),
+        );
+    }
+}
+
+/// A fresh UDP socket connected to `backend`.
+async fn connect(backend: SocketAddr) -> Result<UdpSocket, Error> {
+    let local: IpAddr = match backend {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let socket = UdpSocket::bind((local, 0)).await?;
+    socket
+        .connect(backend)
+        .await
+        .map_err(|e| Error::BackendConnectFailed(backend, e))?;
+    Ok(socket)
+}
+
+/// Sends everything the backend says to a session back to its client.
+async fn relay_downstream(
+    listener: Arc<UdpSocket>,
+    upstream: Arc<UdpSocket>,
+    client: SocketAddr,
+    last_seen: Arc<AtomicU64>,
+    bytes_out: Arc<AtomicU64>,
+    started: Instant,
+) {
+    let mut buf = vec![0; MAX_DATAGRAM];
+
+    loop {
+        match upstream.recv(&mut buf).await {
+            Ok(n) => {
+                last_seen.store(started.elapsed().as_millis() as u64, Ordering::Relaxed);
+                bytes_out.fetch_add(n as u64, Ordering::Relaxed);
+                let _ = listener.send_to(&buf[..n], client).await;
+            }
+            // An ICMP unreachable from the backend, it may come back.
+            Err(e) if e.kind() == ErrorKind::ConnectionRefused => {}
+            Err(e) => {
+                debug!("Bedrock upstream of {} failed: {}", client, e);
+                break;
+            }
+        }
+    }
+}
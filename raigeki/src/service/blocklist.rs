@@ -0,0 +1,331 @@
+//! CIDR blocklists loaded from local files: plain lists, FireHOL netsets and
+//! Spamhaus DROP.
+//!
+//! Each list is parsed into a [`PrefixSet`] and swapped in whole, so a
+//! lookup sees either the old list or the new one. Files are polled for
+//! changes and a list that fails to load keeps its previous contents.
+
+use std::fs;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use ipnet::IpNet;
+use log::{error, info, warn};
+use once_cell::sync::Lazy;
+use pingora::{server::ShutdownWatch, services::background::BackgroundService};
+use prometheus::{register_int_gauge_vec, IntGaugeVec};
+use raigeki::prefix::PrefixSet;
+use serde_json::{json, Value};
+use tokio::time::interval;
+
+use crate::service::ban_store::unix_now;
+
+static BLOCKLIST_PREFIXES: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "blocklist_prefixes",
+        "Networks loaded per blocklist, after dropping covered ones",
+        &["list"]
+    )
+    .unwrap()
+});
+
+/// Layout of a blocklist file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListFormat {
+    /// One address or CIDR range per line, `#` starts a comment.
+    Plain,
+    /// FireHOL `.netset`/`.ipset`, a plain list with a comment header.
+    Netset,
+    /// Spamhaus DROP, `cidr ; SBL id` lines or the JSON lines variant.
+    Drop,
+}
+
+impl ListFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ListFormat::Plain => "plain",
+            ListFormat::Netset => "netset",
+            ListFormat::Drop => "drop",
+        }
+    }
+
+    /// The network on one line, `None` for blank and comment lines.
+    pub fn parse_line(&self, line: &str) -> Option<Result<IpNet, String>> {
+        let line = line.trim();
+        let entry = match self {
+            ListFormat::Plain | ListFormat::Netset => line.split('#').next().unwrap_or_default(),
+            ListFormat::Drop if line.starts_with('{') => {
+                let value: Value = match serde_json::from_str(line) {
+                    Ok(value) => value,
+                    Err(e) => return Some(Err(e.to_string())),
+                };
+                // The trailing metadata record has no range.
+                return value["cidr"].as_str().map(parse_net);
+            }
+            ListFormat::Drop => line.split(';').next().unwrap_or_default(),
+        }
+        .trim();
+
+        (!entry.is_empty()).then(|| parse_net(entry))
+    }
+}
+
+impl FromStr for ListFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "plain" | "cidr" => Ok(ListFormat::Plain),
+            "netset" | "ipset" | "firehol" => Ok(ListFormat::Netset),
+            "drop" | "spamhaus" => Ok(ListFormat::Drop),
+            _ => Err(format!("unknown blocklist format: {}", s)),
+        }
+    }
+}
+
+fn parse_net(s: &str) -> Result<IpNet, String> {
+    s.parse::<IpNet>()
+        .map(|net| net.trunc())
+        .or_else(|_| s.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| format!("invalid network {}", s))
+}
+
+/// A named blocklist file.
+#[derive(Debug, Clone)]
+pub struct BlocklistSource {
+    pub name: String,
+    pub path: PathBuf,
+    pub format: ListFormat,
+}
+
+#[derive(Debug, Clone)]
+pub struct BlocklistSettings {
+    pub lists: Vec<BlocklistSource>,
+    /// How often the files are checked for changes.
+    pub reload: Duration,
+}
+
+/// One parsed copy of a list file.
+struct Loaded {
+    set: PrefixSet,
+    /// Modification time and size of the file it was read from. The size
+    /// catches a file read while it was still being written.
+    version: Option<(SystemTime, u64)>,
+    /// Unix time it was read, in seconds, zero if it never was.
+    loaded_at: u64,
+}
+
+impl Loaded {
+    fn empty() -> Self {
+        Loaded {
+            set: PrefixSet::new(),
+            version: None,
+            loaded_at: 0,
+        }
+    }
+}
+
+struct List {
+    source: BlocklistSource,
+    loaded: RwLock<Arc<Loaded>>,
+}
+
+impl List {
+    fn current(&self) -> Arc<Loaded> {
+        self.loaded.read().unwrap().clone()
+    }
+
+    /// Reads the file again if it changed since the last load.
+    fn reload(&self) {
+        let source = &self.source;
+        let version = match fs::metadata(&source.path)
+            .and_then(|meta| meta.modified().map(|modified| (modified, meta.len())))
+        {
+            Ok(version) => version,
+            Err(e) => {
+                error!(
+                    "Failed to stat blocklist {} at {}: {}",
+                    source.name,
+                    source.path.display(),
+                    e
+                );
+                return;
+            }
+        };
+        if self.current().version == Some(version) {
+            return;
+        }
+
+        let text = match fs::read_to_string(&source.path) {
+            Ok(text) => text,
+            Err(e) => {
+                error!(
+                    "Failed to read blocklist {} at {}: {}",
+                    source.name,
+                    source.path.display(),
+                    e
+                );
+                return;
+            }
+        };
+
+        let mut invalid = 0;
+        let set: PrefixSet = text
+            .lines()
+            .filter_map(|line| source.format.parse_line(line))
+            .filter_map(|net| {
+                net.map_err(|e| {
+                    if invalid == 0 {
+                        warn!("Blocklist {}: {}, skipping", source.name, e);
+                    }
+                    invalid += 1;
+                })
+                .ok()
+            })
+            .collect();
+        if invalid > 1 {
+            warn!(
+                "Blocklist {}: skipped {} invalid lines",
+                source.name, invalid
+            );
+        }
+
+        info!(
+            "Loaded blocklist {} with {} networks",
+            source.name,
+            set.len()
+        );
+        BLOCKLIST_PREFIXES
+            .with_label_values(&[&source.name])
+            .set(set.len() as i64);
+        *self.loaded.write().unwrap() = Arc::new(Loaded {
+            set,
+            version: Some(version),
+            loaded_at: unix_now(),
+        });
+    }
+}
+
+pub struct Blocklists {
+    settings: BlocklistSettings,
+    lists: Vec<List>,
+}
+
+impl Blocklists {
+    /// Loads every list, a missing or unreadable file starts out empty.
+    pub fn new(settings: BlocklistSettings) -> Self {
+        let lists = settings
+            .lists
+            .iter()
+            .map(|source| {
+                let list = List {
+                    source: source.clone(),
+                    loaded: RwLock::new(Arc::new(Loaded::empty())),
+                };
+                list.reload();
+                list
+            })
+            .collect();
+
+        Blocklists { settings, lists }
+    }
+
+    /// The first list covering `addr`, with the matching network.
+    pub fn lookup(&self, addr: IpAddr) -> Option<(&str, IpNet)> {
+        self.lists.iter().find_map(|list| {
+            list.current()
+                .set
+                .lookup(addr)
+                .map(|net| (list.source.name.as_str(), net))
+        })
+    }
+
+    /// Size and load time of each list as JSON.
+    pub fn report(&self) -> Value {
+        let lists: Vec<Value> = self
+            .lists
+            .iter()
+            .map(|list| {
+                let loaded = list.current();
+                json!({
+                    "name": list.source.name,
+                    "path": list.source.path.display().to_string(),
+                    "format": list.source.format.as_str(),
+                    "prefixes": loaded.set.len(),
+                    "loaded_at": loaded.loaded_at,
+                })
+            })
+            .collect();
+        json!(lists)
+    }
+
+    /// Reloads the lists whose files changed.
+    fn reload(&self) {
+        for list in &self.lists {
+            list.reload();
+        }
+    }
+}
+
+/// Polls the list files and swaps in the ones that changed.
+#[async_trait]
+impl BackgroundService for Blocklists {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        if self.lists.is_empty() {
+            return;
+        }
+
+        let mut period = interval(self.settings.reload);
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => break,
+                _ = period.tick() => self.reload(),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(format: ListFormat, text: &str) -> Vec<Result<String, String>> {
+        text.lines()
+            .filter_map(|line| format.parse_line(line))
+            .map(|net| net.map(|net| net.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn plain_lists_skip_comments_and_truncate_hosts() {
+        let text = "# header\n\n192.0.2.7\n198.51.100.9/24 # inline\n2001:db8::1/32\nnonsense\n";
+        assert_eq!(
+            parsed(ListFormat::Plain, text),
+            vec![
+                Ok("192.0.2.7/32".to_string()),
+                Ok("198.51.100.0/24".to_string()),
+                Ok("2001:db8::/32".to_string()),
+                Err("invalid network nonsense".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn drop_lists_read_both_layouts() {
+        let text = "; Spamhaus DROP List\n\
+                    192.0.2.0/24 ; SBL000001\n\
+                    {\"cidr\":\"198.51.100.0/24\",\"sblid\":\"SBL000002\"}\n\
+                    {\"type\":\"metadata\",\"records\":2}\n";
+        assert_eq!(
+            parsed(ListFormat::Drop, text),
+            vec![
+                Ok("192.0.2.0/24".to_string()),
+                Ok("198.51.100.0/24".to_string()),
+            ]
+        );
+    }
+}
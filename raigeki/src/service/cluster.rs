@@ -0,0 +1,641 @@
+//! Optional cluster mode: nodes behind the same anycast address share their
+//! bans, whitelist entries and attack state.
+//!
+//! Every value written to the ban store is also sent to the static peer list
+//! over UDP, signed with HMAC-SHA256 under `CLUSTER_SECRET`. Each value
+//! carries the time and node it was written at, and a node only applies one
+//! that is newer than what it has, so duplicates and reordered values are
+//! harmless. A datagram not sent after the last one accepted from its node
+//! is dropped as a replay, and the full state is only sent back to a joining
+//! node that is in the peer list. Recent values are sent again every sync interval to make up for
+//! lost datagrams, and every value every few intervals and whenever a node
+//! joins, so a restarted node or one cut off for a while catches up.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use log::{debug, error, info, warn};
+use once_cell::sync::Lazy;
+use pingora::{server::ShutdownWatch, services::background::BackgroundService};
+use prometheus::{register_int_counter_vec, register_int_gauge, IntCounterVec, IntGauge};
+use raigeki_error::Error;
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+use tokio::net::{lookup_host, UdpSocket};
+use tokio::sync::mpsc;
+use tokio::time::interval;
+
+use crate::service::ban_store::{unix_now, BanStore, SnapshotRecord};
+use crate::service::forward::DDOS_MODE;
+
+static CLUSTER_MESSAGES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "cluster_messages_total",
+        "Cluster datagrams by outcome",
+        &["result"]
+    )
+    .unwrap()
+});
+
+/// Peers that reported an attack within the last few sync intervals.
+pub static PEERS_UNDER_ATTACK: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "cluster_peers_under_attack",
+        "Cluster peers currently reporting an attack"
+    )
+    .unwrap()
+});
+
+/// Length of the HMAC-SHA256 tag in front of every datagram.
+const TAG_LEN: usize = 32;
+/// Events are batched into datagrams of about this size.
+const DATAGRAM_BUDGET: usize = 1200;
+/// Datagrams sent further apart from now than this are dropped as replays.
+const MAX_SKEW: Duration = Duration::from_secs(60);
+/// Local writes waiting to be sent, beyond this they are only synced.
+const QUEUE: usize = 4096;
+/// Every this many syncs all values are sent, not only recent ones.
+const FULL_SYNC_EVERY: u32 = 30;
+
+#[derive(Debug, Clone)]
+pub struct ClusterSettings {
+    /// Address gossip is received on, `None` disables cluster mode.
+    pub bind: Option<String>,
+    pub peers: Vec<String>,
+    pub secret: String,
+    pub node_id: String,
+    /// How often recent values and the attack state are sent again.
+    pub sync_interval: Duration,
+}
+
+/// When and where a value was written, later ones win.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+struct Version {
+    /// Unix time, in milliseconds.
+    at: u64,
+    node: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Event {
+    /// A ban store value: a ban, a strike counter or the whitelist.
+    Record {
+        key: String,
+        value: String,
+        /// Unix time the value ends, in seconds.
+        expires_at: u64,
+        version: Version,
+    },
+    /// Whether the sending node is in DDoS mode.
+    Attack { active: bool },
+    /// The sending node just started and wants every value.
+    Join,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Message {
+    node: String,
+    /// Unix time, in milliseconds, increasing with every datagram a node
+    /// sends.
+    sent_at: u64,
+    events: Vec<Event>,
+}
+
+struct Known {
+    value: String,
+    expires_at: u64,
+    version: Version,
+}
+
+/// A ban store that shares its writes with the other nodes and applies
+/// theirs. Without a bind address it only passes calls through.
+pub struct Cluster {
+    inner: Arc<dyn BanStore>,
+    settings: ClusterSettings,
+    key: hmac::Key,
+    known: Mutex<HashMap<String, Known>>,
+    /// Unix time, in seconds, each peer's attack report lasts until.
+    attacks: Mutex<HashMap<String, u64>>,
+    /// `sent_at` of the last datagram accepted from each peer.
+    last_seen: Mutex<HashMap<String, u64>>,
+    /// `sent_at` of the last datagram sent.
+    last_sent: AtomicU64,
+    queue: mpsc::Sender<Event>,
+    outbox: Mutex<Option<mpsc::Receiver<Event>>>,
+}
+
+impl Cluster {
+    pub fn new(inner: Arc<dyn BanStore>, settings: ClusterSettings) -> Self {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, settings.secret.as_bytes());
+        let (queue, outbox) = mpsc::channel(QUEUE);
+        Cluster {
+            inner,
+            settings,
+            key,
+            known: Mutex::new(HashMap::new()),
+            attacks: Mutex::new(HashMap::new()),
+            last_seen: Mutex::new(HashMap::new()),
+            last_sent: AtomicU64::new(0),
+            queue,
+            outbox: Mutex::new(Some(outbox)),
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        self.settings.bind.is_some()
+    }
+
+    /// Peer addresses, resolved again on every sync so DNS changes apply.
+    async fn peers(&self) -> Vec<SocketAddr> {
+        let mut peers = Vec::new();
+        for peer in &self.settings.peers {
+            match lookup_host(peer).await {
+                Ok(mut addrs) => peers.extend(addrs.next()),
+                Err(e) => warn!("Failed to resolve cluster peer {}: {}", peer, e),
+            }
+        }
+        peers
+    }
+
+    /// Records `version` of `key` unless a newer one is known, returning
+    /// whether it was.
+    fn remember(&self, key: &str, value: &str, expires_at: u64, version: Version) -> bool {
+        let mut known = self.known.lock().unwrap();
+        if known
+            .get(key)
+            .is_some_and(|current| current.version >= version)
+        {
+            return false;
+        }
+        known.insert(
+            key.to_string(),
+            Known {
+                value: value.to_string(),
+                expires_at,
+                version,
+            },
+        );
+        true
+    }
+
+    /// Sends `events` to every peer, as few datagrams as fit them.
+    async fn send(&self, socket: &UdpSocket, peers: &[SocketAddr], events: Vec<Event>) {
+        let mut batch = Vec::new();
+        let mut size = 0;
+        for event in events {
+            let event_size = serde_json::to_vec(&event).map_or(0, |bytes| bytes.len());
+            if !batch.is_empty() && size + event_size > DATAGRAM_BUDGET {
+                self.send_batch(socket, peers, std::mem::take(&mut batch))
+                    .await;
+                size = 0;
+            }
+            size += event_size;
+            batch.push(event);
+        }
+        if !batch.is_empty() {
+            self.send_batch(socket, peers, batch).await;
+        }
+    }
+
+    async fn send_batch(&self, socket: &UdpSocket, peers: &[SocketAddr], events: Vec<Event>) {
+        let datagram = self.seal(&Message {
+            node: self.settings.node_id.clone(),
+            sent_at: self.next_sent_at(),
+            events,
+        });
+        for peer in peers {
+            match socket.send_to(&datagram, peer).await {
+                Ok(_) => CLUSTER_MESSAGES.with_label_values(&["sent"]).inc(),
+                Err(e) => {
+                    CLUSTER_MESSAGES.with_label_values(&["send_failed"]).inc();
+                    debug!("Failed to send to cluster peer {}: {}", peer, e);
+                }
+            }
+        }
+    }
+
+    /// The current time, or one past the last datagram sent if that is not
+    /// later, so batches sent in the same millisecond are not replays.
+    fn next_sent_at(&self) -> u64 {
+        let now = unix_millis();
+        let last = self
+            .last_sent
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |last| {
+                Some(now.max(last + 1))
+            })
+            .unwrap();
+        now.max(last + 1)
+    }
+
+    /// Checks and applies one datagram, returning whether its sender asked
+    /// for every value.
+    async fn receive(&self, datagram: &[u8], from: SocketAddr) -> bool {
+        let message = match self.open(datagram) {
+            Ok(message) => message,
+            Err(result) => {
+                CLUSTER_MESSAGES.with_label_values(&[result]).inc();
+                debug!("Dropped cluster datagram from {}: {}", from, result);
+                return false;
+            }
+        };
+        CLUSTER_MESSAGES.with_label_values(&["accepted"]).inc();
+
+        let now = unix_now();
+        let mut joined = false;
+        for event in message.events {
+            match event {
+                Event::Record {
+                    key,
+                    value,
+                    expires_at,
+                    version,
+                } => {
+                    if expires_at <= now || !self.remember(&key, &value, expires_at, version) {
+                        continue;
+                    }
+                    let ttl = u32::try_from(expires_at - now).unwrap_or(u32::MAX);
+                    if let Err(e) = self.inner.set(&key, &value, ttl).await {
+                        error!("Failed to apply {} from {}: {}", key, message.node, e);
+                    }
+                }
+                Event::Attack { active } => {
+                    let mut attacks = self.attacks.lock().unwrap();
+                    if active {
+                        let lasts = self.settings.sync_interval.as_secs().max(1) * 3;
+                        if attacks.insert(message.node.clone(), now + lasts).is_none() {
+                            warn!("Cluster peer {} reports an attack", message.node);
+                        }
+                    } else if attacks.remove(&message.node).is_some() {
+                        info!("Cluster peer {} no longer under attack", message.node);
+                    }
+                    PEERS_UNDER_ATTACK.set(attacks.len() as i64);
+                }
+                Event::Join => {
+                    info!("Cluster peer {} joined, sending every value", message.node);
+                    joined = true;
+                }
+            }
+        }
+        joined
+    }
+
+    /// Signs `message` into a datagram.
+    fn seal(&self, message: &Message) -> Vec<u8> {
+        let body = serde_json::to_vec(message).unwrap();
+        let mut datagram = hmac::sign(&self.key, &body).as_ref().to_vec();
+        datagram.extend_from_slice(&body);
+        datagram
+    }
+
+    /// Verifies a datagram, or names why it was dropped.
+    fn open(&self, datagram: &[u8]) -> Result<Message, &'static str> {
+        if datagram.len() <= TAG_LEN {
+            return Err("malformed");
+        }
+        let (tag, body) = datagram.split_at(TAG_LEN);
+        hmac::verify(&self.key, body, tag).map_err(|_| "unauthenticated")?;
+
+        let message: Message = serde_json::from_slice(body).map_err(|_| "malformed")?;
+        if message.node == self.settings.node_id {
+            return Err("own");
+        }
+        if unix_millis().abs_diff(message.sent_at) > MAX_SKEW.as_millis() as u64 {
+            return Err("stale");
+        }
+
+        let mut last_seen = self.last_seen.lock().unwrap();
+        if last_seen
+            .get(&message.node)
+            .is_some_and(|last| *last >= message.sent_at)
+        {
+            return Err("replayed");
+        }
+        last_seen.insert(message.node.clone(), message.sent_at);
+        Ok(message)
+    }
+
+    /// Values changed within the last two sync intervals, or every value if
+    /// `full`, and the local attack state. Forgets expired values and attack
+    /// reports.
+    fn state(&self, full: bool) -> Vec<Event> {
+        let now = unix_now();
+        let since = if full {
+            0
+        } else {
+            unix_millis().saturating_sub(2 * self.settings.sync_interval.as_millis() as u64)
+        };
+
+        let mut known = self.known.lock().unwrap();
+        known.retain(|_, known| known.expires_at > now);
+        let mut events: Vec<Event> = known
+            .iter()
+            .filter(|(_, known)| known.version.at >= since)
+            .map(|(key, known)| Event::Record {
+                key: key.clone(),
+                value: known.value.clone(),
+                expires_at: known.expires_at,
+                version: known.version.clone(),
+            })
+            .collect();
+        drop(known);
+
+        let mut attacks = self.attacks.lock().unwrap();
+        attacks.retain(|_, until| *until > now);
+        PEERS_UNDER_ATTACK.set(attacks.len() as i64);
+
+        events.push(Event::Attack {
+            active: DDOS_MODE.get() == 1,
+        });
+        events
+    }
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[async_trait]
+impl BanStore for Cluster {
+    async fn get_many(&self, keys: &[&str]) -> Result<HashMap<String, String>, Error> {
+        self.inner.get_many(keys).await
+    }
+
+    async fn set(&self, key: &str, value: &str, ttl: u32) -> Result<(), Error> {
+        self.inner.set(key, value, ttl).await?;
+        if !self.enabled() {
+            return Ok(());
+        }
+
+        let expires_at = unix_now() + u64::from(ttl);
+        let version = Version {
+            at: unix_millis(),
+            node: self.settings.node_id.clone(),
+        };
+        if self.remember(key, value, expires_at, version.clone()) {
+            // A full queue only delays the value until the next sync.
+            let _ = self.queue.try_send(Event::Record {
+                key: key.to_string(),
+                value: value.to_string(),
+                expires_at,
+                version,
+            });
+        }
+        Ok(())
+    }
+
+    async fn dump(&self) -> Result<Option<Vec<SnapshotRecord>>, Error> {
+        self.inner.dump().await
+    }
+}
+
+/// Receives the peers' datagrams and sends ours.
+#[async_trait]
+impl BackgroundService for Cluster {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let Some(bind) = &self.settings.bind else {
+            return;
+        };
+        let socket = match UdpSocket::bind(bind).await {
+            Ok(socket) => socket,
+            Err(e) => {
+                error!("Failed to bind cluster socket {}: {}", bind, e);
+                return;
+            }
+        };
+        let Some(mut outbox) = self.outbox.lock().unwrap().take() else {
+            return;
+        };
+        info!(
+            "cluster node {} on {} with peers {:?}",
+            self.settings.node_id, bind, self.settings.peers
+        );
+
+        let mut peers = self.peers().await;
+        // Ask the others for what we missed while down.
+        self.send(&socket, &peers, vec![Event::Join]).await;
+
+        let mut buf = vec![0; u16::MAX as usize];
+        let mut sync = interval(self.settings.sync_interval);
+        let mut syncs: u32 = 0;
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => break,
+                Some(event) = outbox.recv() => {
+                    let mut events = vec![event];
+                    while let Ok(event) = outbox.try_recv() {
+                        events.push(event);
+                    }
+                    self.send(&socket, &peers, events).await;
+                }
+                received = socket.recv_from(&mut buf) => match received {
+                    Ok((len, from)) => {
+                        if !self.receive(&buf[..len], from).await {
+                            continue;
+                        }
+                        // The source address can be forged, only known peers
+                        // get the whole state.
+                        if peers.contains(&from) {
+                            let events = self.state(true);
+                            self.send(&socket, &[from], events).await;
+                        } else {
+                            warn!("Cluster join from {} which is not a peer, ignored", from);
+                        }
+                    }
+                    Err(e) => debug!("Failed to receive cluster datagram: {}", e),
+                },
+                _ = sync.tick() => {
+                    peers = self.peers().await;
+                    let events = self.state(syncs.is_multiple_of(FULL_SYNC_EVERY));
+                    syncs = syncs.wrapping_add(1);
+                    self.send(&socket, &peers, events).await;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::ban_store::MemoryBanStore;
+    use tokio::sync::watch;
+    use tokio::time::sleep;
+
+    fn node(
+        node_id: &str,
+        secret: &str,
+        bind: Option<SocketAddr>,
+        peer: Option<SocketAddr>,
+    ) -> Arc<Cluster> {
+        let settings = ClusterSettings {
+            bind: bind.map(|bind| bind.to_string()),
+            peers: peer.iter().map(SocketAddr::to_string).collect(),
+            secret: secret.to_string(),
+            node_id: node_id.to_string(),
+            sync_interval: Duration::from_secs(1),
+        };
+        Arc::new(Cluster::new(Arc::new(MemoryBanStore::new()), settings))
+    }
+
+    fn message(node: &str, sent_at: u64, events: Vec<Event>) -> Message {
+        Message {
+            node: node.to_string(),
+            sent_at,
+            events,
+        }
+    }
+
+    fn record(value: &str, at: u64, node: &str) -> Event {
+        Event::Record {
+            key: "ban:192.0.2.1".to_string(),
+            value: value.to_string(),
+            expires_at: unix_now() + 60,
+            version: Version {
+                at,
+                node: node.to_string(),
+            },
+        }
+    }
+
+    /// A free localhost UDP port.
+    fn free_addr() -> SocketAddr {
+        std::net::UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+    }
+
+    async fn wait_for(store: &Cluster, key: &str, value: &str) -> bool {
+        for _ in 0..50 {
+            let values = store.get_many(&[key]).await.unwrap();
+            if values.get(key).map(String::as_str) == Some(value) {
+                return true;
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+        false
+    }
+
+    #[test]
+    fn only_datagrams_signed_with_the_secret_open() {
+        let a = node("a", "secret", None, None);
+        let b = node("b", "secret", None, None);
+        let other = node("c", "other secret", None, None);
+
+        let datagram = a.seal(&message("a", unix_millis(), vec![Event::Join]));
+        assert!(b.open(&datagram).is_ok());
+        assert_eq!(a.open(&datagram).err(), Some("own"));
+        assert_eq!(other.open(&datagram).err(), Some("unauthenticated"));
+
+        let mut tampered = datagram.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert_eq!(b.open(&tampered).err(), Some("unauthenticated"));
+        assert_eq!(b.open(&datagram[..TAG_LEN]).err(), Some("malformed"));
+    }
+
+    #[test]
+    fn skewed_datagrams_are_dropped() {
+        let a = node("a", "secret", None, None);
+        let b = node("b", "secret", None, None);
+        let skew = MAX_SKEW.as_millis() as u64;
+
+        for sent_at in [unix_millis() - skew - 1000, unix_millis() + skew + 1000] {
+            let datagram = a.seal(&message("a", sent_at, vec![]));
+            assert_eq!(b.open(&datagram).err(), Some("stale"));
+        }
+        let datagram = a.seal(&message("a", unix_millis() - skew / 2, vec![]));
+        assert!(b.open(&datagram).is_ok());
+    }
+
+    #[test]
+    fn replayed_datagrams_are_dropped() {
+        let a = node("a", "secret", None, None);
+        let b = node("b", "secret", None, None);
+
+        let now = unix_millis();
+        let first = a.seal(&message("a", now, vec![Event::Join]));
+        let second = a.seal(&message("a", now + 1, vec![Event::Join]));
+        assert!(b.open(&first).is_ok());
+        assert_eq!(b.open(&first).err(), Some("replayed"));
+        assert!(b.open(&second).is_ok());
+        assert_eq!(b.open(&first).err(), Some("replayed"));
+
+        // Batches sent in the same millisecond still go out in order.
+        let sent: Vec<u64> = (0..100).map(|_| a.next_sent_at()).collect();
+        assert!(sent.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[tokio::test]
+    async fn joins_from_strangers_get_nothing() {
+        let (addr_a, addr_b) = (free_addr(), free_addr());
+        let a = node("a", "secret", None, None);
+        let b = node("b", "secret", Some(addr_b), Some(addr_a));
+        let (stop, shutdown) = watch::channel(false);
+        b.set("ban:192.0.2.1", "banned", 60).await.unwrap();
+
+        let node_b = b.clone();
+        tokio::spawn(async move { node_b.start(shutdown).await });
+        sleep(Duration::from_millis(100)).await;
+
+        // Signed by a node, sent from an address that is not a peer.
+        let stranger = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let join = a.seal(&message("a", unix_millis(), vec![Event::Join]));
+        stranger.send_to(&join, addr_b).await.unwrap();
+        let mut buf = [0; 64];
+        let reply = tokio::time::timeout(Duration::from_millis(500), stranger.recv_from(&mut buf));
+        assert!(reply.await.is_err());
+
+        let _ = stop.send(true);
+    }
+
+    #[tokio::test]
+    async fn later_versions_win() {
+        let a = node("a", "secret", None, None);
+        let b = node("b", "secret", None, None);
+        let from = free_addr();
+        let key = "ban:192.0.2.1";
+
+        let now = unix_millis();
+        let newer = a.seal(&message("a", now, vec![record("newer", now, "a")]));
+        let older = a.seal(&message("a", now + 1, vec![record("older", now - 1, "a")]));
+        b.receive(&newer, from).await;
+        b.receive(&older, from).await;
+        assert!(wait_for(&b, key, "newer").await);
+
+        // Writes in the same millisecond are ordered by node.
+        let tie = a.seal(&message("a", now + 2, vec![record("tie", now, "c")]));
+        b.receive(&tie, from).await;
+        assert!(wait_for(&b, key, "tie").await);
+    }
+
+    #[tokio::test]
+    async fn two_nodes_share_bans() {
+        let (addr_a, addr_b) = (free_addr(), free_addr());
+        let a = node("a", "secret", Some(addr_a), Some(addr_b));
+        let b = node("b", "secret", Some(addr_b), Some(addr_a));
+        let (stop, shutdown) = watch::channel(false);
+
+        let node_a = a.clone();
+        let watch_a = shutdown.clone();
+        tokio::spawn(async move { node_a.start(watch_a).await });
+        a.set("ban:192.0.2.1", "before", 60).await.unwrap();
+
+        // B only hears of the first ban through the state sent on its join.
+        let node_b = b.clone();
+        tokio::spawn(async move { node_b.start(shutdown).await });
+        assert!(wait_for(&b, "ban:192.0.2.1", "before").await);
+
+        b.set("ban:192.0.2.2", "after", 60).await.unwrap();
+        assert!(wait_for(&a, "ban:192.0.2.2", "after").await);
+
+        let _ = stop.send(true);
+    }
+}
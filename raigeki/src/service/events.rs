@@ -0,0 +1,369 @@
+//! Structured per-connection events.
+//!
+//! Every connection gets a session ID and produces JSON lines for its accept,
+//! verdict, chosen backend and close. Lines are queued to a writer thread so
+//! a slow sink never stalls a session; when the queue is full they are
+//! dropped and counted.
+
+use std::fmt::Display;
+use std::fs::{self, OpenOptions};
+use std::hash::{BuildHasher, RandomState};
+use std::io::{self, BufWriter, Write};
+use std::net::SocketAddr;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use log::error;
+use once_cell::sync::{Lazy, OnceCell};
+use prometheus::{register_int_counter, register_int_counter_vec, IntCounter, IntCounterVec};
+use raigeki_error::{Error, Reason};
+use serde_json::{json, Value};
+
+static EVENTS_DROPPED: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "events_dropped_total",
+        "Connection events lost to a full queue or a failing sink"
+    )
+    .unwrap()
+});
+
+static CONNECTION_VERDICTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "connection_verdicts_total",
+        "Connections let through or turned away, by listener, verdict and reason",
+        &["listener", "verdict", "reason"]
+    )
+    .unwrap()
+});
+
+static QUEUE: OnceCell<SyncSender<String>> = OnceCell::new();
+
+/// How long to wait before reconnecting to a Unix socket sink that failed.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Where event lines are written.
+#[derive(Debug, Clone)]
+pub enum EventSink {
+    None,
+    Stdout,
+    /// Rotated to `<path>.1` … `<path>.<keep>` once it reaches `max_bytes`.
+    File {
+        path: PathBuf,
+        max_bytes: u64,
+        keep: usize,
+    },
+    Unix(PathBuf),
+}
+
+impl FromStr for EventSink {
+    type Err = String;
+
+    /// Parses `none`, `stdout`, `file:<path>` or `unix:<path>`. File rotation
+    /// limits are filled in by the caller.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "none" => Ok(EventSink::None),
+            None if s == "stdout" => Ok(EventSink::Stdout),
+            Some(("file", path)) => Ok(EventSink::File {
+                path: path.into(),
+                max_bytes: 0,
+                keep: 0,
+            }),
+            Some(("unix", path)) => Ok(EventSink::Unix(path.into())),
+            _ => Err(format!("invalid event sink {}", s)),
+        }
+    }
+}
+
+/// Starts the writer thread. Events emitted before this are discarded.
+pub fn init(sink: EventSink, queue: usize) {
+    if matches!(sink, EventSink::None) {
+        return;
+    }
+
+    let (tx, rx) = sync_channel(queue);
+    if QUEUE.set(tx).is_err() {
+        return;
+    }
+
+    thread::Builder::new()
+        .name("events".to_string())
+        .spawn(move || write_events(sink, rx))
+        .expect("spawn event writer");
+}
+
+fn emit(mut event: Value) {
+    let Some(queue) = QUEUE.get() else {
+        return;
+    };
+
+    let ts = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+    event["ts_ms"] = ts.into();
+
+    match queue.try_send(event.to_string()) {
+        Ok(()) => {}
+        Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => EVENTS_DROPPED.inc(),
+    }
+}
+
+fn write_events(sink: EventSink, rx: Receiver<String>) {
+    let mut writer = SinkWriter::new(sink);
+
+    while let Ok(line) = rx.recv() {
+        writer.write(&line);
+        // Batch whatever else is already queued before flushing.
+        while let Ok(line) = rx.try_recv() {
+            writer.write(&line);
+        }
+        writer.flush();
+    }
+}
+
+struct SinkWriter {
+    sink: EventSink,
+    out: Option<Box<dyn Write + Send>>,
+    written: u64,
+    retry_at: Option<Instant>,
+}
+
+impl SinkWriter {
+    fn new(sink: EventSink) -> Self {
+        SinkWriter {
+            sink,
+            out: None,
+            written: 0,
+            retry_at: None,
+        }
+    }
+
+    fn open(&mut self) -> io::Result<Box<dyn Write + Send>> {
+        match &self.sink {
+            EventSink::None => Ok(Box::new(io::sink())),
+            EventSink::Stdout => Ok(Box::new(io::stdout())),
+            EventSink::File { path, .. } => {
+                let file = OpenOptions::new().create(true).append(true).open(path)?;
+                self.written = file.metadata()?.len();
+                Ok(Box::new(BufWriter::new(file)))
+            }
+            EventSink::Unix(path) => Ok(Box::new(BufWriter::new(UnixStream::connect(path)?))),
+        }
+    }
+
+    fn write(&mut self, line: &str) {
+        if self.out.is_none() {
+            if self.retry_at.is_some_and(|at| Instant::now() < at) {
+                EVENTS_DROPPED.inc();
+                return;
+            }
+            match self.open() {
+                Ok(out) => self.out = Some(out),
+                Err(e) => {
+                    error!("Failed to open event sink {:?}: {}", self.sink, e);
+                    self.retry_at = Some(Instant::now() + RECONNECT_DELAY);
+                    EVENTS_DROPPED.inc();
+                    return;
+                }
+            }
+        }
+
+        let out = self.out.as_mut().unwrap();
+        if let Err(e) = writeln!(out, "{}", line) {
+            error!("Failed to write event: {}", e);
+            self.out = None;
+            self.retry_at = Some(Instant::now() + RECONNECT_DELAY);
+            EVENTS_DROPPED.inc();
+            return;
+        }
+
+        self.written += line.len() as u64 + 1;
+        self.rotate();
+    }
+
+    fn flush(&mut self) {
+        if let Some(out) = self.out.as_mut() {
+            if let Err(e) = out.flush() {
+                error!("Failed to flush events: {}", e);
+                self.out = None;
+            }
+        }
+    }
+
+    fn rotate(&mut self) {
+        let EventSink::File {
+            path,
+            max_bytes,
+            keep,
+        } = &self.sink
+        else {
+            return;
+        };
+        if *max_bytes == 0 || self.written < *max_bytes {
+            return;
+        }
+
+        // Dropping the writer flushes it; the next write reopens the path.
+        self.out = None;
+
+        let rotated = |n: usize| PathBuf::from(format!("{}.{}", path.display(), n));
+        if *keep == 0 {
+            let _ = fs::remove_file(path);
+        } else {
+            for n in (1..*keep).rev() {
+                let _ = fs::rename(rotated(n), rotated(n + 1));
+            }
+            if let Err(e) = fs::rename(path, rotated(1)) {
+                error!("Failed to rotate {}: {}", path.display(), e);
+            }
+        }
+        self.written = 0;
+    }
+}
+
+/// Identifies one connection across all of its events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionId(u64);
+
+impl SessionId {
+    pub fn next() -> Self {
+        // Random start so IDs do not repeat across restarts.
+        static NEXT: Lazy<AtomicU64> =
+            Lazy::new(|| AtomicU64::new(RandomState::new().hash_one(std::process::id())));
+        SessionId(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl Display for SessionId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+/// Event trail of one connection. The close event is emitted on drop.
+///
+/// Each connection gets exactly one verdict: `allow` once it reaches a
+/// backend, or `reject` with the reason it was turned away.
+pub struct SessionEvents {
+    id: SessionId,
+    listener: String,
+    accepted_at: Instant,
+    cause: &'static str,
+    bytes_in: u64,
+    bytes_out: u64,
+}
+
+impl SessionEvents {
+    pub fn accept(listener: &str, protocol: &str, peer: SocketAddr) -> Self {
+        let id = SessionId::next();
+        emit(json!({
+            "event": "accept",
+            "session": id.to_string(),
+            "listener": listener,
+            "protocol": protocol,
+            "peer": peer.to_string(),
+        }));
+
+        SessionEvents {
+            id,
+            listener: listener.to_string(),
+            accepted_at: Instant::now(),
+            cause: "closed",
+            bytes_in: 0,
+            bytes_out: 0,
+        }
+    }
+
+    pub fn id(&self) -> SessionId {
+        self.id
+    }
+
+    pub fn allow(&self, reason: Reason, rule: impl Display) {
+        self.verdict("allow", reason, rule);
+    }
+
+    /// Records a rejection; the session is expected to end right after.
+    pub fn reject(&mut self, reason: Reason, rule: impl Display) {
+        self.verdict("reject", reason, rule);
+        self.cause = reason.as_str();
+    }
+
+    /// Records a rejection caused by `e`, with the rule it matched.
+    pub fn reject_error(&mut self, e: &Error) {
+        self.reject(e.reason(), matched_rule(e));
+    }
+
+    /// Records a rejected connection that is held in the tarpit instead of
+    /// being disconnected.
+    pub fn tarpit(&mut self, reason: Reason, rule: impl Display) {
+        self.verdict("tarpit", reason, rule);
+        self.cause = "tarpitted";
+    }
+
+    fn verdict(&self, verdict: &str, reason: Reason, rule: impl Display) {
+        CONNECTION_VERDICTS
+            .with_label_values(&[&self.listener, verdict, reason.as_str()])
+            .inc();
+        emit(json!({
+            "event": "verdict",
+            "session": self.id.to_string(),
+            "verdict": verdict,
+            "reason": reason.as_str(),
+            "rule": rule.to_string(),
+        }));
+    }
+
+    pub fn backend(&self, addr: SocketAddr) {
+        emit(json!({
+            "event": "backend",
+            "session": self.id.to_string(),
+            "backend": addr.to_string(),
+        }));
+    }
+
+    pub fn transferred(&mut self, bytes_in: u64, bytes_out: u64) {
+        self.bytes_in += bytes_in;
+        self.bytes_out += bytes_out;
+    }
+
+    /// Sets the cause reported by the close event.
+    pub fn close(&mut self, cause: &'static str) {
+        self.cause = cause;
+    }
+}
+
+/// The rule a connection that failed with `e` matched.
+pub fn matched_rule(e: &Error) -> String {
+    match e {
+        Error::IpBlockedInCache(addr, _) | Error::ScannerBlockedInCache(addr, _) => {
+            format!("ip:{}", addr)
+        }
+        Error::SubnetBlockedInCache(_, subnet, _) => format!("subnet:{}", subnet),
+        Error::Blocklisted(_, list, net) => format!("blocklist:{}:{}", list, net),
+        Error::AsnBlocked(_, Some(asn)) => format!("asn:{}", asn),
+        Error::AsnBlocked(_, None) => "asn:unknown".to_string(),
+        Error::CountryBlocked(_, Some(country)) => format!("country:{}", country),
+        Error::CountryBlocked(_, None) => "country:unknown".to_string(),
+        Error::Timeout(stage) => stage.to_string(),
+        e => e.to_string(),
+    }
+}
+
+impl Drop for SessionEvents {
+    fn drop(&mut self) {
+        emit(json!({
+            "event": "close",
+            "session": self.id.to_string(),
+            "cause": self.cause,
+            "bytes_in": self.bytes_in,
+            "bytes_out": self.bytes_out,
+            "duration_ms": self.accepted_at.elapsed().as_millis() as u64,
+        }));
+    }
+}
@@ -0,0 +1,591 @@
+//! Threat feeds: ASN and IP lists fetched on a schedule.
+//!
+//! Every feed is a named source, a URL or a local file, with its own format,
+//! refresh interval and exclusions. ASN feeds are merged into one set checked
+//! next to `BLOCKED_ASN`, IP feeds into one prefix set checked next to the
+//! blocklists. The last good copy of each remote feed is kept in the cache
+//! directory, so a restart without network still starts with the lists.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use ipnet::IpNet;
+use log::{error, info, warn};
+use once_cell::sync::Lazy;
+use pingora::{server::ShutdownWatch, services::background::BackgroundService};
+use prometheus::{register_int_gauge_vec, IntGaugeVec};
+use raigeki::prefix::PrefixSet;
+use raigeki_error::Error;
+use raigeki_tools::download::{download, DownloadSettings, Fetched};
+use serde_json::{json, Value};
+use tokio::task::spawn_blocking;
+use tokio::time::interval;
+
+use crate::service::ban_store::unix_now;
+use crate::service::blocklist::ListFormat;
+
+static FEED_ENTRIES: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "feed_entries",
+        "ASNs or networks in the last good copy of each feed",
+        &["feed"]
+    )
+    .unwrap()
+});
+
+static FEED_UPDATED_AT: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "feed_updated_at",
+        "Unix time the last good copy of each feed was fetched",
+        &["feed"]
+    )
+    .unwrap()
+});
+
+/// How soon a failed fetch is retried, unless the feed refreshes sooner.
+const RETRY_AFTER: Duration = Duration::from_secs(5 * 60);
+
+/// What a feed lists.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FeedFormat {
+    /// `AS<number>` tokens anywhere on a line, or a bare number per line.
+    Asn,
+    /// Networks, in any of the blocklist file formats.
+    Ip(ListFormat),
+}
+
+impl FeedFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FeedFormat::Asn => "asn",
+            FeedFormat::Ip(format) => format.as_str(),
+        }
+    }
+
+    fn parse(&self, name: &str, text: &str) -> Entries {
+        let mut entries = Entries::default();
+        match self {
+            FeedFormat::Asn => {
+                for line in text.lines() {
+                    let line = line.split('#').next().unwrap_or_default().trim();
+                    let before = entries.asns.len();
+                    entries.asns.extend(
+                        line.split(|c: char| !c.is_ascii_alphanumeric())
+                            .filter_map(|token| {
+                                token
+                                    .strip_prefix("AS")
+                                    .or_else(|| token.strip_prefix("as"))
+                            })
+                            .filter_map(|number| number.parse::<u32>().ok()),
+                    );
+                    if entries.asns.len() == before {
+                        entries.asns.extend(line.parse::<u32>().ok());
+                    }
+                }
+            }
+            FeedFormat::Ip(format) => {
+                let mut invalid = 0;
+                for net in text.lines().filter_map(|line| format.parse_line(line)) {
+                    match net {
+                        Ok(net) => entries.nets.push(net),
+                        Err(e) => {
+                            if invalid == 0 {
+                                warn!("Feed {}: {}, skipping", name, e);
+                            }
+                            invalid += 1;
+                        }
+                    }
+                }
+                if invalid > 1 {
+                    warn!("Feed {}: skipped {} invalid lines", name, invalid);
+                }
+            }
+        }
+        entries
+    }
+}
+
+impl FromStr for FeedFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "asn" => Ok(FeedFormat::Asn),
+            _ => s
+                .parse()
+                .map(FeedFormat::Ip)
+                .map_err(|_| format!("unknown feed format: {}", s)),
+        }
+    }
+}
+
+/// Where a feed is read from.
+#[derive(Debug, Clone)]
+pub enum FeedLocation {
+    Url(DownloadSettings),
+    File(PathBuf),
+}
+
+/// Entries a feed must never contribute, whatever the upstream list says.
+#[derive(Debug, Clone, Default)]
+pub struct Exclusions {
+    pub asns: Vec<u32>,
+    pub nets: Vec<IpNet>,
+}
+
+#[derive(Debug, Clone)]
+pub struct FeedSource {
+    pub name: String,
+    pub location: FeedLocation,
+    pub format: FeedFormat,
+    pub refresh: Duration,
+    pub exclude: Exclusions,
+}
+
+#[derive(Debug, Clone)]
+pub struct FeedSettings {
+    pub sources: Vec<FeedSource>,
+    /// Where the last good copy of each remote feed is kept.
+    pub cache_dir: PathBuf,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Entries {
+    asns: Vec<u32>,
+    nets: Vec<IpNet>,
+}
+
+impl Entries {
+    fn len(&self) -> usize {
+        self.asns.len() + self.nets.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.asns.is_empty() && self.nets.is_empty()
+    }
+
+    /// Sorts, deduplicates and drops the excluded entries.
+    fn normalize(mut self, exclude: &Exclusions) -> Self {
+        self.asns.retain(|asn| !exclude.asns.contains(asn));
+        self.asns.sort_unstable();
+        self.asns.dedup();
+
+        for hole in &exclude.nets {
+            self.nets = self
+                .nets
+                .into_iter()
+                .flat_map(|net| carve(net, hole))
+                .collect();
+        }
+        self.nets.sort_unstable();
+        self.nets.dedup();
+        self
+    }
+}
+
+/// `net` without `hole`, as the networks left around it.
+fn carve(net: IpNet, hole: &IpNet) -> Vec<IpNet> {
+    if hole.contains(&net) {
+        return Vec::new();
+    }
+    if !net.contains(hole) {
+        return vec![net];
+    }
+
+    let mut left = Vec::new();
+    let mut at = net;
+    while at.prefix_len() < hole.prefix_len() {
+        // Both halves exist, `at` is shorter than `hole`.
+        for half in at.subnets(at.prefix_len() + 1).unwrap() {
+            if half.contains(hole) {
+                at = half;
+            } else {
+                left.push(half);
+            }
+        }
+    }
+    left
+}
+
+#[derive(Debug, Default)]
+struct FeedState {
+    entries: Entries,
+    /// Unix time of the last good copy, zero if there is none.
+    updated_at: u64,
+    /// Unix time of the last fetch, good or not.
+    checked_at: u64,
+    error: Option<String>,
+}
+
+struct Feed {
+    source: FeedSource,
+    state: RwLock<FeedState>,
+}
+
+impl Feed {
+    /// Next unix time the feed should be fetched.
+    fn due_at(&self) -> u64 {
+        let state = self.state.read().unwrap();
+        let wait = match state.error {
+            Some(_) => self.source.refresh.min(RETRY_AFTER),
+            None => self.source.refresh,
+        };
+        state.checked_at.max(state.updated_at) + wait.as_secs()
+    }
+
+    fn export(&self) {
+        let state = self.state.read().unwrap();
+        let name = self.source.name.as_str();
+        FEED_ENTRIES
+            .with_label_values(&[name])
+            .set(state.entries.len() as i64);
+        FEED_UPDATED_AT
+            .with_label_values(&[name])
+            .set(state.updated_at as i64);
+    }
+
+    fn report(&self, now: u64) -> Value {
+        let state = self.state.read().unwrap();
+        let (kind, location) = match &self.source.location {
+            FeedLocation::Url(download) => ("urls", json!(download.urls)),
+            FeedLocation::File(path) => ("path", json!(path.display().to_string())),
+        };
+        json!({
+            "name": self.source.name,
+            kind: location,
+            "format": self.source.format.as_str(),
+            "refresh_secs": self.source.refresh.as_secs(),
+            "entries": state.entries.len(),
+            "updated_at": state.updated_at,
+            "age_secs": (state.updated_at > 0).then(|| now.saturating_sub(state.updated_at)),
+            "checked_at": state.checked_at,
+            "error": state.error,
+        })
+    }
+}
+
+/// Feed entries merged across every feed.
+#[derive(Default)]
+struct Merged {
+    asns: HashSet<u32>,
+    nets: PrefixSet,
+    /// First feed listing each network in `nets`.
+    owners: HashMap<IpNet, String>,
+}
+
+pub struct Feeds {
+    settings: FeedSettings,
+    feeds: Vec<Feed>,
+    merged: RwLock<Arc<Merged>>,
+}
+
+impl Feeds {
+    /// Starts from the cached copies of remote feeds and the current contents
+    /// of local ones. Fetching is left to the background service.
+    pub fn new(settings: FeedSettings) -> Self {
+        if !settings.sources.is_empty() {
+            if let Err(e) = fs::create_dir_all(&settings.cache_dir) {
+                error!(
+                    "Failed to create feed cache {}: {}",
+                    settings.cache_dir.display(),
+                    e
+                );
+            }
+        }
+
+        let feeds = settings
+            .sources
+            .iter()
+            .map(|source| {
+                let path = match &source.location {
+                    FeedLocation::Url(_) => cache_path(&settings.cache_dir, &source.name),
+                    FeedLocation::File(path) => path.clone(),
+                };
+                let mut state = FeedState::default();
+                match read(source, &path) {
+                    Ok(entries) => {
+                        info!(
+                            "Loaded feed {} with {} entries from {}",
+                            source.name,
+                            entries.len(),
+                            path.display()
+                        );
+                        state.updated_at = modified_at(&path);
+                        state.entries = entries;
+                    }
+                    Err(e) => warn!("Feed {} has no usable copy yet: {}", source.name, e),
+                }
+                let feed = Feed {
+                    source: source.clone(),
+                    state: RwLock::new(state),
+                };
+                feed.export();
+                feed
+            })
+            .collect();
+
+        let feeds = Feeds {
+            settings,
+            feeds,
+            merged: RwLock::new(Arc::default()),
+        };
+        feeds.merge();
+        feeds
+    }
+
+    /// Whether any ASN feed lists `asn`.
+    pub fn asn_listed(&self, asn: u32) -> bool {
+        self.merged.read().unwrap().asns.contains(&asn)
+    }
+
+    /// The feed and network covering `addr`, if any IP feed lists it.
+    pub fn lookup(&self, addr: IpAddr) -> Option<(String, IpNet)> {
+        let merged = self.merged.read().unwrap().clone();
+        let net = merged.nets.lookup(addr)?;
+        let owner = merged.owners.get(&net).cloned().unwrap_or_default();
+        Some((owner, net))
+    }
+
+    /// Size and freshness of every feed as JSON.
+    pub fn report(&self) -> Value {
+        let now = unix_now();
+        let merged = self.merged.read().unwrap().clone();
+        json!({
+            "asns": merged.asns.len(),
+            "networks": merged.nets.len(),
+            "feeds": self.feeds.iter().map(|feed| feed.report(now)).collect::<Vec<_>>(),
+        })
+    }
+
+    /// Fetches every feed that is due, returning whether any changed.
+    async fn refresh(&self) -> bool {
+        let now = unix_now();
+        let mut changed = false;
+        for feed in self.feeds.iter().filter(|feed| feed.due_at() <= now) {
+            let fetched = fetch(feed.source.clone(), &self.settings.cache_dir).await;
+
+            let mut state = feed.state.write().unwrap();
+            state.checked_at = unix_now();
+            match fetched {
+                Ok(None) => state.error = None,
+                Ok(Some(entries)) => {
+                    info!(
+                        "Fetched feed {} with {} entries",
+                        feed.source.name,
+                        entries.len()
+                    );
+                    state.entries = entries;
+                    state.updated_at = state.checked_at;
+                    state.error = None;
+                    changed = true;
+                }
+                Err(e) => {
+                    error!(
+                        "Failed to fetch feed {}, keeping the last good copy: {}",
+                        feed.source.name, e
+                    );
+                    state.error = Some(e.to_string());
+                }
+            }
+            drop(state);
+            feed.export();
+        }
+        changed
+    }
+
+    /// Rebuilds the merged sets from every feed's last good copy.
+    fn merge(&self) {
+        let mut merged = Merged::default();
+        let mut nets = Vec::new();
+        for feed in &self.feeds {
+            let state = feed.state.read().unwrap();
+            merged.asns.extend(&state.entries.asns);
+            for net in &state.entries.nets {
+                merged
+                    .owners
+                    .entry(*net)
+                    .or_insert_with(|| feed.source.name.clone());
+                nets.push(*net);
+            }
+        }
+        merged.nets = nets.into_iter().collect();
+        // Networks covered by a shorter one never match, forget their owners.
+        let kept = &merged.nets;
+        merged
+            .owners
+            .retain(|net, _| kept.lookup(net.network()) == Some(*net));
+
+        *self.merged.write().unwrap() = Arc::new(merged);
+    }
+}
+
+fn cache_path(cache_dir: &Path, name: &str) -> PathBuf {
+    cache_dir.join(format!("{}.list", name))
+}
+
+fn modified_at(path: &Path) -> u64 {
+    fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |age| age.as_secs())
+}
+
+/// Parses a copy of a feed. An empty one is refused, it is far more likely
+/// a broken upstream than a list that was emptied.
+fn read(source: &FeedSource, path: &Path) -> Result<Entries, Error> {
+    let text = fs::read_to_string(path)?;
+    let entries = source
+        .format
+        .parse(&source.name, &text)
+        .normalize(&source.exclude);
+    if entries.is_empty() {
+        return Err(Error::InternalError(format!(
+            "{} has no entries",
+            path.display()
+        )));
+    }
+    Ok(entries)
+}
+
+/// Reads a feed from its source, `None` if the remote copy did not change.
+/// A remote copy replaces the cached one only once it parsed.
+async fn fetch(source: FeedSource, cache_dir: &Path) -> Result<Option<Entries>, Error> {
+    let staged = match &source.location {
+        FeedLocation::File(path) => {
+            let path = path.clone();
+            return spawn_blocking(move || read(&source, &path).map(Some))
+                .await
+                .unwrap_or_else(|e| Err(Error::InternalError(e.to_string())));
+        }
+        FeedLocation::Url(settings) => {
+            match download(settings, &cache_path(cache_dir, &source.name)).await? {
+                Fetched::NotModified => return Ok(None),
+                Fetched::Downloaded(staged) => staged,
+            }
+        }
+    };
+
+    spawn_blocking(move || {
+        let entries = read(&source, staged.path())?;
+        staged.commit()?;
+        Ok(Some(entries))
+    })
+    .await
+    .unwrap_or_else(|e| Err(Error::InternalError(e.to_string())))
+}
+
+/// Fetches the feeds as they come due and swaps in the merged result.
+#[async_trait]
+impl BackgroundService for Feeds {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        if self.feeds.is_empty() {
+            return;
+        }
+
+        let mut period = interval(Duration::from_secs(1));
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => break,
+                _ = period.tick() => {
+                    if self.refresh().await {
+                        self.merge();
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nets(nets: &[&str]) -> Vec<IpNet> {
+        nets.iter().map(|net| net.parse().unwrap()).collect()
+    }
+
+    fn carved(net: &str, hole: &str) -> Vec<IpNet> {
+        let mut left = carve(net.parse().unwrap(), &hole.parse().unwrap());
+        left.sort_unstable();
+        left
+    }
+
+    #[test]
+    fn carving_leaves_the_networks_around_the_hole() {
+        assert_eq!(
+            carved("10.0.0.0/8", "10.1.0.0/16"),
+            nets(&[
+                "10.0.0.0/16",
+                "10.2.0.0/15",
+                "10.4.0.0/14",
+                "10.8.0.0/13",
+                "10.16.0.0/12",
+                "10.32.0.0/11",
+                "10.64.0.0/10",
+                "10.128.0.0/9",
+            ])
+        );
+        assert_eq!(
+            carved("192.0.2.0/24", "192.0.2.0/25"),
+            nets(&["192.0.2.128/25"])
+        );
+        assert_eq!(
+            carved("2001:db8::/32", "2001:db8:8000::/33"),
+            nets(&["2001:db8::/33"])
+        );
+    }
+
+    #[test]
+    fn carving_never_covers_the_hole() {
+        let hole: IpNet = "10.1.2.3/32".parse().unwrap();
+        let left = carve("10.0.0.0/8".parse().unwrap(), &hole);
+        assert_eq!(left.len(), 24);
+        let set: PrefixSet = left.into_iter().collect();
+        assert_eq!(set.lookup("10.1.2.3".parse().unwrap()), None);
+        for addr in ["10.1.2.2", "10.1.2.4", "10.0.0.0", "10.255.255.255"] {
+            assert!(set.lookup(addr.parse().unwrap()).is_some(), "{}", addr);
+        }
+    }
+
+    #[test]
+    fn carving_keeps_unrelated_networks_and_drops_covered_ones() {
+        assert_eq!(
+            carved("192.0.2.0/24", "198.51.100.0/24"),
+            nets(&["192.0.2.0/24"])
+        );
+        assert_eq!(
+            carved("192.0.2.0/24", "2001:db8::/32"),
+            nets(&["192.0.2.0/24"])
+        );
+        assert_eq!(carved("192.0.2.64/26", "192.0.2.0/24"), nets(&[]));
+        assert_eq!(carved("192.0.2.0/24", "192.0.2.0/24"), nets(&[]));
+    }
+
+    #[test]
+    fn normalizing_applies_every_exclusion() {
+        let entries = Entries {
+            asns: vec![64512, 13335, 64512, 15169],
+            nets: nets(&[
+                "10.0.0.0/24",
+                "192.0.2.0/24",
+                "198.51.100.0/24",
+                "10.0.0.0/24",
+            ]),
+        };
+        let exclude = Exclusions {
+            asns: vec![13335],
+            nets: nets(&["10.0.0.0/25", "10.0.0.128/26", "198.51.100.0/24"]),
+        };
+
+        let entries = entries.normalize(&exclude);
+        assert_eq!(entries.asns, vec![15169, 64512]);
+        assert_eq!(entries.nets, nets(&["10.0.0.192/26", "192.0.2.0/24"]));
+    }
+}
@@ -1,34 +1,68 @@
 use async_trait::async_trait;
-use log::{debug, error, warn};
+use ipnet::IpNet;
+use log::{debug, error, info, warn};
 use once_cell::sync::Lazy;
+use raigeki::net::normalize;
+use raigeki::pi::handshake::{read_opening, Deadlines};
+use raigeki::relay::{self, Direction, RelayEnd, RelayHook, RelayOptions, Verdict};
+use raigeki_mcproto::handshake::{HandshakePacket, NextState};
 use raigeki_mcproto::login::DisconnectPacket as LoginDisconnectPacket;
+use raigeki_mcproto::status::{LegacyKickPacket, StatusResponsePacket, PING_PACKET_ID};
+use raigeki_mcproto::{read_varint, split_frame, MAX_PRE_PLAY_FRAME};
 use serde_json::json;
+use std::fmt::Display;
 use std::net::{IpAddr, SocketAddr};
+#[cfg(target_os = "linux")]
+use std::os::fd::{AsRawFd, RawFd};
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
-use tokio::select;
+use tokio::time::{sleep, timeout, Instant};
 
 use pingora::apps::ServerApp;
-use pingora::protocols::{Stream, IO};
+#[cfg(target_os = "linux")]
+use pingora::protocols::l4::stream::Stream as L4Stream;
+use pingora::protocols::Stream;
 use pingora::server::ShutdownWatch;
 use pingora::services::listening::Service;
 use pingora_limits::rate::Rate;
 
 use prometheus::{
-    register_counter_vec, register_int_counter, register_int_gauge, CounterVec, IntCounter,
+    register_int_counter, register_int_counter_vec, register_int_gauge, IntCounter, IntCounterVec,
     IntGauge,
 };
 
-use crate::service::MemcachedStatus;
+use crate::service::admission::Admission;
+use crate::service::ban_store::unix_now;
+use crate::service::events::{matched_rule, SessionEvents};
+use crate::service::heavy_hitters::{HeavyHitters, Origin};
+use crate::service::limits::{ConnectionTracker, LimitExceeded, SubnetRateLimit};
+use crate::service::shaping::{OverflowAction, SessionShaper, Shaper};
+use crate::service::tarpit::Tarpit;
+use crate::settings::{BackendRetry, ListenerSettings, Settings, ShutdownPolicy, Timeouts};
 
-use super::geoip;
-use raigeki_error::Error;
+use raigeki_error::{BanNotice, Error, Reason, Timeout};
 
 pub static TOTAL_CONNS: Lazy<IntGauge> =
     Lazy::new(|| register_int_gauge!("total_connections", "total tcp connections").unwrap());
 
+/// A connection counted in [`TOTAL_CONNS`] until dropped.
+struct CountedConnection(());
+
+impl CountedConnection {
+    fn new() -> Self {
+        TOTAL_CONNS.inc();
+        CountedConnection(())
+    }
+}
+
+impl Drop for CountedConnection {
+    fn drop(&mut self) {
+        TOTAL_CONNS.dec();
+    }
+}
+
 static INCOMING_BYTES_TOTAL: Lazy<IntCounter> =
     Lazy::new(|| register_int_counter!("incoming_bytes_total", "Total incoming bytes").unwrap());
 
@@ -38,17 +72,18 @@ static OUTGOING_BYTES_TOTAL: Lazy<IntCounter> =
 pub static DDOS_MODE: Lazy<IntGauge> =
     Lazy::new(|| register_int_gauge!("ddos_mode", "DDoS protection mode").unwrap());
 
-static RATE_LIMITER: Lazy<Rate> = Lazy::new(|| Rate::new(Duration::from_secs(60)));
-static CONNECTION_RATE_LIMITER: Lazy<Rate> = Lazy::new(|| Rate::new(Duration::from_secs(60)));
+/// Disconnect title shown when no backend accepts the connection.
+const BACKEND_RESTARTING: &str = "Сервер перезапускается";
 
-pub static REQUEST_PER_IP: Lazy<CounterVec> = Lazy::new(|| {
-    register_counter_vec!(
-        "request_per_ip",
-        "Number of processed requests from single ip",
-        &["ip"]
-    )
-    .unwrap()
-});
+/// How long a client answered with a "server unavailable" status may take to
+/// send its ping.
+const UNAVAILABLE_PING_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How often a live session publishes its accumulated traffic counters.
+const METRICS_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Window of the request and connection rate limiters.
+const RATE_WINDOW: Duration = Duration::from_secs(60);
 
 pub static REQUEST_TOTAL: Lazy<IntCounter> =
     Lazy::new(|| register_int_counter!("request_total", "Total requests processed").unwrap());
@@ -57,35 +92,114 @@ pub static INCOMING_CONNECTIONS_ATTEMPTS: Lazy<IntGauge> = Lazy::new(|| {
     register_int_gauge!("incoming_connections_attempts", "Total number of incoming connection attempts, including both successful and unsuccessful connections.").unwrap()
 });
 
+static CONNECTION_TIMEOUTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "connection_timeouts_total",
+        "Connections closed for exceeding a stage timeout",
+        &["stage"]
+    )
+    .unwrap()
+});
+
+static CONNECTION_LIMIT_REJECTIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "connection_limit_rejections_total",
+        "Connections refused for exceeding a concurrency cap",
+        &["limit"]
+    )
+    .unwrap()
+});
+
+static BANDWIDTH_OVERFLOWS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "bandwidth_overflows_total",
+        "Relayed chunks that exceeded a bandwidth limit",
+        &["direction", "action"]
+    )
+    .unwrap()
+});
+
+static BACKEND_CONNECT_FAILURES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "backend_connect_failures_total",
+        "Failed attempts to connect to a backend",
+        &["backend", "kind"]
+    )
+    .unwrap()
+});
+
 pub fn forward_service(app: ForwardApp) -> Service<ForwardApp> {
-    Service::new("Raigeki Proxy Service".to_string(), app)
+    Service::new(format!("Raigeki Proxy Service ({})", app.listener), app)
+}
+
+/// What is known about a client by the time it is handed to the relay.
+struct Client {
+    addr: IpAddr,
+    /// Whitelisted clients skip the rate limits.
+    whitelisted: bool,
+    subnets: Vec<(IpNet, SubnetRateLimit)>,
+    /// `None` for a legacy server list ping.
+    handshake: Option<HandshakePacket>,
+    accepted_at: Instant,
+    events: SessionEvents,
 }
 
 pub struct ForwardApp {
-    geoip_service: Arc<geoip::GeoIPService>,
-    outbound_addr: SocketAddr,
+    listener: String,
+    backends: Vec<SocketAddr>,
+    backend_retry: BackendRetry,
     mrpm: isize,
     mcpm: isize,
-    memcached_client: memcache::Client,
+    admission: Admission,
     haproxy: bool,
+    splice: bool,
+    timeouts: Timeouts,
+    connection_tracker: Arc<ConnectionTracker>,
+    shaper: Arc<Shaper>,
+    subnet_rate_limits: Vec<SubnetRateLimit>,
+    rate_limiter: Rate,
+    connection_rate_limiter: Rate,
+    whitelist_connection_rate_limiter: Rate,
+    subnet_rate_limiter: Rate,
+    subnet_connection_rate_limiter: Rate,
+    shutdown_policy: ShutdownPolicy,
+    heavy_hitters: Arc<HeavyHitters>,
+    tarpit: Tarpit,
 }
 
 impl ForwardApp {
+    /// Builds the app serving `listener`. Connection caps and bandwidth are
+    /// shared by all listeners, everything else is the listener's own.
     pub fn new(
-        outbound_addr: SocketAddr,
-        geoip_service: Arc<geoip::GeoIPService>,
-        mrpm: isize,
-        mcpm: isize,
-        memcached_client: memcache::Client,
-        haproxy: bool,
+        listener: &ListenerSettings,
+        backends: Vec<SocketAddr>,
+        admission: Admission,
+        connection_tracker: Arc<ConnectionTracker>,
+        shaper: Arc<Shaper>,
+        heavy_hitters: Arc<HeavyHitters>,
+        settings: &Settings,
     ) -> Self {
         ForwardApp {
-            outbound_addr,
-            geoip_service,
-            mrpm,
-            mcpm,
-            memcached_client,
-            haproxy,
+            listener: listener.name.clone(),
+            backends,
+            backend_retry: settings.backend_retry,
+            mrpm: listener.rate_limit,
+            mcpm: listener.connect_rate_limit,
+            admission,
+            haproxy: listener.haproxy,
+            splice: settings.splice,
+            timeouts: settings.timeouts,
+            connection_tracker,
+            shaper,
+            subnet_rate_limits: listener.subnet_rate_limits.clone(),
+            rate_limiter: Rate::new(RATE_WINDOW),
+            connection_rate_limiter: Rate::new(RATE_WINDOW),
+            whitelist_connection_rate_limiter: Rate::new(RATE_WINDOW),
+            subnet_rate_limiter: Rate::new(RATE_WINDOW),
+            subnet_connection_rate_limiter: Rate::new(RATE_WINDOW),
+            shutdown_policy: settings.shutdown.clone(),
+            heavy_hitters,
+            tarpit: Tarpit::new(settings.tarpit.clone()),
         }
     }
 }
@@ -99,99 +213,180 @@ impl ServerApp for ForwardApp {
     ) -> Option<Stream> {
         INCOMING_CONNECTIONS_ATTEMPTS.inc();
 
+        let accepted_at = Instant::now();
+
         let socket_digest = io.get_socket_digest();
         let socket_addr = socket_digest
             .as_ref()
             .map(|d| d.peer_addr())
             .unwrap()
             .unwrap();
-        let incoming_addr = socket_addr.as_inet().unwrap().ip();
-
-        if CONNECTION_RATE_LIMITER.observe(&incoming_addr, 1) > self.mcpm {
+        let peer_addr = *socket_addr.as_inet().unwrap();
+        let incoming_addr = normalize(peer_addr.ip());
+        let whitelisted = self.admission.whitelist().contains(incoming_addr);
+        let subnets = if whitelisted {
+            Vec::new()
+        } else {
+            self.subnets(incoming_addr)
+        };
+        let mut events = SessionEvents::accept(&self.listener, "java", peer_addr);
+
+        let over_connect_limit = if whitelisted {
+            let limit = self.admission.whitelist().connect_rate_limit();
+            limit > 0
+                && self
+                    .whitelist_connection_rate_limiter
+                    .observe(&incoming_addr, 1)
+                    > limit
+        } else {
+            self.connection_rate_limiter.observe(&incoming_addr, 1) > self.mcpm
+        };
+        if over_connect_limit {
             warn!("Connection rate limit exceeded for {}", incoming_addr);
+            let rule = format!("ip:{}", incoming_addr);
+            self.turn_away(
+                &mut io,
+                &mut events,
+                Reason::RateLimit,
+                rule,
+                None,
+                shutdown,
+            )
+            .await;
 
-            let reason = json!({
-                "text": "IP адрес забанен на 1 час",
-                "color": "red",
-                "bold": true,
-                "extra": [
-                    {
-                        "text": "\nСлишком много попыток подключения!\n",
-                        "color": "red",
-                        "bold": true
-                    },
-                    {
-                        "text": "Если думаете что это ошибка: ",
-                        "color": "gray",
-                        "bold": false
-                    },
-                    {
-                        "text": "https://discord.darateria.com/",
-                        "color": "gray",
-                        "underlined": true,
-                        "bold": false,
-                        "clickEvent": {
-                            "action": "open_url",
-                            "value": "https://discord.darateria.com"   
-                        }
-                    }
-                ]
-            })
-            .to_owned();
+            return None;
+        }
 
-            let packet = LoginDisconnectPacket::new(reason.to_string());
-            io.write_all(&packet.serialize()).await.ok()?;
-            io.flush().await.ok()?;
+        for (subnet, limit) in &subnets {
+            if limit.connect_limit > 0
+                && self.subnet_connection_rate_limiter.observe(subnet, 1) > limit.connect_limit
+            {
+                warn!(
+                    "Connection rate limit exceeded for {} from subnet {}",
+                    incoming_addr, subnet
+                );
+                let rule = format!("subnet:{}", subnet);
+                self.turn_away(
+                    &mut io,
+                    &mut events,
+                    Reason::SubnetRateLimit,
+                    rule,
+                    None,
+                    shutdown,
+                )
+                .await;
 
-            return None;
+                return None;
+            }
         }
 
-        if let Err(e) = self.is_valid_connection(&mut io).await {
-            warn!("Failed validation: {:?}", e);
+        let acquired = if whitelisted {
+            Ok(self.connection_tracker.acquire_exempt(incoming_addr))
+        } else {
+            self.connection_tracker.acquire(incoming_addr)
+        };
+        let mut slot = match acquired {
+            Ok(slot) => slot,
+            Err(limit) => {
+                warn!(
+                    "Address {} reject; {} connection limit reached",
+                    incoming_addr,
+                    limit.as_str()
+                );
+                CONNECTION_LIMIT_REJECTIONS
+                    .with_label_values(&[limit.as_str()])
+                    .inc();
+
+                let reason = match limit {
+                    LimitExceeded::PerIp | LimitExceeded::PerSubnet => Reason::ConnectionLimit,
+                    LimitExceeded::Total | LimitExceeded::HalfOpen => Reason::Overloaded,
+                };
+                self.turn_away(&mut io, &mut events, reason, limit.as_str(), None, shutdown)
+                    .await;
 
-            let reason = json!({
-                "text": e.to_string(),
-                "color": "red",
-                "bold": true,
-                "extra": [
-                    {
-                        "text": "\nПопробуйте отключить ВПН!\n",
-                        "color": "red",
-                        "bold": true
-                    },
-                    {
-                        "text": "Если думаете что это ошибка: ",
-                        "color": "gray",
-                        "bold": false
-                    },
-                    {
-                        "text": "https://discord.darateria.com/",
-                        "color": "gray",
-                        "underlined": true,
-                        "bold": false,
-                        "clickEvent": {
-                            "action": "open_url",
-                            "value": "https://discord.darateria.com"   
-                        }
-                    }
-                ]
-            })
-            .to_owned();
-
-            let packet = LoginDisconnectPacket::new(reason.to_string());
-            io.write_all(&packet.serialize()).await.ok()?;
-            io.flush().await.ok()?;
-            
+                return None;
+            }
+        };
+
+        let admitted = match self.admission.check(incoming_addr, &subnets).await {
+            Ok(reason) => reason,
+            Err(e) => {
+                warn!("Session {} failed validation: {:?}", events.id(), e);
+                // A tarpitted client must not hold a connection slot.
+                drop(slot);
+                self.turn_away(
+                    &mut io,
+                    &mut events,
+                    e.reason(),
+                    matched_rule(&e),
+                    e.ban_notice(),
+                    shutdown,
+                )
+                .await;
+
+                return None;
+            }
+        };
+
+        let deadlines = Deadlines {
+            first_byte: accepted_at + self.timeouts.first_byte,
+            handshake: accepted_at + self.timeouts.handshake,
+            login_start: accepted_at + self.timeouts.login_start,
+        };
+
+        let opening = match read_opening(&mut io, &deadlines).await {
+            Ok(opening) => opening,
+            Err(Error::Timeout(stage)) => {
+                warn!("Address {} timed out waiting for {}", incoming_addr, stage);
+                CONNECTION_TIMEOUTS
+                    .with_label_values(&[stage.as_str()])
+                    .inc();
+                events.reject(Reason::Timeout, stage);
+                return None;
+            }
+            Err(e) => {
+                debug!("Address {} failed opening: {}", incoming_addr, e);
+                events.reject_error(&e);
+                return None;
+            }
+        };
+
+        slot.established();
+
+        // The listener stops accepting on shutdown, but connections still in
+        // their opening exchange have to be turned away here.
+        if *shutdown.borrow() {
+            info!("Address {} refused; shutting down", incoming_addr);
+            events.reject(Reason::ShuttingDown, "");
+            unavailable(
+                &mut io,
+                opening.handshake.as_ref(),
+                &self.shutdown_policy.message,
+            )
+            .await;
             return None;
         }
 
-        let mut outbound = TcpStream::connect(self.outbound_addr).await.ok()?;
+        let mut outbound = match self.connect_backend().await {
+            Ok((backend, outbound)) => {
+                events.allow(admitted, format!("ip:{}", incoming_addr));
+                events.backend(backend);
+                outbound
+            }
+            Err(e) => {
+                error!("Address {} dropped; {}", incoming_addr, e);
+                events.reject_error(&e);
+                unavailable(&mut io, opening.handshake.as_ref(), BACKEND_RESTARTING).await;
+                return None;
+            }
+        };
 
-        TOTAL_CONNS.inc();
+        let _counted = CountedConnection::new();
 
         if self.haproxy {
             if let Err(e) = self.write_haproxy_header(&mut outbound, &io).await {
                 warn!("Failed to write TCP Proxy header: {:?}", e);
+                events.close("error");
 
                 let reason = json!({
                     "text": e.to_string(),
@@ -208,16 +403,32 @@ impl ServerApp for ForwardApp {
             }
         }
 
+        if outbound.write_all(&opening.buffered).await.is_err() {
+            events.close("backend_closed");
+            return None;
+        }
+        events.transferred(opening.buffered.len() as u64, 0);
+
         if self
-            .handle_connection(&mut io, &mut outbound, shutdown)
+            .handle_connection(
+                &mut io,
+                &mut outbound,
+                Client {
+                    addr: incoming_addr,
+                    whitelisted: admitted == Reason::Whitelisted,
+                    subnets,
+                    handshake: opening.handshake,
+                    accepted_at,
+                    events,
+                },
+                shutdown,
+            )
             .await
             .is_err()
         {
             warn!("Connection ended with error");
         }
 
-        TOTAL_CONNS.dec();
-
         None
     }
 }
@@ -278,161 +489,508 @@ impl ForwardApp {
         Ok(())
     }
 
-    async fn is_valid_connection(&self, io: &mut Stream) -> Result<(), Error> {
-        let socket_digest = io.get_socket_digest();
-        let socket_addr = socket_digest
-            .as_ref()
-            .map(|d| d.peer_addr())
-            .unwrap()
-            .unwrap();
-
-        let incoming_addr = socket_addr.as_inet().unwrap().ip();
-
-        let ip_status: i16 = self
-            .memcached_client
-            .get(&incoming_addr.to_string())
-            .map_err(|e| {
-                match e {
-                    memcache::MemcacheError::CommandError(memcache::CommandError::KeyNotFound) => {
-                        return Ok(());
-                    }
-                    _ => {}
-                }
-                Err(Error::InternalError(e.to_string()))
-            })
-            .unwrap()
-            .unwrap_or_default();
-
-        if ip_status == MemcachedStatus::IpBlocked as i16 {
-            warn!("Address {} reject from cache; IP banned", incoming_addr);
-            return Err(Error::IpBlockedInCache(incoming_addr));
+    /// Rejects a client, holding it in the tarpit if `reason` calls for it
+    /// and there is room.
+    async fn turn_away(
+        &self,
+        io: &mut Stream,
+        events: &mut SessionEvents,
+        reason: Reason,
+        rule: impl Display,
+        notice: Option<&BanNotice>,
+        shutdown: &ShutdownWatch,
+    ) {
+        match self.tarpit.enter(reason) {
+            Some(slot) => {
+                events.tarpit(reason, rule);
+                self.tarpit.hold(io, slot, shutdown).await;
+            }
+            None => {
+                events.reject(reason, rule);
+                refuse(io, reason, notice).await;
+            }
         }
+    }
 
-        if ip_status == MemcachedStatus::IpWhiteList as i16 {
-            warn!("Address {} reject from cache; IP banned", incoming_addr);
-            return Ok(());
-        }
+    /// Connects to the first backend that answers, going over the whole list
+    /// up to [`BackendRetry::rounds`] times.
+    async fn connect_backend(&self) -> Result<(SocketAddr, TcpStream), Error> {
+        for round in 0..self.backend_retry.rounds {
+            if round > 0 {
+                sleep(self.backend_retry.backoff * round).await;
+            }
 
-        if self
-            .geoip_service
-            .in_asn_blacklist(incoming_addr)
-            .unwrap_or(true)
-        {
-            warn!(
-                "Address {} reject by asn; Please disable VPN",
-                incoming_addr
-            );
-            self.memcached_client
-                .set(
-                    &incoming_addr.to_string(),
-                    MemcachedStatus::IpBlocked as i16,
-                    1 * 60 * 60,
-                )
-                .unwrap();
-            return Err(Error::AsnBlocked(incoming_addr));
-        }
+            for addr in &self.backends {
+                let e = match timeout(self.timeouts.backend_connect, TcpStream::connect(addr)).await
+                {
+                    Ok(Ok(outbound)) => return Ok((*addr, outbound)),
+                    Ok(Err(e)) => Error::BackendConnectFailed(*addr, e),
+                    Err(_) => Error::BackendConnectTimeout(*addr),
+                };
 
-        if DDOS_MODE.get() == 0 {
-            return Ok(());
+                let kind = match e {
+                    Error::BackendConnectTimeout(_) => "timeout",
+                    _ => "error",
+                };
+                warn!("{}", e);
+                BACKEND_CONNECT_FAILURES
+                    .with_label_values(&[&addr.to_string(), kind])
+                    .inc();
+            }
         }
 
-        if self
-            .geoip_service
-            .in_country_blacklist(incoming_addr)
-            .unwrap_or(true)
-        {
-            warn!("Address {} reject by country", incoming_addr);
-            self.memcached_client
-                .set(
-                    &incoming_addr.to_string(),
-                    MemcachedStatus::IpBlocked as i16,
-                    1 * 60 * 60,
-                )
-                .unwrap();
-            return Err(Error::CountryBlocked(incoming_addr));
-        }
+        Err(Error::BackendUnavailable)
+    }
 
-        return Ok(());
+    /// The subnets of `addr` that carry their own rate limits.
+    fn subnets(&self, addr: IpAddr) -> Vec<(IpNet, SubnetRateLimit)> {
+        self.subnet_rate_limits
+            .iter()
+            .filter_map(|limit| limit.subnet(addr).map(|subnet| (subnet, *limit)))
+            .collect()
     }
 
     async fn handle_connection(
         &self,
-        io: &mut Box<dyn IO>,
+        io: &mut Stream,
         outbound: &mut TcpStream,
+        client: Client,
         shutdown: &ShutdownWatch,
     ) -> Result<(), Error> {
-        let mut buf_io = vec![0; 1024];
-        let mut buf_outbound = vec![0; 1024];
+        let Client {
+            addr: incoming_addr,
+            whitelisted,
+            subnets,
+            handshake,
+            accepted_at,
+            mut events,
+        } = client;
+        let options = RelayOptions {
+            idle_timeout: Some(self.timeouts.idle),
+            deadline: self
+                .timeouts
+                .max_lifetime
+                .map(|lifetime| accepted_at + lifetime),
+        };
+        // Reported if the relay fails; replaced below once it ends normally.
+        events.close("error");
+        let origin = self
+            .heavy_hitters
+            .origin(incoming_addr, self.admission.geoip());
+        let mut meter =
+            SessionMeter::new(self, incoming_addr, whitelisted, subnets, origin, events);
+        // Sessions keep running through the drain window after the shutdown
+        // signal, which also lets them outlive a graceful upgrade.
+        let mut shutdown = shutdown.clone();
+        let drain = self.shutdown_policy.drain;
+        let stop = async move {
+            let _ = shutdown.wait_for(|stopping| *stopping).await;
+            sleep(drain).await;
+        };
 
-        let socket_digest = io.get_socket_digest();
-        let socket_addr = socket_digest
-            .as_ref()
-            .map(|d| d.peer_addr())
-            .unwrap()
-            .unwrap();
-        let incoming_addr = socket_addr.as_inet().unwrap().ip();
-        let ip_str = incoming_addr.to_string();
+        #[cfg(target_os = "linux")]
+        let end = match (self.splice, splice_fd(io)) {
+            (true, Some(client_fd)) => {
+                io.flush().await?;
+                outbound.flush().await?;
+                relay::splice::relay(client_fd, outbound.as_raw_fd(), &options, &mut meter, stop)
+                    .await?
+            }
+            _ => relay::relay(io, outbound, &options, &mut meter, stop).await?,
+        };
+
+        #[cfg(not(target_os = "linux"))]
+        let end = relay::relay(io, outbound, &options, &mut meter, stop).await?;
 
-        let mut shutdown_clone = shutdown.clone();
+        let cause = match end {
+            RelayEnd::Closed => "closed",
+            RelayEnd::Aborted => meter.abort_cause.unwrap_or("aborted"),
+            RelayEnd::Stopped => "drained",
+            RelayEnd::IdleTimeout => "idle_timeout",
+            RelayEnd::Expired => "lifetime",
+        };
+        meter.events.close(cause);
+
+        match end {
+            RelayEnd::Closed => debug!("Session closing"),
+            RelayEnd::Aborted => debug!("Session aborted from {}", incoming_addr),
+            RelayEnd::Stopped => {
+                warn!(
+                    "Drain period over, closing connection from {}",
+                    incoming_addr
+                );
+                // Only clients still waiting on the backend get a message.
+                // Past that point the proxy can't speak to the client: the
+                // relay copies the backend's bytes without parsing them, so
+                // there is no frame boundary to put a packet at and no
+                // record of the compression threshold, online-mode sessions
+                // are encrypted with a key only the two ends hold, and the
+                // id of the play Disconnect changes between protocol
+                // versions. Players in game are cut off, so the backend has
+                // to kick them itself within the drain period.
+                if !meter.downstream_seen {
+                    unavailable(io, handshake.as_ref(), &self.shutdown_policy.message).await;
+                }
+            }
+            RelayEnd::IdleTimeout | RelayEnd::Expired => {
+                let stage = if end == RelayEnd::IdleTimeout {
+                    Timeout::Idle
+                } else {
+                    Timeout::Lifetime
+                };
+                warn!("Address {} timed out; {}", incoming_addr, stage);
+                CONNECTION_TIMEOUTS
+                    .with_label_values(&[stage.as_str()])
+                    .inc();
+            }
+        }
 
-        loop {
-            select! {
-                _ = shutdown_clone.changed() => {
-                warn!("Shutdown signal received, closing connection from {}", ip_str);
+        Ok(())
+    }
+}
+
+/// Title and hint of the disconnect shown for `reason`, if the client gets
+/// one at all.
+fn rejection_text(reason: Reason) -> Option<(&'static str, &'static str)> {
+    match reason {
+        Reason::RateLimit => Some((
+            "IP адрес забанен",
+            "Слишком много попыток подключения!",
+        )),
+        Reason::SubnetRateLimit => Some((
+            "Слишком много подключений из вашей сети",
+            "Попробуйте подключиться через пару минут!",
+        )),
+        Reason::ConnectionLimit => Some((
+            "Слишком много подключений",
+            "С вашего адреса уже открыто слишком много соединений!",
+        )),
+        Reason::Overloaded | Reason::SessionLimit => Some((
+            "Сервер перегружен",
+            "Попробуйте подключиться через пару минут!",
+        )),
+        Reason::CachedBan | Reason::Scanner | Reason::Blocklist => {
+            Some(("Ваш IP адрес заблокирован", "Попробуйте отключить ВПН!"))
+        }
+        Reason::Asn => Some(("Ваш провайдер заблокирован", "Попробуйте отключить ВПН!")),
+        Reason::Country => Some((
+            "Подключения из вашей страны запрещены",
+            "Попробуйте отключить ВПН!",
+        )),
+        Reason::AdmissionError => Some((
+            "Не удалось проверить подключение",
+            "Попробуйте подключиться через пару минут!",
+        )),
+        // Nothing to say to a client that timed out or spoke garbage, and
+        // backend and shutdown notices depend on what the client asked for.
+        Reason::Passed
+        | Reason::Whitelisted
+        | Reason::Timeout
+        | Reason::ProtocolViolation
+        | Reason::ShuttingDown
+        | Reason::BackendDown => None,
+    }
+}
 
-                return Ok(());
+/// Turns a client away with the disconnect its reason calls for.
+async fn refuse(io: &mut Stream, reason: Reason, notice: Option<&BanNotice>) {
+    if let Some((title, hint)) = rejection_text(reason) {
+        let hint = match notice {
+            Some(notice) => format!("{}\n{}", hint, ban_details(notice)),
+            None => hint.to_string(),
+        };
+        disconnect(io, rejection_reason(title, &hint)).await;
+    }
+}
+
+/// How long a ban has left and the code to appeal it with.
+fn ban_details(notice: &BanNotice) -> String {
+    let left = notice.expires_at.saturating_sub(unix_now());
+    let (days, hours, minutes) = (left / 86400, left % 86400 / 3600, left % 3600 / 60);
+    let left = if days > 0 {
+        format!("{} д {} ч", days, hours)
+    } else if hours > 0 {
+        format!("{} ч {} мин", hours, minutes)
+    } else if minutes > 0 {
+        format!("{} мин", minutes)
+    } else {
+        "меньше минуты".to_string()
+    };
+
+    format!(
+        "До разблокировки: {}\nКод для обжалования: {}",
+        left, notice.appeal_code
+    )
+}
+
+/// Builds the chat component shown to a rejected player.
+fn rejection_reason(title: &str, hint: &str) -> String {
+    json!({
+        "text": title,
+        "color": "red",
+        "bold": true,
+        "extra": [
+            {
+                "text": format!("\n{}\n", hint),
+                "color": "red",
+                "bold": true
+            },
+            {
+                "text": "Если думаете что это ошибка: ",
+                "color": "gray",
+                "bold": false
+            },
+            {
+                "text": "https://discord.darateria.com/",
+                "color": "gray",
+                "underlined": true,
+                "bold": false,
+                "clickEvent": {
+                    "action": "open_url",
+                    "value": "https://discord.darateria.com"
+                }
             }
+        ]
+    })
+    .to_string()
+}
 
-                result = io.read(&mut buf_io) => {
-                    match result {
-                        Ok(n) if n > 0 => {
-                            outbound.write_all(&buf_io[0..n]).await?;
-                            outbound.flush().await?;
-
-                            // TODO: dpi
-                            INCOMING_BYTES_TOTAL.inc_by(n as u64);
-                            REQUEST_PER_IP.with_label_values(&[&ip_str]).inc();
-                            REQUEST_TOTAL.inc();
-
-                            let curr_window_requests = RATE_LIMITER.observe(&incoming_addr, 1);
-
-                            if curr_window_requests > self.mrpm {
-                                warn!("Address {} exceed max rpm; rpm={}", incoming_addr, curr_window_requests);
-                                self.memcached_client.set(&incoming_addr.to_string(), MemcachedStatus::IpBlocked as i16, 1 * 60 * 60)?;
-                                io.shutdown().await?;
-                                return Ok(());
-                            }
-                        }
-                        Ok(_) => {
-                            debug!("Session closing");
-                            return Ok(());
-                        }
-                        Err(e) => {
-                            error!("Error reading from io: {:?}", e);
-                            return Err(Error::IOError(e));
-                        }
-                    }
+/// Sends a login disconnect with `reason`, ignoring clients that are already
+/// gone.
+pub async fn disconnect(io: &mut Stream, reason: String) {
+    let packet = LoginDisconnectPacket::new(reason);
+    if io.write_all(&packet.serialize()).await.is_ok() {
+        let _ = io.flush().await;
+    }
+}
+
+/// Tells the client the server is unavailable in whatever form its handshake
+/// expects: a login disconnect, a server list entry or a legacy kick.
+async fn unavailable(io: &mut Stream, handshake: Option<&HandshakePacket>, title: &str) {
+    const HINT: &str = "Попробуйте подключиться через минуту!";
+
+    let handshake = match handshake {
+        Some(handshake) => handshake,
+        None => {
+            let packet = LegacyKickPacket {
+                protocol_version: 127,
+                version_name: title.to_string(),
+                motd: HINT.to_string(),
+                online_players: 0,
+                max_players: 0,
+            };
+            if io.write_all(&packet.serialize()).await.is_ok() {
+                let _ = io.flush().await;
+            }
+            return;
+        }
+    };
+
+    if handshake.next_state != NextState::Status {
+        disconnect(io, rejection_reason(title, HINT)).await;
+        return;
+    }
+
+    // Protocol -1 never matches, so the client shows the version name in red
+    // instead of a player count.
+    let status = json!({
+        "version": { "name": title, "protocol": -1 },
+        "players": { "max": 0, "online": 0 },
+        "description": { "text": HINT, "color": "red" },
+    });
+    let packet = StatusResponsePacket::new(status.to_string());
+    if io.write_all(&packet.serialize()).await.is_err() || io.flush().await.is_err() {
+        return;
+    }
+
+    // The pong is the ping sent back unchanged.
+    if let Ok(Some(ping)) = timeout(UNAVAILABLE_PING_TIMEOUT, read_ping(io)).await {
+        if io.write_all(&ping).await.is_ok() {
+            let _ = io.flush().await;
+        }
+    }
+}
+
+/// Reads frames until the status ping and returns it whole, framing included.
+pub async fn read_ping(io: &mut Stream) -> Option<Vec<u8>> {
+    let mut buf = Vec::new();
+    loop {
+        while let Some((body, len)) = split_frame(&buf, MAX_PRE_PLAY_FRAME).ok()? {
+            if read_varint(&mut &body[..]).ok()? == PING_PACKET_ID {
+                return Some(buf[..len].to_vec());
+            }
+            buf.drain(..len);
+        }
+
+        buf.reserve(relay::BUF_SIZE);
+        if io.read_buf(&mut buf).await.ok()? == 0 {
+            return None;
+        }
+    }
+}
+
+/// Raw socket of the client stream, if it is a plain TCP stream that can be
+/// handed to `splice(2)`.
+#[cfg(target_os = "linux")]
+fn splice_fd(io: &Stream) -> Option<RawFd> {
+    io.as_any()
+        .downcast_ref::<L4Stream>()
+        .map(|stream| stream.as_raw_fd())
+}
+
+/// Accumulates per-session traffic counters and publishes them to prometheus
+/// in batches instead of on every chunk. Also enforces the per-session rate
+/// and bandwidth limits.
+struct SessionMeter<'a> {
+    app: &'a ForwardApp,
+    addr: IpAddr,
+    whitelisted: bool,
+    subnets: Vec<(IpNet, SubnetRateLimit)>,
+    /// `None` for whitelisted clients, which are not shaped.
+    shaper: Option<SessionShaper>,
+    incoming_bytes: u64,
+    outgoing_bytes: u64,
+    requests: u64,
+    last_flush: Instant,
+    /// Whether the backend has sent anything yet.
+    downstream_seen: bool,
+    /// Why the hook aborted the session, if it did.
+    abort_cause: Option<&'static str>,
+    origin: Origin,
+    events: SessionEvents,
+}
+
+impl<'a> SessionMeter<'a> {
+    fn new(
+        app: &'a ForwardApp,
+        addr: IpAddr,
+        whitelisted: bool,
+        subnets: Vec<(IpNet, SubnetRateLimit)>,
+        origin: Origin,
+        events: SessionEvents,
+    ) -> Self {
+        SessionMeter {
+            app,
+            addr,
+            whitelisted,
+            subnets,
+            shaper: (!whitelisted).then(|| app.shaper.session(addr)),
+            incoming_bytes: 0,
+            outgoing_bytes: 0,
+            requests: 0,
+            last_flush: Instant::now(),
+            downstream_seen: false,
+            abort_cause: None,
+            origin,
+            events,
+        }
+    }
+
+    fn flush(&mut self) {
+        INCOMING_BYTES_TOTAL.inc_by(self.incoming_bytes);
+        OUTGOING_BYTES_TOTAL.inc_by(self.outgoing_bytes);
+        self.events
+            .transferred(self.incoming_bytes, self.outgoing_bytes);
+        if self.requests > 0 {
+            self.app.heavy_hitters.observe(&self.origin, self.requests);
+            REQUEST_TOTAL.inc_by(self.requests);
+        }
+
+        self.incoming_bytes = 0;
+        self.outgoing_bytes = 0;
+        self.requests = 0;
+        self.last_flush = Instant::now();
+    }
+}
+
+impl RelayHook for SessionMeter<'_> {
+    fn on_transfer(&mut self, direction: Direction, bytes: usize) -> Verdict {
+        match direction {
+            Direction::Upstream => {
+                // TODO: dpi
+                self.incoming_bytes += bytes as u64;
+                self.requests += 1;
+
+                let curr_window_requests = self.app.rate_limiter.observe(&self.addr, 1);
+
+                if !self.whitelisted && curr_window_requests > self.app.mrpm {
+                    warn!(
+                        "Address {} exceed max rpm; rpm={}",
+                        self.addr, curr_window_requests
+                    );
+                    let key = self.addr.to_string();
+                    let rule = format!("ip:{}", key);
+                    self.app.admission.ban(&key, Reason::RateLimit, rule);
+                    self.abort_cause = Some("rate_limit_ban");
+                    return Verdict::Abort;
                 }
 
-                result = outbound.read(&mut buf_outbound) => {
-                    match result {
-                        Ok(n) if n > 0 => {
-                            io.write_all(&buf_outbound[0..n]).await?;
-                            io.flush().await?;
-
-                            OUTGOING_BYTES_TOTAL.inc_by(n as u64);
-                        }
-                        Ok(_) => {
-                            debug!("Outbound connection closed");
-                            return Ok(());
-                        }
-                        Err(e) => {
-                            eprintln!("Error reading from outbound: {:?}", e);
-                            return Err(Error::IOError(e));
-                        }
+                for (subnet, limit) in &self.subnets {
+                    if limit.rate_limit > 0
+                        && self.app.subnet_rate_limiter.observe(subnet, 1) > limit.rate_limit
+                    {
+                        warn!(
+                            "Subnet {} exceed max rpm; last address {}",
+                            subnet, self.addr
+                        );
+                        let key = subnet.to_string();
+                        let rule = format!("subnet:{}", key);
+                        self.app.admission.ban(&key, Reason::SubnetRateLimit, rule);
+                        self.abort_cause = Some("subnet_rate_limit_ban");
+                        return Verdict::Abort;
                     }
                 }
             }
+            Direction::Downstream => {
+                self.outgoing_bytes += bytes as u64;
+                self.downstream_seen = true;
+            }
+        }
+
+        if self.last_flush.elapsed() >= METRICS_FLUSH_INTERVAL {
+            self.flush();
         }
+
+        let delay = self
+            .shaper
+            .as_ref()
+            .map_or(Duration::ZERO, |shaper| shaper.take(direction, bytes));
+        if delay.is_zero() {
+            return Verdict::Continue;
+        }
+
+        let direction_label = match direction {
+            Direction::Upstream => "in",
+            Direction::Downstream => "out",
+        };
+
+        match self.app.shaper.overflow() {
+            OverflowAction::Throttle => {
+                BANDWIDTH_OVERFLOWS
+                    .with_label_values(&[direction_label, "throttle"])
+                    .inc();
+                Verdict::Throttle(delay)
+            }
+            OverflowAction::Ban => {
+                warn!(
+                    "Address {} exceed bandwidth limit; direction={}",
+                    self.addr, direction_label
+                );
+                BANDWIDTH_OVERFLOWS
+                    .with_label_values(&[direction_label, "ban"])
+                    .inc();
+                let key = self.addr.to_string();
+                let rule = format!("bandwidth:{}", direction_label);
+                self.app.admission.ban(&key, Reason::RateLimit, rule);
+                self.abort_cause = Some("bandwidth_ban");
+                Verdict::Abort
+            }
+        }
+    }
+}
+
+impl Drop for SessionMeter<'_> {
+    fn drop(&mut self) {
+        self.flush();
     }
 }
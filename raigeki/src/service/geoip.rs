@@ -1,110 +1,392 @@
 use std::{
-    net::IpAddr,
-    sync::{Arc, RwLock},
-    thread,
-    time::Duration,
+    fs,
+    net::{IpAddr, Ipv4Addr},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
 };
 
-use log::info;
-use maxminddb::{geoip2, Reader};
+use arc_swap::ArcSwap;
+use async_trait::async_trait;
+use log::{error, info};
+use maxminddb::{geoip2, MaxMindDBError, Reader};
+use once_cell::sync::Lazy;
+use pingora::{server::ShutdownWatch, services::background::BackgroundService};
+use prometheus::{register_int_counter_vec, register_int_gauge_vec, IntCounterVec, IntGaugeVec};
 use raigeki_error::Error;
-use raigeki_tools::download::download;
+use raigeki_tools::download::{download, DownloadSettings, Fetched, Staged};
+use tokio::task::spawn_blocking;
+use tokio::time::interval;
+
+use crate::service::ban_store::unix_now;
+
+static GEOIP_BUILD_EPOCH: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "geoip_build_epoch",
+        "Unix time the served GeoIP database was built",
+        &["database"]
+    )
+    .unwrap()
+});
+
+static GEOIP_AGE: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "geoip_age_seconds",
+        "Seconds since the served GeoIP database was built",
+        &["database"]
+    )
+    .unwrap()
+});
+
+static GEOIP_REFRESH_FAILURES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "geoip_refresh_failures_total",
+        "GeoIP database refreshes that failed, the old database is kept",
+        &["database"]
+    )
+    .unwrap()
+});
+
+/// How often the files are checked for updates made by something else,
+/// such as `geoipupdate`.
+const WATCH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Address looked up to check that a new database decodes.
+const PROBE: IpAddr = IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1));
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Database {
+    Asn,
+    City,
+}
+
+impl Database {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Database::Asn => "asn",
+            Database::City => "city",
+        }
+    }
+
+    /// Whether a database of `database_type` can serve the lookups.
+    fn accepts(&self, database_type: &str) -> bool {
+        match self {
+            Database::Asn => database_type.contains("ASN"),
+            Database::City => database_type.contains("City") || database_type.contains("Country"),
+        }
+    }
+}
+
+/// Opens `path` and checks it is a readable database of the right kind.
+fn open(database: Database, path: &Path) -> Result<Reader<Vec<u8>>, Error> {
+    let reader = Reader::open_readfile(path)?;
+    let database_type = &reader.metadata.database_type;
+    if !database.accepts(database_type) {
+        return Err(Error::InternalError(format!(
+            "{} is a {} database, expected {}",
+            path.display(),
+            database_type,
+            database.as_str()
+        )));
+    }
+
+    let probe = match database {
+        Database::Asn => reader.lookup::<geoip2::Asn>(PROBE).map(|_| ()),
+        Database::City => reader.lookup::<geoip2::Country>(PROBE).map(|_| ()),
+    };
+    match probe {
+        Ok(()) | Err(MaxMindDBError::AddressNotFoundError(_)) => Ok(reader),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Downloads a new copy of the database next to `path`, and moves it into
+/// place once it opens. `None` if the upstream copy did not change.
+async fn fetch(
+    database: Database,
+    settings: &DownloadSettings,
+    path: &Path,
+) -> Result<Option<Reader<Vec<u8>>>, Error> {
+    let staged = match download(settings, path).await? {
+        Fetched::NotModified => return Ok(None),
+        Fetched::Downloaded(staged) => staged,
+    };
+    spawn_blocking(move || install(database, staged).map(Some))
+        .await
+        .unwrap_or_else(|e| Err(Error::InternalError(e.to_string())))
+}
+
+fn install(database: Database, staged: Staged) -> Result<Reader<Vec<u8>>, Error> {
+    let reader = open(database, staged.path())?;
+    staged.commit()?;
+    Ok(reader)
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+fn export(database: Database, reader: &Reader<Vec<u8>>) {
+    let build_epoch = reader.metadata.build_epoch;
+    GEOIP_BUILD_EPOCH
+        .with_label_values(&[database.as_str()])
+        .set(build_epoch as i64);
+    GEOIP_AGE
+        .with_label_values(&[database.as_str()])
+        .set(unix_now().saturating_sub(build_epoch) as i64);
+}
+
+/// A database file and the reader serving it, swapped without locking out
+/// lookups.
+struct Served {
+    database: Database,
+    path: PathBuf,
+    reader: ArcSwap<Reader<Vec<u8>>>,
+    /// Modification time of the file the reader was opened from.
+    modified: Mutex<Option<SystemTime>>,
+}
+
+impl Served {
+    fn new(database: Database, path: &str) -> Result<Self, Error> {
+        let path = PathBuf::from(path);
+        let reader = open(database, &path)?;
+        export(database, &reader);
+        Ok(Served {
+            database,
+            modified: Mutex::new(modified(&path)),
+            path,
+            reader: ArcSwap::from_pointee(reader),
+        })
+    }
+
+    fn swap(&self, reader: Reader<Vec<u8>>) {
+        info!(
+            "Serving {} GeoIP database built at {}",
+            self.database.as_str(),
+            reader.metadata.build_epoch
+        );
+        export(self.database, &reader);
+        *self.modified.lock().unwrap() = modified(&self.path);
+        self.reader.store(Arc::new(reader));
+    }
+
+    fn failed(&self, e: Error) {
+        GEOIP_REFRESH_FAILURES
+            .with_label_values(&[self.database.as_str()])
+            .inc();
+        error!(
+            "Failed to refresh {} GeoIP database, keeping the old one: {}",
+            self.database.as_str(),
+            e
+        );
+    }
+
+    /// Downloads a new copy of the database and serves it if it is valid.
+    async fn download(&self, settings: &DownloadSettings) {
+        match fetch(self.database, settings, &self.path).await {
+            Ok(Some(reader)) => self.swap(reader),
+            Ok(None) => {}
+            Err(e) => self.failed(e),
+        }
+    }
+
+    /// Serves the file again if something else replaced it.
+    async fn reload_if_changed(&self) {
+        let current = modified(&self.path);
+        if current.is_none() || current == *self.modified.lock().unwrap() {
+            return;
+        }
+
+        let database = self.database;
+        let path = self.path.clone();
+        match spawn_blocking(move || open(database, &path)).await {
+            Ok(Ok(reader)) => self.swap(reader),
+            Ok(Err(e)) => {
+                // Don't retry the same broken file every minute.
+                *self.modified.lock().unwrap() = current;
+                self.failed(e);
+            }
+            Err(e) => self.failed(Error::InternalError(e.to_string())),
+        }
+    }
+}
 
 pub struct GeoIPService {
-    ddb_asn: Arc<RwLock<Reader<Vec<u8>>>>,
-    ddb_city: Arc<RwLock<Reader<Vec<u8>>>>,
+    ddb_asn: Arc<Served>,
+    ddb_city: Arc<Served>,
     asn_blacklist: Vec<u32>,
     country_blacklist: Vec<String>,
 }
 
 impl GeoIPService {
     pub fn new(
-        mmdb_asn_path: String,
-        mmdb_city_path: String,
+        mmdb_asn_path: &str,
+        mmdb_city_path: &str,
         asn_blacklist: Vec<u32>,
         country_blacklist: Vec<String>,
-    ) -> Self {
-        let ddb_asn = Arc::new(RwLock::new(
-            maxminddb::Reader::open_readfile(&mmdb_asn_path).unwrap(),
-        ));
-        let ddb_city = Arc::new(RwLock::new(
-            maxminddb::Reader::open_readfile(&mmdb_city_path).unwrap(),
-        ));
-
-        let ddb_asn_clone = Arc::clone(&ddb_asn);
-        let ddb_city_clone = Arc::clone(&ddb_city);
-
-        thread::spawn(move || {
-            loop {
-                thread::sleep(Duration::from_secs(24 * 60 * 60));
-
-                let new_ddb_asn = maxminddb::Reader::open_readfile(&mmdb_asn_path).unwrap();
-                let new_ddb_city = maxminddb::Reader::open_readfile(&mmdb_city_path).unwrap();
-
-                // Acquire write locks to update the readers
-                {
-                    let mut asn_lock = ddb_asn_clone.write().unwrap();
-                    *asn_lock = new_ddb_asn;
-                }
-
-                {
-                    let mut city_lock = ddb_city_clone.write().unwrap();
-                    *city_lock = new_ddb_city;
-                }
-            }
-        });
+    ) -> Result<Self, Error> {
+        Ok(GeoIPService {
+            ddb_asn: Arc::new(Served::new(Database::Asn, mmdb_asn_path)?),
+            ddb_city: Arc::new(Served::new(Database::City, mmdb_city_path)?),
+            asn_blacklist,
+            country_blacklist,
+        })
+    }
 
+    /// Shares the databases, and their reloads, with another set of
+    /// blacklists.
+    pub fn with_blacklists(&self, asn_blacklist: Vec<u32>, country_blacklist: Vec<String>) -> Self {
         GeoIPService {
-            ddb_asn,
-            ddb_city,
+            ddb_asn: Arc::clone(&self.ddb_asn),
+            ddb_city: Arc::clone(&self.ddb_city),
             asn_blacklist,
             country_blacklist,
         }
     }
 
-    pub fn in_asn_blacklist(&self, ip: IpAddr) -> Result<bool, Error> {
-        let binding = self.ddb_asn.read().unwrap();
-        let info: geoip2::Asn = binding.lookup(ip)?;
-
-        let asn_number = &info.autonomous_system_number.unwrap_or_default().to_owned();
-
-        info!("ip: {}, asn: {}", ip, asn_number);
-
-        if !self.asn_blacklist.contains(asn_number) {
-            return Ok(false);
+    /// The service keeping these databases up to date.
+    pub fn refresher(&self, settings: GeoIPRefreshSettings) -> GeoIPRefresh {
+        GeoIPRefresh {
+            ddb_asn: Arc::clone(&self.ddb_asn),
+            ddb_city: Arc::clone(&self.ddb_city),
+            settings,
         }
+    }
 
-        Ok(true)
+    pub fn asn(&self, ip: IpAddr) -> Result<u32, Error> {
+        let reader = self.ddb_asn.reader.load();
+        let info: geoip2::Asn = reader.lookup(ip)?;
+
+        Ok(info.autonomous_system_number.unwrap_or_default())
     }
 
-    pub fn in_country_blacklist(&self, ip: IpAddr) -> Result<bool, Error> {
-        let binding = self.ddb_city.read().unwrap();
-        let info: geoip2::Country = binding.lookup(ip)?;
+    /// ISO code of the country of `ip`.
+    pub fn country(&self, ip: IpAddr) -> Result<String, Error> {
+        let reader = self.ddb_city.reader.load();
+        let info: geoip2::Country = reader.lookup(ip)?;
 
-        let country = &info
+        Ok(info
             .country
             .ok_or(Error::MaxminddbCountryNotFoundError)?
             .iso_code
             .unwrap_or_default()
-            .to_owned();
+            .to_owned())
+    }
+
+    /// Returns the ASN of `ip` if it is blacklisted, here or by `listed`.
+    pub fn in_asn_blacklist(
+        &self,
+        ip: IpAddr,
+        listed: impl Fn(u32) -> bool,
+    ) -> Result<Option<u32>, Error> {
+        let asn_number = self.asn(ip)?;
+
+        info!("ip: {}, asn: {}", ip, asn_number);
+
+        if !self.asn_blacklist.contains(&asn_number) && !listed(asn_number) {
+            return Ok(None);
+        }
+
+        Ok(Some(asn_number))
+    }
+
+    /// Returns the ISO code of the country of `ip` if it is blacklisted.
+    pub fn in_country_blacklist(&self, ip: IpAddr) -> Result<Option<String>, Error> {
+        let country = self.country(ip)?;
 
         info!("ip: {}, country: {}", ip, country);
 
-        if !self.country_blacklist.contains(country) {
-            return Ok(false);
+        if !self.country_blacklist.contains(&country) {
+            return Ok(None);
         }
 
-        Ok(true)
+        Ok(Some(country))
     }
 }
 
-const DDBM_ASN: &str = "https://git.io/GeoLite2-ASN.mmdb";
-const DDBM_CITY: &str = "https://git.io/GeoLite2-City.mmdb";
+#[derive(Debug, Clone)]
+pub struct GeoIPRefreshSettings {
+    /// Download new databases, rather than only pick up replaced files.
+    pub auto_download: bool,
+    pub interval: Duration,
+    pub asn: DownloadSettings,
+    pub city: DownloadSettings,
+}
+
+/// Downloads the databases on a schedule and picks up files replaced by
+/// hand. A database that fails to download or open leaves the old one
+/// serving.
+pub struct GeoIPRefresh {
+    ddb_asn: Arc<Served>,
+    ddb_city: Arc<Served>,
+    settings: GeoIPRefreshSettings,
+}
+
+#[async_trait]
+impl BackgroundService for GeoIPRefresh {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let mut period = interval(self.settings.interval);
+        // Startup has just downloaded them.
+        period.tick().await;
+        let mut watch = interval(WATCH_INTERVAL);
+
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => break,
+                _ = period.tick(), if self.settings.auto_download => {
+                    self.ddb_asn.download(&self.settings.asn).await;
+                    self.ddb_city.download(&self.settings.city).await;
+                }
+                _ = watch.tick() => {
+                    for served in [&self.ddb_asn, &self.ddb_city] {
+                        served.reload_if_changed().await;
+                        export(served.database, &served.reader.load());
+                    }
+                }
+            }
+        }
+    }
+}
 
-pub fn download_ddbm(asn_path: &str, city_path: &str) -> Result<(), Error> {
+/// Downloads both databases before the server runs, keeping the current
+/// file of any download that is not a valid database. Both are tried, the
+/// first failure is returned.
+pub fn download_ddbm(
+    asn_path: &str,
+    city_path: &str,
+    settings: &GeoIPRefreshSettings,
+) -> Result<(), Error> {
     info!("start download ddbm");
-    download(DDBM_ASN, asn_path)?;
-    download(DDBM_CITY, city_path)?;
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let results = runtime.block_on(async {
+        [
+            (
+                Database::Asn,
+                fetch(Database::Asn, &settings.asn, Path::new(asn_path)).await,
+            ),
+            (
+                Database::City,
+                fetch(Database::City, &settings.city, Path::new(city_path)).await,
+            ),
+        ]
+    });
+
+    let mut failure = None;
+    for (database, result) in results {
+        if let Err(e) = result {
+            error!(
+                "Failed to download {} GeoIP database: {}",
+                database.as_str(),
+                e
+            );
+            failure.get_or_insert(e);
+        }
+    }
+    if let Some(e) = failure {
+        return Err(e);
+    }
     info!("finish ddbm");
 
     Ok(())
@@ -0,0 +1,166 @@
+//! Top request sources by address, subnet, ASN and country.
+//!
+//! Every dimension is a bounded [`TopK`] sketch, so a flood from millions of
+//! addresses costs the same memory as a quiet day. Only the top few entries
+//! are exported to prometheus; the admin endpoint serves all of them.
+
+use std::fmt::Display;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use ipnet::IpNet;
+use once_cell::sync::Lazy;
+use pingora::{server::ShutdownWatch, services::background::BackgroundService};
+use prometheus::{register_int_gauge_vec, IntGaugeVec};
+use raigeki::net::SubnetPrefix;
+use raigeki::topk::TopK;
+use serde_json::{json, Value};
+use tokio::time::interval;
+
+use crate::service::geoip::GeoIPService;
+use crate::settings::HeavyHitterSettings;
+
+static HEAVY_HITTER_REQUESTS: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "heavy_hitter_requests",
+        "Requests of the top sources per dimension, decayed over time",
+        &["dimension", "rank", "key"]
+    )
+    .unwrap()
+});
+
+/// Where a session comes from, resolved once when it starts.
+#[derive(Debug, Clone)]
+pub struct Origin {
+    addr: IpAddr,
+    subnet: IpNet,
+    asn: Option<u32>,
+    country: Option<String>,
+}
+
+pub struct HeavyHitters {
+    settings: HeavyHitterSettings,
+    subnet_prefix: SubnetPrefix,
+    ip: Mutex<TopK<IpAddr>>,
+    subnet: Mutex<TopK<IpNet>>,
+    asn: Mutex<TopK<u32>>,
+    country: Mutex<TopK<String>>,
+}
+
+impl HeavyHitters {
+    pub fn new(settings: HeavyHitterSettings, subnet_prefix: SubnetPrefix) -> Self {
+        let capacity = settings.capacity;
+        HeavyHitters {
+            settings,
+            subnet_prefix,
+            ip: Mutex::new(TopK::new(capacity)),
+            subnet: Mutex::new(TopK::new(capacity)),
+            asn: Mutex::new(TopK::new(capacity)),
+            country: Mutex::new(TopK::new(capacity)),
+        }
+    }
+
+    pub fn origin(&self, addr: IpAddr, geoip: &GeoIPService) -> Origin {
+        Origin {
+            addr,
+            subnet: self.subnet_prefix.subnet(addr),
+            asn: geoip.asn(addr).ok(),
+            country: geoip.country(addr).ok(),
+        }
+    }
+
+    /// Counts `requests` from `origin`.
+    pub fn observe(&self, origin: &Origin, requests: u64) {
+        self.ip.lock().unwrap().observe(&origin.addr, requests);
+        self.subnet
+            .lock()
+            .unwrap()
+            .observe(&origin.subnet, requests);
+        if let Some(asn) = origin.asn {
+            self.asn.lock().unwrap().observe(&asn, requests);
+        }
+        if let Some(country) = &origin.country {
+            self.country.lock().unwrap().observe(country, requests);
+        }
+    }
+
+    /// Every tracked entry of `dimension`, or of all of them, as JSON.
+    pub fn report(&self, dimension: Option<&str>, limit: Option<usize>) -> Option<Value> {
+        let limit = limit.unwrap_or(self.settings.capacity);
+        let mut report = serde_json::Map::new();
+
+        for name in ["ip", "subnet", "asn", "country"] {
+            if dimension.is_some_and(|dimension| dimension != name) {
+                continue;
+            }
+            let entries = match name {
+                "ip" => entries(&self.ip, limit),
+                "subnet" => entries(&self.subnet, limit),
+                "asn" => entries(&self.asn, limit),
+                _ => entries(&self.country, limit),
+            };
+            report.insert(name.to_string(), entries);
+        }
+
+        (!report.is_empty()).then_some(Value::Object(report))
+    }
+
+    fn export(&self) {
+        HEAVY_HITTER_REQUESTS.reset();
+        let exported = self.settings.exported;
+        export("ip", &self.ip, exported);
+        export("subnet", &self.subnet, exported);
+        export("asn", &self.asn, exported);
+        export("country", &self.country, exported);
+    }
+
+    fn decay(&self) {
+        self.ip.lock().unwrap().decay();
+        self.subnet.lock().unwrap().decay();
+        self.asn.lock().unwrap().decay();
+        self.country.lock().unwrap().decay();
+    }
+}
+
+fn entries<K: Clone + Eq + Hash + Display>(sketch: &Mutex<TopK<K>>, limit: usize) -> Value {
+    let top = sketch.lock().unwrap().top(limit);
+    top.into_iter()
+        .map(|entry| {
+            json!({
+                "key": entry.key.to_string(),
+                "count": entry.count,
+                "error": entry.error,
+            })
+        })
+        .collect()
+}
+
+fn export<K: Clone + Eq + Hash + Display>(dimension: &str, sketch: &Mutex<TopK<K>>, n: usize) {
+    let top = sketch.lock().unwrap().top(n);
+    for (rank, entry) in top.into_iter().enumerate() {
+        HEAVY_HITTER_REQUESTS
+            .with_label_values(&[dimension, &(rank + 1).to_string(), &entry.key.to_string()])
+            .set(entry.count as i64);
+    }
+}
+
+/// Publishes the top entries and decays the sketches.
+#[async_trait]
+impl BackgroundService for HeavyHitters {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let mut export_period = interval(self.settings.export_interval);
+        let mut decay_period = interval(self.settings.decay_period);
+        // Both fire right away, skip the first decay.
+        decay_period.tick().await;
+
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => break,
+                _ = export_period.tick() => self.export(),
+                _ = decay_period.tick() => self.decay(),
+            }
+        }
+    }
+}
@@ -0,0 +1,157 @@
+//! Decoy listeners that look like Minecraft servers.
+//!
+//! Nothing legitimate connects to them, so every address that does is banned
+//! as a scanner before it finds the real listener.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use log::{debug, warn};
+use once_cell::sync::Lazy;
+use pingora::apps::ServerApp;
+use pingora::protocols::Stream;
+use pingora::server::ShutdownWatch;
+use pingora::services::listening::Service;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use raigeki::net::normalize;
+use raigeki::pi::handshake::{read_opening, Deadlines};
+use raigeki_error::Reason;
+use raigeki_mcproto::handshake::NextState;
+use raigeki_mcproto::status::{LegacyKickPacket, StatusResponsePacket};
+use serde_json::json;
+use tokio::io::AsyncWriteExt;
+use tokio::time::{timeout, Instant};
+
+use crate::service::admission::Admission;
+use crate::service::events::SessionEvents;
+use crate::service::forward::{disconnect, read_ping};
+use crate::settings::{HoneypotSettings, Timeouts};
+
+static HONEYPOT_HITS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "honeypot_hits_total",
+        "Connections to the decoy listeners",
+        &["port"]
+    )
+    .unwrap()
+});
+
+/// What vanilla servers tell players missing from the whitelist.
+const NOT_WHITELISTED: &str = "You are not white-listed on this server!";
+
+pub fn honeypot_service(app: HoneypotApp) -> Service<HoneypotApp> {
+    Service::new("Raigeki Honeypot".to_string(), app)
+}
+
+pub struct HoneypotApp {
+    settings: HoneypotSettings,
+    admission: Admission,
+    timeouts: Timeouts,
+}
+
+impl HoneypotApp {
+    pub fn new(settings: HoneypotSettings, admission: Admission, timeouts: Timeouts) -> Self {
+        HoneypotApp {
+            settings,
+            admission,
+            timeouts,
+        }
+    }
+
+    /// Answers the client like an ordinary whitelisted server would.
+    async fn respond(&self, io: &mut Stream) {
+        let accepted_at = Instant::now();
+        let deadlines = Deadlines {
+            first_byte: accepted_at + self.timeouts.first_byte,
+            handshake: accepted_at + self.timeouts.handshake,
+            login_start: accepted_at + self.timeouts.login_start,
+        };
+
+        let Ok(opening) = read_opening(io, &deadlines).await else {
+            return;
+        };
+
+        let Some(handshake) = opening.handshake else {
+            let packet = LegacyKickPacket {
+                // What vanilla servers answer the legacy ping with.
+                protocol_version: 127,
+                version_name: self.settings.version.clone(),
+                motd: self.settings.motd.clone(),
+                online_players: self.settings.online_players,
+                max_players: self.settings.max_players,
+            };
+            if io.write_all(&packet.serialize()).await.is_ok() {
+                let _ = io.flush().await;
+            }
+            return;
+        };
+
+        if handshake.next_state != NextState::Status {
+            disconnect(io, json!({ "text": NOT_WHITELISTED }).to_string()).await;
+            return;
+        }
+
+        let status = json!({
+            "version": {
+                "name": self.settings.version,
+                "protocol": self.settings.protocol,
+            },
+            "players": {
+                "max": self.settings.max_players,
+                "online": self.settings.online_players,
+            },
+            "description": { "text": self.settings.motd },
+            "enforcesSecureChat": true,
+        });
+        let packet = StatusResponsePacket::new(status.to_string());
+        if io.write_all(&packet.serialize()).await.is_err() || io.flush().await.is_err() {
+            return;
+        }
+
+        if let Ok(Some(ping)) = timeout(self.timeouts.handshake, read_ping(io)).await {
+            if io.write_all(&ping).await.is_ok() {
+                let _ = io.flush().await;
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl ServerApp for HoneypotApp {
+    async fn process_new(
+        self: &Arc<Self>,
+        mut io: Stream,
+        _shutdown: &ShutdownWatch,
+    ) -> Option<Stream> {
+        let digest = io.get_socket_digest()?;
+        let peer_addr = *digest.peer_addr()?.as_inet()?;
+        let port = digest
+            .local_addr()
+            .and_then(|addr| addr.as_inet().map(SocketAddr::port))
+            .unwrap_or_default();
+        let addr = normalize(peer_addr.ip());
+
+        HONEYPOT_HITS.with_label_values(&[&port.to_string()]).inc();
+        let mut events = SessionEvents::accept("honeypot", "java", peer_addr);
+
+        if self
+            .admission
+            .ban_scanner(addr, self.settings.ban_ttl, format!("port:{}", port))
+            .await
+        {
+            warn!("Address {} touched honeypot port {}; banned", addr, port);
+            events.reject(Reason::Scanner, format!("port:{}", port));
+        } else {
+            debug!(
+                "Address {} touched honeypot port {}; no new ban",
+                addr, port
+            );
+            events.close("honeypot");
+        }
+
+        self.respond(&mut io).await;
+
+        None
+    }
+}
@@ -0,0 +1,217 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use ipnet::IpNet;
+use once_cell::sync::Lazy;
+use prometheus::{register_int_gauge, IntGauge};
+use raigeki::net::SubnetPrefix;
+
+static HALF_OPEN_CONNS: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "half_open_connections",
+        "Connections accepted but not yet past Login Start"
+    )
+    .unwrap()
+});
+
+/// Caps on simultaneously open connections. Zero disables a cap.
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionLimits {
+    pub max_total: usize,
+    pub max_per_ip: usize,
+    pub max_per_subnet: usize,
+    pub max_half_open: usize,
+    pub subnet_prefix: SubnetPrefix,
+}
+
+/// Rate limits shared by every address of a subnet, on top of the
+/// per-address ones.
+///
+/// Parsed from `<v4|v6>/<prefix>=<connects per minute>:<packets per minute>`,
+/// e.g. `v6/48=120:80000`. A zero limit is not enforced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SubnetRateLimit {
+    pub v6: bool,
+    pub prefix_len: u8,
+    pub connect_limit: isize,
+    pub rate_limit: isize,
+}
+
+impl SubnetRateLimit {
+    /// The subnet `ip` falls into, if the rule covers its address family.
+    pub fn subnet(&self, ip: IpAddr) -> Option<IpNet> {
+        if ip.is_ipv6() != self.v6 {
+            return None;
+        }
+        IpNet::new(ip, self.prefix_len).ok().map(|net| net.trunc())
+    }
+}
+
+impl FromStr for SubnetRateLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid subnet rate limit: {}", s);
+
+        let (prefix, limits) = s.split_once('=').ok_or_else(invalid)?;
+        let (family, prefix_len) = prefix.trim().split_once('/').ok_or_else(invalid)?;
+        let (connect_limit, rate_limit) = limits.split_once(':').ok_or_else(invalid)?;
+
+        let v6 = match family {
+            "v4" => false,
+            "v6" => true,
+            _ => return Err(invalid()),
+        };
+        let prefix_len: u8 = prefix_len.parse().map_err(|_| invalid())?;
+        if prefix_len > if v6 { 128 } else { 32 } {
+            return Err(invalid());
+        }
+
+        Ok(SubnetRateLimit {
+            v6,
+            prefix_len,
+            connect_limit: connect_limit.trim().parse().map_err(|_| invalid())?,
+            rate_limit: rate_limit.trim().parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitExceeded {
+    Total,
+    PerIp,
+    PerSubnet,
+    HalfOpen,
+}
+
+impl LimitExceeded {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LimitExceeded::Total => "total",
+            LimitExceeded::PerIp => "per_ip",
+            LimitExceeded::PerSubnet => "per_subnet",
+            LimitExceeded::HalfOpen => "half_open",
+        }
+    }
+}
+
+#[derive(Default)]
+struct Counts {
+    total: usize,
+    half_open: usize,
+    per_ip: HashMap<IpAddr, usize>,
+    per_subnet: HashMap<IpNet, usize>,
+}
+
+/// Tracks open connections and hands out slots while they stay under the
+/// configured caps.
+pub struct ConnectionTracker {
+    limits: ConnectionLimits,
+    counts: Mutex<Counts>,
+}
+
+impl ConnectionTracker {
+    pub fn new(limits: ConnectionLimits) -> Arc<Self> {
+        Arc::new(ConnectionTracker {
+            limits,
+            counts: Mutex::new(Counts::default()),
+        })
+    }
+
+    /// Reserves a half-open slot for a connection from `ip`.
+    pub fn acquire(self: &Arc<Self>, ip: IpAddr) -> Result<ConnectionSlot, LimitExceeded> {
+        let subnet = self.limits.subnet_prefix.subnet(ip);
+        let mut counts = self.counts.lock().unwrap();
+
+        if exceeds(counts.total, self.limits.max_total) {
+            return Err(LimitExceeded::Total);
+        }
+        if exceeds(counts.half_open, self.limits.max_half_open) {
+            return Err(LimitExceeded::HalfOpen);
+        }
+        if exceeds(count(&counts.per_ip, &ip), self.limits.max_per_ip) {
+            return Err(LimitExceeded::PerIp);
+        }
+        if exceeds(
+            count(&counts.per_subnet, &subnet),
+            self.limits.max_per_subnet,
+        ) {
+            return Err(LimitExceeded::PerSubnet);
+        }
+
+        Ok(self.reserve(&mut counts, ip, subnet))
+    }
+
+    /// Reserves a half-open slot for a whitelisted `ip`. It counts towards
+    /// the caps of other connections but is never refused.
+    pub fn acquire_exempt(self: &Arc<Self>, ip: IpAddr) -> ConnectionSlot {
+        let subnet = self.limits.subnet_prefix.subnet(ip);
+        let mut counts = self.counts.lock().unwrap();
+        self.reserve(&mut counts, ip, subnet)
+    }
+
+    fn reserve(self: &Arc<Self>, counts: &mut Counts, ip: IpAddr, subnet: IpNet) -> ConnectionSlot {
+        counts.total += 1;
+        counts.half_open += 1;
+        *counts.per_ip.entry(ip).or_default() += 1;
+        *counts.per_subnet.entry(subnet).or_default() += 1;
+        HALF_OPEN_CONNS.inc();
+
+        ConnectionSlot {
+            tracker: Arc::clone(self),
+            ip,
+            subnet,
+            half_open: true,
+        }
+    }
+}
+
+fn exceeds(current: usize, max: usize) -> bool {
+    max != 0 && current >= max
+}
+
+fn count<K: Hash + Eq>(map: &HashMap<K, usize>, key: &K) -> usize {
+    map.get(key).copied().unwrap_or_default()
+}
+
+fn release<K: Hash + Eq>(map: &mut HashMap<K, usize>, key: &K) {
+    if let Some(n) = map.get_mut(key) {
+        *n -= 1;
+        if *n == 0 {
+            map.remove(key);
+        }
+    }
+}
+
+/// A reserved connection slot, released on drop.
+pub struct ConnectionSlot {
+    tracker: Arc<ConnectionTracker>,
+    ip: IpAddr,
+    subnet: IpNet,
+    half_open: bool,
+}
+
+impl ConnectionSlot {
+    /// Marks the connection as past Login Start, freeing its half-open slot.
+    pub fn established(&mut self) {
+        if self.half_open {
+            self.half_open = false;
+            self.tracker.counts.lock().unwrap().half_open -= 1;
+            HALF_OPEN_CONNS.dec();
+        }
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.established();
+
+        let mut counts = self.tracker.counts.lock().unwrap();
+        counts.total -= 1;
+        release(&mut counts.per_ip, &self.ip);
+        release(&mut counts.per_subnet, &self.subnet);
+    }
+}
@@ -1,9 +1,17 @@
+pub mod admin;
+pub mod admission;
+pub mod ban_store;
+pub mod bedrock;
+pub mod blocklist;
+pub mod cluster;
+pub mod events;
+pub mod feeds;
 pub mod forward;
 pub mod geoip;
+pub mod heavy_hitters;
+pub mod honeypot;
+pub mod limits;
+pub mod shaping;
 pub mod stats;
-
-enum MemcachedStatus {
-    _Unknown,
-    IpBlocked,
-    IpWhiteList,
-}
\ No newline at end of file
+pub mod tarpit;
+pub mod whitelist;
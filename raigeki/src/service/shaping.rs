@@ -0,0 +1,244 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use raigeki::relay::Direction;
+
+/// What to do with a session that sends or receives faster than allowed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowAction {
+    /// Pause the direction until the bucket refills.
+    Throttle,
+    /// Drop the session and ban the address.
+    Ban,
+}
+
+impl FromStr for OverflowAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "throttle" => Ok(OverflowAction::Throttle),
+            "ban" => Ok(OverflowAction::Ban),
+            _ => Err(format!("unknown overflow action: {}", s)),
+        }
+    }
+}
+
+/// Byte rates in bytes per second. Zero disables a limit.
+#[derive(Debug, Clone, Copy)]
+pub struct BandwidthLimits {
+    pub conn_upstream: u64,
+    pub conn_downstream: u64,
+    pub ip_upstream: u64,
+    pub ip_downstream: u64,
+    pub global_egress: u64,
+    pub overflow: OverflowAction,
+}
+
+/// Token bucket that lets its balance go negative: a consumer that overdraws
+/// is told how long to wait until the debt is paid off.
+struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    fn new(rate: u64) -> Option<Self> {
+        if rate == 0 {
+            return None;
+        }
+
+        // Allow a second worth of traffic, but never less than one relay read.
+        let burst = (rate as f64).max(raigeki::relay::BUF_SIZE as f64);
+
+        Some(TokenBucket {
+            rate: rate as f64,
+            burst,
+            state: Mutex::new((burst, Instant::now())),
+        })
+    }
+
+    fn take(&self, bytes: usize) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let (tokens, last) = &mut *state;
+
+        let now = Instant::now();
+        *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * self.rate).min(self.burst);
+        *last = now;
+        *tokens -= bytes as f64;
+
+        if *tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-*tokens / self.rate)
+        }
+    }
+}
+
+fn take(bucket: &Option<TokenBucket>, bytes: usize) -> Duration {
+    bucket
+        .as_ref()
+        .map(|bucket| bucket.take(bytes))
+        .unwrap_or_default()
+}
+
+struct Buckets {
+    upstream: Option<TokenBucket>,
+    downstream: Option<TokenBucket>,
+}
+
+impl Buckets {
+    fn take(&self, direction: Direction, bytes: usize) -> Duration {
+        match direction {
+            Direction::Upstream => take(&self.upstream, bytes),
+            Direction::Downstream => take(&self.downstream, bytes),
+        }
+    }
+}
+
+/// Shared bandwidth state: the global egress bucket and one pair of buckets
+/// per client address with live sessions.
+pub struct Shaper {
+    limits: BandwidthLimits,
+    global_egress: Option<TokenBucket>,
+    per_ip: Mutex<HashMap<IpAddr, Arc<Buckets>>>,
+}
+
+impl Shaper {
+    pub fn new(limits: BandwidthLimits) -> Arc<Self> {
+        Arc::new(Shaper {
+            limits,
+            global_egress: TokenBucket::new(limits.global_egress),
+            per_ip: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn overflow(&self) -> OverflowAction {
+        self.limits.overflow
+    }
+
+    /// Buckets for a new session from `ip`.
+    pub fn session(self: &Arc<Self>, ip: IpAddr) -> SessionShaper {
+        let per_ip = (self.limits.ip_upstream != 0 || self.limits.ip_downstream != 0).then(|| {
+            let mut per_ip = self.per_ip.lock().unwrap();
+            Arc::clone(per_ip.entry(ip).or_insert_with(|| {
+                Arc::new(Buckets {
+                    upstream: TokenBucket::new(self.limits.ip_upstream),
+                    downstream: TokenBucket::new(self.limits.ip_downstream),
+                })
+            }))
+        });
+
+        SessionShaper {
+            shaper: Arc::clone(self),
+            ip,
+            conn: Buckets {
+                upstream: TokenBucket::new(self.limits.conn_upstream),
+                downstream: TokenBucket::new(self.limits.conn_downstream),
+            },
+            per_ip,
+        }
+    }
+}
+
+/// Bandwidth accounting of one session.
+pub struct SessionShaper {
+    shaper: Arc<Shaper>,
+    ip: IpAddr,
+    conn: Buckets,
+    per_ip: Option<Arc<Buckets>>,
+}
+
+impl SessionShaper {
+    /// Charges `bytes` against every bucket that applies and returns how long
+    /// the direction has to pause to stay within all of them.
+    pub fn take(&self, direction: Direction, bytes: usize) -> Duration {
+        let mut delay = self.conn.take(direction, bytes);
+
+        if let Some(per_ip) = &self.per_ip {
+            delay = delay.max(per_ip.take(direction, bytes));
+        }
+
+        if direction == Direction::Downstream {
+            delay = delay.max(take(&self.shaper.global_egress, bytes));
+        }
+
+        delay
+    }
+}
+
+impl Drop for SessionShaper {
+    fn drop(&mut self) {
+        let Some(per_ip) = self.per_ip.take() else {
+            return;
+        };
+
+        let mut map = self.shaper.per_ip.lock().unwrap();
+        // References are only taken and released under the lock, so the map
+        // holds the only one once the last session of this address is gone.
+        drop(per_ip);
+        if map
+            .get(&self.ip)
+            .is_some_and(|buckets| Arc::strong_count(buckets) == 1)
+        {
+            map.remove(&self.ip);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits() -> BandwidthLimits {
+        BandwidthLimits {
+            conn_upstream: 0,
+            conn_downstream: 0,
+            ip_upstream: 1 << 20,
+            ip_downstream: 1 << 20,
+            global_egress: 0,
+            overflow: OverflowAction::Throttle,
+        }
+    }
+
+    #[test]
+    fn sessions_of_an_address_share_its_buckets() {
+        let shaper = Shaper::new(limits());
+        let ip = "192.0.2.1".parse().unwrap();
+        let first = shaper.session(ip);
+        let second = shaper.session(ip);
+        assert_eq!(shaper.per_ip.lock().unwrap().len(), 1);
+
+        drop(first);
+        assert_eq!(shaper.per_ip.lock().unwrap().len(), 1);
+        drop(second);
+        assert!(shaper.per_ip.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn concurrent_drops_forget_the_address() {
+        let shaper = Shaper::new(limits());
+        let ip = "192.0.2.1".parse().unwrap();
+        for _ in 0..200 {
+            let start = Arc::new(std::sync::Barrier::new(8));
+            let threads: Vec<_> = (0..8)
+                .map(|_| {
+                    let session = shaper.session(ip);
+                    let start = Arc::clone(&start);
+                    std::thread::spawn(move || {
+                        start.wait();
+                        drop(session);
+                    })
+                })
+                .collect();
+            for thread in threads {
+                thread.join().unwrap();
+            }
+            assert!(shaper.per_ip.lock().unwrap().is_empty());
+        }
+    }
+}
@@ -71,7 +71,7 @@ impl BackgroundService for ExportService {
                     self.conn_inspector.lock().unwrap().add_metrics(raigeki::pi::conn::ConnectionMetrics {
                         total_conns: TOTAL_CONNS.get() as u64,
                         incoming_attempts: INCOMING_CONNECTIONS_ATTEMPTS.get() as u64,
-                        request_total: REQUEST_TOTAL.get() as u64,
+                        request_total: REQUEST_TOTAL.get(),
                     });
                 }
                 _ = period_check_ddos.tick() => {
@@ -0,0 +1,124 @@
+//! Holds rejected clients open instead of disconnecting them, so a bot that
+//! retries right away pays for it in time.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use once_cell::sync::Lazy;
+use pingora::protocols::Stream;
+use pingora::server::ShutdownWatch;
+use prometheus::{register_int_counter_vec, register_int_gauge, IntCounterVec, IntGauge};
+use raigeki_error::Reason;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::{interval_at, timeout, Instant};
+
+static TARPITTED_SESSIONS: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "tarpitted_sessions",
+        "Connections currently held in the tarpit"
+    )
+    .unwrap()
+});
+
+static TARPIT_OVERFLOWS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "tarpit_overflows_total",
+        "Connections disconnected right away because the tarpit was full",
+        &["reason"]
+    )
+    .unwrap()
+});
+
+/// Tarpitted sockets across all listeners.
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+/// Length prefix of a frame as large as the protocol allows, so the client
+/// keeps waiting for a body that only ever arrives one byte at a time.
+const ENDLESS_FRAME: [u8; 3] = [0xff, 0xff, 0x7f];
+
+#[derive(Debug, Clone)]
+pub struct TarpitSettings {
+    /// Rejection reasons that are tarpitted, empty disables the tarpit.
+    pub reasons: Vec<Reason>,
+    /// How long a connection is held.
+    pub duration: Duration,
+    /// Pause between the bytes dripped to the client.
+    pub drip_interval: Duration,
+    /// Tarpitted sockets allowed at once, over all listeners.
+    pub max_connections: usize,
+}
+
+pub struct Tarpit {
+    settings: TarpitSettings,
+}
+
+/// A taken tarpit slot, released on drop.
+pub struct TarpitSlot(());
+
+impl Drop for TarpitSlot {
+    fn drop(&mut self) {
+        ACTIVE.fetch_sub(1, Ordering::Relaxed);
+        TARPITTED_SESSIONS.dec();
+    }
+}
+
+impl Tarpit {
+    pub fn new(settings: TarpitSettings) -> Self {
+        Tarpit { settings }
+    }
+
+    /// Takes a slot for a client rejected for `reason`, if that reason is
+    /// tarpitted and the tarpit is not full.
+    pub fn enter(&self, reason: Reason) -> Option<TarpitSlot> {
+        if !self.settings.reasons.contains(&reason) {
+            return None;
+        }
+
+        let max = self.settings.max_connections;
+        if ACTIVE
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |active| {
+                (active < max).then_some(active + 1)
+            })
+            .is_err()
+        {
+            TARPIT_OVERFLOWS.with_label_values(&[reason.as_str()]).inc();
+            return None;
+        }
+
+        TARPITTED_SESSIONS.inc();
+        Some(TarpitSlot(()))
+    }
+
+    /// Drip-feeds `io` and discards whatever it sends until the tarpit time
+    /// is up, the client gives up or the proxy shuts down.
+    pub async fn hold(&self, io: &mut Stream, _slot: TarpitSlot, shutdown: &ShutdownWatch) {
+        let mut shutdown = shutdown.clone();
+        let drip_interval = self.settings.drip_interval;
+
+        // Writes block once the client stops reading, so the deadline has to
+        // cover them too.
+        let _ = timeout(self.settings.duration, async {
+            if io.write_all(&ENDLESS_FRAME).await.is_err() || io.flush().await.is_err() {
+                return;
+            }
+
+            let mut drip = interval_at(Instant::now() + drip_interval, drip_interval);
+            let mut buf = [0; 256];
+            loop {
+                tokio::select! {
+                    _ = shutdown.changed() => break,
+                    _ = drip.tick() => {
+                        if io.write_all(&[0]).await.is_err() || io.flush().await.is_err() {
+                            break;
+                        }
+                    }
+                    read = io.read(&mut buf) => match read {
+                        Ok(0) | Err(_) => break,
+                        Ok(_) => {}
+                    },
+                }
+            }
+        })
+        .await;
+    }
+}
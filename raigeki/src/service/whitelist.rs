@@ -0,0 +1,176 @@
+//! Addresses that skip the ban, GeoIP and rate-limit checks, the connection
+//! caps and bandwidth shaping: staff, partner servers and monitoring probes.
+//!
+//! Static entries come from `IP_WHITELIST`. Dynamic ones are added through
+//! the admin endpoint and kept in the ban store under a single key, so every
+//! node sharing the store picks them up on its next refresh. The key is read
+//! and written past the local cache, so a change only succeeds once the
+//! store has it; two nodes changing it in the same instant can still lose
+//! one of the changes.
+
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use ipnet::IpNet;
+use log::{error, info};
+use pingora::{server::ShutdownWatch, services::background::BackgroundService};
+use raigeki_error::Error;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::sync::Mutex;
+use tokio::time::interval;
+
+use crate::service::ban_store::{unix_now, BanStore, WHITELIST_KEY};
+
+/// Dynamic entries have their own expiry, the store key never expires.
+const STORE_TTL: u32 = u32::MAX;
+
+#[derive(Debug, Clone)]
+pub struct WhitelistSettings {
+    pub nets: Vec<IpNet>,
+    /// Connections per minute allowed from a whitelisted address, zero for
+    /// no limit.
+    pub connect_rate_limit: isize,
+    /// How often dynamic entries are reloaded from the ban store.
+    pub refresh: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WhitelistEntry {
+    pub net: IpNet,
+    /// Unix time the entry ends, in seconds, zero for never.
+    #[serde(default)]
+    pub expires_at: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+}
+
+impl WhitelistEntry {
+    fn live(&self, now: u64) -> bool {
+        self.expires_at == 0 || self.expires_at > now
+    }
+}
+
+pub struct Whitelist {
+    settings: WhitelistSettings,
+    ban_store: Arc<dyn BanStore>,
+    dynamic: RwLock<Vec<WhitelistEntry>>,
+    /// Held across the read, change and write of the store key, so that
+    /// concurrent changes on this node don't drop each other.
+    updates: Mutex<()>,
+}
+
+impl Whitelist {
+    pub fn new(settings: WhitelistSettings, ban_store: Arc<dyn BanStore>) -> Self {
+        Whitelist {
+            settings,
+            ban_store,
+            dynamic: RwLock::new(Vec::new()),
+            updates: Mutex::new(()),
+        }
+    }
+
+    pub fn connect_rate_limit(&self) -> isize {
+        self.settings.connect_rate_limit
+    }
+
+    /// Whether `addr` is whitelisted, statically or by a live dynamic entry.
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        if self.settings.nets.iter().any(|net| net.contains(&addr)) {
+            return true;
+        }
+
+        let now = unix_now();
+        self.dynamic
+            .read()
+            .unwrap()
+            .iter()
+            .any(|entry| entry.live(now) && entry.net.contains(&addr))
+    }
+
+    /// Static and live dynamic entries as JSON.
+    pub fn report(&self) -> Value {
+        let now = unix_now();
+        let entries = self.dynamic.read().unwrap();
+        let dynamic: Vec<&WhitelistEntry> =
+            entries.iter().filter(|entry| entry.live(now)).collect();
+
+        json!({
+            "static": self.settings.nets.iter().map(IpNet::to_string).collect::<Vec<_>>(),
+            "dynamic": dynamic,
+        })
+    }
+
+    /// Adds or replaces the dynamic entry for `entry.net`.
+    pub async fn add(&self, entry: WhitelistEntry) -> Result<(), Error> {
+        let _update = self.updates.lock().await;
+        let mut entries = self.load().await?;
+        entries.retain(|known| known.net != entry.net);
+        info!("Whitelisted {}", entry.net);
+        entries.push(entry);
+        self.store(entries).await
+    }
+
+    /// Removes the dynamic entry for `net`, returning whether there was one.
+    pub async fn remove(&self, net: IpNet) -> Result<bool, Error> {
+        let _update = self.updates.lock().await;
+        let mut entries = self.load().await?;
+        let before = entries.len();
+        entries.retain(|known| known.net != net);
+        if entries.len() == before {
+            return Ok(false);
+        }
+        info!("Removed {} from the whitelist", net);
+        self.store(entries).await?;
+        Ok(true)
+    }
+
+    /// Reloads the dynamic entries, keeping the current ones if the store
+    /// can't be read.
+    pub async fn refresh(&self) {
+        match self.load().await {
+            Ok(entries) => *self.dynamic.write().unwrap() = entries,
+            Err(e) => error!("Failed to load the whitelist: {}", e),
+        }
+    }
+
+    /// Live dynamic entries in the store.
+    async fn load(&self) -> Result<Vec<WhitelistEntry>, Error> {
+        let value = self
+            .ban_store
+            .get_many(&[WHITELIST_KEY])
+            .await?
+            .remove(WHITELIST_KEY);
+        let mut entries: Vec<WhitelistEntry> = match value {
+            Some(value) => serde_json::from_str(&value)
+                .map_err(|e| Error::InternalError(format!("corrupt whitelist: {}", e)))?,
+            None => Vec::new(),
+        };
+        let now = unix_now();
+        entries.retain(|entry| entry.live(now));
+        Ok(entries)
+    }
+
+    async fn store(&self, entries: Vec<WhitelistEntry>) -> Result<(), Error> {
+        let value = serde_json::to_string(&entries).unwrap();
+        self.ban_store.set(WHITELIST_KEY, &value, STORE_TTL).await?;
+        *self.dynamic.write().unwrap() = entries;
+        Ok(())
+    }
+}
+
+/// Reloads the dynamic entries so changes made on other nodes show up.
+#[async_trait]
+impl BackgroundService for Whitelist {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let mut period = interval(self.settings.refresh);
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => break,
+                _ = period.tick() => self.refresh().await,
+            }
+        }
+    }
+}
@@ -1,27 +1,208 @@
 use std::env;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::Duration;
 
 use dotenvy::dotenv;
+use ipnet::IpNet;
 use log::{error, info};
+use pingora::listeners::TcpSocketOptions;
+use pingora::protocols::TcpKeepalive;
+use raigeki::net::SubnetPrefix;
+use raigeki_tools::download::DownloadSettings;
+
+use crate::service::admission::StrikeSettings;
+use crate::service::ban_store::{
+    BanStoreKind, BanStoreSettings, CacheSettings, FailurePolicy, SnapshotSettings,
+};
+use crate::service::blocklist::{BlocklistSettings, BlocklistSource, ListFormat};
+use crate::service::cluster::ClusterSettings;
+use crate::service::events::EventSink;
+use crate::service::feeds::{Exclusions, FeedFormat, FeedLocation, FeedSettings, FeedSource};
+use crate::service::geoip::GeoIPRefreshSettings;
+use crate::service::limits::{ConnectionLimits, SubnetRateLimit};
+use crate::service::shaping::{BandwidthLimits, OverflowAction};
+use crate::service::tarpit::TarpitSettings;
+use crate::service::whitelist::WhitelistSettings;
 
 #[derive(Debug)]
 pub struct Settings {
     pub auto_mmdb: bool,
-    pub haproxy: bool,
+    pub prometheus_addr: String,
+    pub splice: bool,
     pub mmdb_asn: String,
     pub mmdb_city: String,
+    pub mmdb_refresh: GeoIPRefreshSettings,
+    pub listeners: Vec<ListenerSettings>,
+    pub bedrock: Option<BedrockSettings>,
+    pub backend_retry: BackendRetry,
+    pub ban_store: BanStoreSettings,
+    pub strikes: StrikeSettings,
+    pub whitelist: WhitelistSettings,
+    pub blocklists: BlocklistSettings,
+    pub feeds: FeedSettings,
+    pub cluster: ClusterSettings,
+    pub timeouts: Timeouts,
+    pub limits: ConnectionLimits,
+    pub bandwidth: BandwidthLimits,
+    pub shutdown: ShutdownPolicy,
+    pub events: EventSettings,
+    pub heavy_hitters: HeavyHitterSettings,
+    pub admin: AdminSettings,
+    pub tarpit: TarpitSettings,
+    pub honeypot: HoneypotSettings,
+}
+
+/// One L4 address with its own backend and admission policy.
+///
+/// Every variable is read as `LISTENER_<NAME>_<VAR>` first and falls back to
+/// the global `<VAR>`, so listeners only spell out what differs.
+#[derive(Debug)]
+pub struct ListenerSettings {
+    pub name: String,
+    pub haproxy: bool,
     pub l4_ip: String,
     pub l4_port: u16,
     pub outbound_ip: String,
     pub outbound_port: u16,
+    pub outbound_fallback_addrs: Vec<String>,
     pub blocked_asn: Vec<u32>,
     pub blocked_country: Vec<String>,
     pub rate_limit: isize,
     pub connect_rate_limit: isize,
-    pub memcached_addrs: Vec<String>,
+    pub subnet_rate_limits: Vec<SubnetRateLimit>,
+    pub socket_options: TcpSocketOptions,
+}
+
+/// UDP listener for Bedrock Edition.
+///
+/// Admission variables are read as `BEDROCK_<VAR>` first and fall back to the
+/// global `<VAR>`, like a TCP listener's.
+#[derive(Debug)]
+pub struct BedrockSettings {
+    pub l4_ip: String,
+    pub l4_port: u16,
+    pub outbound_addr: String,
+    /// Server list text used until the backend answers a ping.
+    pub motd: String,
+    pub motd_refresh: Duration,
+    pub idle_timeout: Duration,
+    pub max_sessions: usize,
+    pub ping_rate_limit: isize,
+    pub connect_rate_limit: isize,
+    pub packet_rate_limit: isize,
+    pub blocked_asn: Vec<u32>,
+    pub blocked_country: Vec<String>,
+    pub subnet_rate_limits: Vec<SubnetRateLimit>,
+}
+
+/// Limits on how long a connection may take for each stage of its life.
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    pub first_byte: Duration,
+    pub handshake: Duration,
+    pub login_start: Duration,
+    pub idle: Duration,
+    pub backend_connect: Duration,
+    /// `None` lets sessions live forever.
+    pub max_lifetime: Option<Duration>,
+}
+
+/// How hard to try reaching a backend before giving up on a client.
+#[derive(Debug, Clone, Copy)]
+pub struct BackendRetry {
+    /// Passes over the whole backend list.
+    pub rounds: u32,
+    /// Pause before each pass after the first, multiplied by the pass number.
+    pub backoff: Duration,
+}
+
+/// How live sessions are wound down when the proxy stops or upgrades.
+#[derive(Debug, Clone)]
+pub struct ShutdownPolicy {
+    /// How long sessions keep relaying after the shutdown signal before they
+    /// are cut off. Long enough for the backend to kick its players, who
+    /// otherwise only see the connection drop.
+    pub drain: Duration,
+    /// Title of the disconnect shown to clients still waiting on the backend
+    /// after the drain. Players already in game can't be sent one.
+    pub message: String,
+}
+
+/// Where per-connection events go.
+#[derive(Debug, Clone)]
+pub struct EventSettings {
+    /// Off unless set, a line per verdict would swamp the logs on stdout.
+    pub sink: EventSink,
+    /// Events buffered for the sink before new ones are dropped.
+    pub queue: usize,
+}
+
+/// Sketches of the top request sources.
+#[derive(Debug, Clone, Copy)]
+pub struct HeavyHitterSettings {
+    /// Sources tracked per dimension.
+    pub capacity: usize,
+    /// Top sources per dimension exported to prometheus.
+    pub exported: usize,
+    pub export_interval: Duration,
+    /// How often all counts are halved.
+    pub decay_period: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct AdminSettings {
+    /// Listen address of the admin endpoint, `None`, the default, disables
+    /// it.
+    pub addr: Option<String>,
+    pub token: Option<String>,
+}
+
+/// Decoy listeners that ban every address touching them.
+#[derive(Debug, Clone)]
+pub struct HoneypotSettings {
+    pub ip: String,
+    /// Empty disables the honeypot.
+    pub ports: Vec<u16>,
+    /// Seconds a scanner stays banned.
+    pub ban_ttl: u32,
+    pub version: String,
+    pub protocol: i32,
+    pub motd: String,
+    pub online_players: i32,
+    pub max_players: i32,
+}
+
+/// Parses a comma separated list of ports and `first-last` ranges.
+fn parse_ports(var_name: &str) -> Vec<u16> {
+    let mut ports = Vec::new();
+    for item in env::var(var_name).unwrap_or_default().split(',') {
+        let item = item.trim();
+        if item.is_empty() {
+            continue;
+        }
+
+        let range = match item.split_once('-') {
+            Some((first, last)) => first
+                .trim()
+                .parse()
+                .and_then(|first: u16| last.trim().parse().map(|last: u16| first..=last)),
+            None => item.parse().map(|port: u16| port..=port),
+        };
+        match range {
+            Ok(range) => ports.extend(range),
+            Err(_) => error!("Invalid {} value {}, skipping", var_name, item),
+        }
+    }
+    ports
 }
 
 fn parse_env_to_bool(var_name: &str, default: bool) -> bool {
-    match env::var(var_name) {
+    parse_bool(env::var(var_name), default)
+}
+
+fn parse_bool(value: Result<String, env::VarError>, default: bool) -> bool {
+    match value {
         Ok(value) => match value.to_lowercase().as_str() {
             "1" | "true" | "yes" => true,
             "0" | "false" | "no" => false,
@@ -31,22 +212,289 @@ fn parse_env_to_bool(var_name: &str, default: bool) -> bool {
     }
 }
 
-impl Settings {
-    pub fn new() -> Self {
-        let _ = dotenv();
+fn parse_env<T: FromStr>(var_name: &str, default: T) -> T {
+    parse_value(var_name, env::var(var_name), default)
+}
 
-        let auto_mmdb = parse_env_to_bool("MMDB_AUTOMODE", true);
-        let haproxy = parse_env_to_bool("HAPROXY_HEADERS", false);
-        let mmdb_asn = env::var("MMDB_ASN").unwrap_or_else(|_| "/tmp/geolite2-asn.mmdb".to_owned());
-        let mmdb_city =
-            env::var("MMDB_CITY").unwrap_or_else(|_| "/tmp/geolite2-city.mmdb".to_owned());
+fn parse_value<T: FromStr>(var_name: &str, value: Result<String, env::VarError>, default: T) -> T {
+    match value {
+        Ok(value) => value.trim().parse::<T>().unwrap_or_else(|_| {
+            error!("Invalid {} value, using default value", var_name);
+            default
+        }),
+        Err(_) => default,
+    }
+}
+
+fn parse_env_to_secs(var_name: &str, default: u64) -> Duration {
+    Duration::from_secs(parse_env(var_name, default))
+}
+
+/// Parses a comma separated list of addresses and CIDR ranges, skipping
+/// invalid entries.
+fn parse_nets(var_name: &str) -> Vec<IpNet> {
+    env::var(var_name)
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .filter_map(|s| {
+            let net = s
+                .parse::<IpNet>()
+                .or_else(|_| s.parse::<IpAddr>().map(IpNet::from));
+            if net.is_err() {
+                error!("Invalid {} entry {}, skipping", var_name, s);
+            }
+            net.ok()
+        })
+        .collect()
+}
+
+/// Parses a comma separated list of durations such as `10m,1h,7d`. A bare
+/// number is in seconds.
+fn parse_durations(var_name: &str, default: &str) -> Vec<Duration> {
+    let parse = |value: &str| -> Option<Vec<Duration>> {
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(|s| {
+                let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+                    Some(at) => s.split_at(at),
+                    None => (s, "s"),
+                };
+                let scale = match unit {
+                    "s" => 1,
+                    "m" => 60,
+                    "h" => 60 * 60,
+                    "d" => 24 * 60 * 60,
+                    _ => return None,
+                };
+                number
+                    .parse::<u64>()
+                    .ok()
+                    .and_then(|n| n.checked_mul(scale))
+                    .map(Duration::from_secs)
+            })
+            .collect::<Option<Vec<_>>>()
+            .filter(|durations| !durations.is_empty())
+    };
+
+    match env::var(var_name) {
+        Ok(value) => parse(&value).unwrap_or_else(|| {
+            error!("Invalid {} value, using default value", var_name);
+            parse(default).unwrap()
+        }),
+        Err(_) => parse(default).unwrap(),
+    }
+}
+
+/// Downloads from `urls`, a comma separated list of a URL and its mirrors,
+/// retried as `DOWNLOAD_RETRIES` and `DOWNLOAD_BACKOFF` say.
+fn download_settings(urls: &str) -> DownloadSettings {
+    let mut settings = DownloadSettings::new(
+        urls.split(',')
+            .map(|url| url.trim().to_string())
+            .filter(|url| !url.is_empty())
+            .collect(),
+    );
+    settings.retries = parse_env("DOWNLOAD_RETRIES", settings.retries);
+    settings.backoff = parse_durations("DOWNLOAD_BACKOFF", "2s")[0];
+    settings
+}
+
+/// Where a GeoLite2 `edition` is downloaded from. With `MAXMIND_LICENSE_KEY`
+/// set it is the checksummed MaxMind archive, otherwise the
+/// `MMDB_<NAME>_URLS` mirrors, checked against `MMDB_<NAME>_SHA256_URL` if
+/// that is set. There is no default mirror.
+fn mmdb_download(name: &str, edition: &str) -> DownloadSettings {
+    let prefix = format!("MMDB_{}_", name);
+    let mut settings = match env::var("MAXMIND_LICENSE_KEY") {
+        Ok(key) if !key.is_empty() => {
+            let url = format!(
+                "https://download.maxmind.com/app/geoip_download?edition_id={}&license_key={}&suffix=tar.gz",
+                edition, key
+            );
+            let mut settings = download_settings(&url);
+            settings.checksum_url = Some(format!("{}.sha256", url));
+            settings
+        }
+        _ => {
+            let mut settings =
+                download_settings(&env::var(format!("{}URLS", prefix)).unwrap_or_default());
+            settings.checksum_url = env::var(format!("{}SHA256_URL", prefix))
+                .ok()
+                .filter(|url| !url.is_empty());
+            settings
+        }
+    };
+    settings.extract = Some(format!("{}.mmdb", edition));
+    settings
+}
+
+/// A blocklist named in `BLOCKLISTS`, read from `BLOCKLIST_<NAME>_PATH` in
+/// `BLOCKLIST_<NAME>_FORMAT`.
+fn blocklist_source(name: &str) -> Option<BlocklistSource> {
+    let prefix = format!("BLOCKLIST_{}_", name.to_uppercase());
+    let path = match env::var(format!("{}PATH", prefix)) {
+        Ok(path) if !path.is_empty() => path,
+        _ => {
+            error!("{}PATH not set, skipping blocklist {}", prefix, name);
+            return None;
+        }
+    };
+
+    Some(BlocklistSource {
+        name: name.to_string(),
+        path: path.into(),
+        format: parse_env(&format!("{}FORMAT", prefix), ListFormat::Plain),
+    })
+}
+
+/// A feed named in `FEEDS`, read from `FEED_<NAME>_URL` or
+/// `FEED_<NAME>_PATH`. The URL may list mirrors after a comma, and the
+/// download is checked against `FEED_<NAME>_SHA256` or
+/// `FEED_<NAME>_SHA256_URL` if either is set.
+fn feed_source(name: &str) -> Option<FeedSource> {
+    let prefix = format!("FEED_{}_", name.to_uppercase());
+    let var = |var_name: &str| {
+        env::var(format!("{}{}", prefix, var_name))
+            .ok()
+            .filter(|value| !value.is_empty())
+    };
+
+    let location = match (var("URL"), var("PATH")) {
+        (Some(urls), _) => {
+            let mut download = download_settings(&urls);
+            download.sha256 = var("SHA256");
+            download.checksum_url = var("SHA256_URL");
+            FeedLocation::Url(download)
+        }
+        (None, Some(path)) => FeedLocation::File(path.into()),
+        (None, None) => {
+            error!(
+                "{}URL or {}PATH not set, skipping feed {}",
+                prefix, prefix, name
+            );
+            return None;
+        }
+    };
+
+    let mut exclude = Exclusions::default();
+    for item in var("EXCLUDE").unwrap_or_default().split(',').map(str::trim) {
+        if item.is_empty() {
+            continue;
+        }
+        let asn = item
+            .strip_prefix("AS")
+            .or_else(|| item.strip_prefix("as"))
+            .unwrap_or(item);
+        if let Ok(asn) = asn.parse::<u32>() {
+            exclude.asns.push(asn);
+        } else if let Ok(net) = item
+            .parse::<IpNet>()
+            .map(|net| net.trunc())
+            .or_else(|_| item.parse::<IpAddr>().map(IpNet::from))
+        {
+            exclude.nets.push(net);
+        } else {
+            error!("Invalid {}EXCLUDE entry {}, skipping", prefix, item);
+        }
+    }
+
+    Some(FeedSource {
+        name: name.to_string(),
+        location,
+        format: parse_env(&format!("{}FORMAT", prefix), FeedFormat::Asn),
+        refresh: parse_durations(&format!("{}REFRESH", prefix), "24h")[0]
+            .max(Duration::from_secs(60)),
+        exclude,
+    })
+}
+
+/// Variables of a single listener.
+struct ListenerEnv {
+    prefix: String,
+}
+
+impl ListenerEnv {
+    fn var(&self, var_name: &str) -> Result<String, env::VarError> {
+        env::var(format!("{}{}", self.prefix, var_name)).or_else(|_| env::var(var_name))
+    }
+
+    fn parse<T: FromStr>(&self, var_name: &str, default: T) -> T {
+        parse_value(var_name, self.var(var_name), default)
+    }
+
+    fn parse_bool(&self, var_name: &str, default: bool) -> bool {
+        parse_bool(self.var(var_name), default)
+    }
+
+    fn blocked_asn(&self) -> Vec<u32> {
+        self.var("BLOCKED_ASN")
+            .unwrap_or_else(|_| {
+                info!("BLOCKED_ASN not set, using empty list");
+                String::new()
+            })
+            .split(',')
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .map(|s| {
+                s.parse::<u32>().unwrap_or_else(|_| {
+                    error!("Invalid ASN id, using 0");
+                    0
+                })
+            })
+            .collect()
+    }
+
+    fn blocked_country(&self) -> Vec<String> {
+        self.var("BLOCKED_COUNTRY")
+            .unwrap_or_else(|_| {
+                info!("BLOCKED_COUNTRY not set, using empty list");
+                String::new()
+            })
+            .split(',')
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .collect()
+    }
+
+    /// Subnet rate limits, none unless set. A listener connecting 15 times a
+    /// minute per address might use `v4/24=60:0,v6/64=15:0,v6/48=120:0`.
+    fn subnet_rate_limits(&self) -> Vec<SubnetRateLimit> {
+        self.var("SUBNET_RATE_LIMITS")
+            .unwrap_or_else(|_| {
+                info!("SUBNET_RATE_LIMITS not set, no subnet rate limits");
+                String::new()
+            })
+            .split(',')
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .filter_map(|s| {
+                s.parse::<SubnetRateLimit>()
+                    .map_err(|e| error!("{}, skipping", e))
+                    .ok()
+            })
+            .collect()
+    }
+}
 
-        let l4_ip = env::var("L4_IP").unwrap_or_else(|_| {
+impl ListenerSettings {
+    fn new(name: &str) -> Self {
+        let env = ListenerEnv {
+            prefix: format!("LISTENER_{}_", name.to_uppercase()),
+        };
+
+        let haproxy = env.parse_bool("HAPROXY_HEADERS", false);
+
+        let l4_ip = env.var("L4_IP").unwrap_or_else(|_| {
             info!("L4_IP not set, using default value");
             "0.0.0.0".to_string()
         });
 
-        let l4_port = env::var("L4_PORT")
+        let l4_port = env
+            .var("L4_PORT")
             .unwrap_or_else(|_| {
                 info!("PORT not set, using default value");
                 "1337".to_string()
@@ -57,12 +505,13 @@ impl Settings {
                 1337
             });
 
-        let outbound_ip = env::var("OUTBOUND_IP").unwrap_or_else(|_| {
+        let outbound_ip = env.var("OUTBOUND_IP").unwrap_or_else(|_| {
             info!("L4_IP not set, using default value");
             "0.0.0.0".to_string()
         });
 
-        let outbound_port = env::var("OUTBOUND_PORT")
+        let outbound_port = env
+            .var("OUTBOUND_PORT")
             .unwrap_or_else(|_| {
                 info!("PORT not set, using default value");
                 "1337".to_string()
@@ -73,33 +522,16 @@ impl Settings {
                 1337
             });
 
-        let blocked_asn: Vec<u32> = env::var("BLOCKED_ASN")
-            .unwrap_or_else(|_| {
-                info!("BLOCKED_ASN not set, using empty list");
-                String::new()
-            })
+        let outbound_fallback_addrs = env
+            .var("OUTBOUND_FALLBACK_ADDRS")
+            .unwrap_or_default()
             .split(',')
             .map(|s| s.trim().to_string())
             .filter(|s| !s.is_empty())
-            .map(|s| {
-                s.parse::<u32>().unwrap_or_else(|_| {
-                    error!("Invalid ASN id, using 0");
-                    0
-                })
-            })
             .collect();
 
-        let blocked_country = env::var("BLOCKED_COUNTRY")
-            .unwrap_or_else(|_| {
-                info!("BLOCKED_COUNTRY not set, using empty list");
-                String::new()
-            })
-            .split(',')
-            .map(|s| s.trim().to_string())
-            .filter(|s| !s.is_empty())
-            .collect();
-
-        let rate_limit = env::var("RATE_LIMIT")
+        let rate_limit = env
+            .var("RATE_LIMIT")
             .unwrap_or_else(|_| {
                 info!("RATE_LIMIT not set, using default value");
                 "50".to_string()
@@ -110,7 +542,8 @@ impl Settings {
                 50
             });
 
-        let connect_rate_limit = env::var("CONNECT_RATE_LIMIT")
+        let connect_rate_limit = env
+            .var("CONNECT_RATE_LIMIT")
             .unwrap_or_else(|_| {
                 info!("CONNECT_RATE_LIMIT not set, using default value");
                 "15".to_string()
@@ -121,6 +554,117 @@ impl Settings {
                 15
             });
 
+        let blocked_asn = env.blocked_asn();
+        let blocked_country = env.blocked_country();
+        let subnet_rate_limits = env.subnet_rate_limits();
+
+        let tcp_keepalive_idle = env.parse("TCP_KEEPALIVE_IDLE", 60);
+        let mut socket_options = TcpSocketOptions::default();
+        socket_options.ipv6_only = env
+            .var("IPV6_ONLY")
+            .ok()
+            .map(|_| env.parse_bool("IPV6_ONLY", false));
+        socket_options.tcp_fastopen =
+            Some(env.parse("TCP_FASTOPEN", 10)).filter(|backlog| *backlog > 0);
+        socket_options.tcp_keepalive = (tcp_keepalive_idle > 0).then(|| TcpKeepalive {
+            idle: Duration::from_secs(tcp_keepalive_idle),
+            interval: Duration::from_secs(env.parse("TCP_KEEPALIVE_INTERVAL", 5)),
+            count: env.parse("TCP_KEEPALIVE_COUNT", 10),
+            user_timeout: Duration::from_secs(env.parse("TCP_USER_TIMEOUT", 10)),
+        });
+
+        ListenerSettings {
+            name: name.to_string(),
+            haproxy,
+            l4_ip,
+            l4_port,
+            outbound_ip,
+            outbound_port,
+            outbound_fallback_addrs,
+            blocked_asn,
+            blocked_country,
+            rate_limit,
+            connect_rate_limit,
+            subnet_rate_limits,
+            socket_options,
+        }
+    }
+}
+
+impl BedrockSettings {
+    fn new() -> Self {
+        let env = ListenerEnv {
+            prefix: "BEDROCK_".to_string(),
+        };
+        let connect_rate_limit = env.parse("CONNECT_RATE_LIMIT", 15);
+
+        BedrockSettings {
+            l4_ip: env::var("BEDROCK_L4_IP").unwrap_or_else(|_| "0.0.0.0".to_string()),
+            l4_port: parse_env("BEDROCK_L4_PORT", 19132),
+            outbound_addr: env::var("BEDROCK_OUTBOUND_ADDR")
+                .unwrap_or_else(|_| "127.0.0.1:19133".to_string()),
+            motd: env::var("BEDROCK_MOTD").unwrap_or_else(|_| "Raigeki".to_string()),
+            motd_refresh: parse_env_to_secs("BEDROCK_MOTD_REFRESH", 5).max(Duration::from_secs(1)),
+            idle_timeout: parse_env_to_secs("BEDROCK_IDLE_TIMEOUT", 30),
+            max_sessions: parse_env("BEDROCK_MAX_SESSIONS", 4096),
+            ping_rate_limit: parse_env("BEDROCK_PING_RATE_LIMIT", 60),
+            connect_rate_limit,
+            packet_rate_limit: parse_env("BEDROCK_PACKET_RATE_LIMIT", 60_000),
+            blocked_asn: env.blocked_asn(),
+            blocked_country: env.blocked_country(),
+            subnet_rate_limits: env.subnet_rate_limits(),
+        }
+    }
+}
+
+impl Settings {
+    pub fn new() -> Self {
+        let _ = dotenv();
+
+        let mut auto_mmdb = parse_env_to_bool("MMDB_AUTOMODE", true);
+        let prometheus_addr =
+            env::var("PROMETHEUS_ADDR").unwrap_or_else(|_| "0.0.0.0:6150".to_string());
+        let splice = parse_env_to_bool("RELAY_SPLICE", false);
+        let mmdb_asn = env::var("MMDB_ASN").unwrap_or_else(|_| "/tmp/geolite2-asn.mmdb".to_owned());
+        let mmdb_city =
+            env::var("MMDB_CITY").unwrap_or_else(|_| "/tmp/geolite2-city.mmdb".to_owned());
+        let asn = mmdb_download("ASN", "GeoLite2-ASN");
+        let city = mmdb_download("CITY", "GeoLite2-City");
+        if auto_mmdb && (asn.urls.is_empty() || city.urls.is_empty()) {
+            error!(
+                "MMDB_AUTOMODE needs MAXMIND_LICENSE_KEY, or MMDB_ASN_URLS and MMDB_CITY_URLS; \
+                 not downloading the databases"
+            );
+            auto_mmdb = false;
+        }
+        let mmdb_refresh = GeoIPRefreshSettings {
+            auto_download: auto_mmdb,
+            interval: parse_durations("MMDB_REFRESH_INTERVAL", "24h")[0]
+                .max(Duration::from_secs(60)),
+            asn,
+            city,
+        };
+
+        let listeners = match env::var("LISTENERS") {
+            Ok(names) => names
+                .split(',')
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .map(|name| ListenerSettings::new(&name))
+                .collect(),
+            Err(_) => {
+                info!("LISTENERS not set, using a single default listener");
+                vec![ListenerSettings::new("default")]
+            }
+        };
+
+        let bedrock = parse_env_to_bool("BEDROCK_ENABLED", false).then(BedrockSettings::new);
+
+        let backend_retry = BackendRetry {
+            rounds: parse_env("BACKEND_CONNECT_ROUNDS", 2).max(1),
+            backoff: Duration::from_millis(parse_env("BACKEND_RETRY_BACKOFF_MS", 250)),
+        };
+
         let memcached_addrs = env::var("MEMCACHED_ADDRS")
             .unwrap_or_else(|_| {
                 info!("MEMCACHED_ADDRS not set, using default value");
@@ -131,20 +675,216 @@ impl Settings {
             .filter(|s| !s.is_empty())
             .collect();
 
+        let ban_store = BanStoreSettings {
+            kind: parse_env("BAN_STORE", BanStoreKind::Memcached),
+            memcached_addrs,
+            redis_url: env::var("REDIS_URL")
+                .unwrap_or_else(|_| "redis://127.0.0.1:6379".to_string()),
+            cache: CacheSettings {
+                ttl: Duration::from_millis(parse_env("BAN_CACHE_TTL_MS", 5_000)),
+                negative_ttl: Duration::from_millis(parse_env("BAN_CACHE_NEGATIVE_TTL_MS", 1_000)),
+                capacity: parse_env("BAN_CACHE_CAPACITY", 100_000),
+                timeout: Duration::from_millis(parse_env("BAN_STORE_TIMEOUT_MS", 250)),
+                failure_policy: parse_env("BAN_STORE_FAILURE_POLICY", FailurePolicy::Open),
+            },
+            snapshot: SnapshotSettings {
+                path: env::var("BAN_SNAPSHOT_PATH")
+                    .ok()
+                    .filter(|path| !path.is_empty())
+                    .map(Into::into),
+                interval: parse_env_to_secs("BAN_SNAPSHOT_INTERVAL", 30)
+                    .max(Duration::from_secs(1)),
+            },
+        };
+
+        let strikes = StrikeSettings {
+            ladder: parse_durations("BAN_LADDER", "1h,6h,24h,7d")
+                .into_iter()
+                .map(|ttl| ttl.max(Duration::from_secs(1)))
+                .collect(),
+            decay: parse_env_to_secs("STRIKE_DECAY", 24 * 60 * 60),
+        };
+
+        let whitelist = WhitelistSettings {
+            nets: parse_nets("IP_WHITELIST"),
+            connect_rate_limit: parse_env("WHITELIST_CONNECT_RATE_LIMIT", 0),
+            refresh: parse_env_to_secs("WHITELIST_REFRESH", 10).max(Duration::from_secs(1)),
+        };
+
+        let blocklists = BlocklistSettings {
+            lists: env::var("BLOCKLISTS")
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .filter_map(blocklist_source)
+                .collect(),
+            reload: parse_env_to_secs("BLOCKLIST_RELOAD_INTERVAL", 5).max(Duration::from_secs(1)),
+        };
+
+        let feeds = FeedSettings {
+            sources: env::var("FEEDS")
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .filter_map(feed_source)
+                .collect(),
+            cache_dir: env::var("FEED_CACHE_DIR")
+                .unwrap_or_else(|_| "/tmp/raigeki-feeds".to_string())
+                .into(),
+        };
+
+        let cluster_bind = env::var("CLUSTER_BIND")
+            .ok()
+            .filter(|bind| !bind.is_empty());
+        let cluster_secret = env::var("CLUSTER_SECRET").unwrap_or_default();
+        let cluster = ClusterSettings {
+            bind: cluster_bind.clone().filter(|_| {
+                // Unauthenticated peers could ban anyone, so no secret means no cluster.
+                let missing = cluster_secret.is_empty();
+                if missing {
+                    error!("CLUSTER_SECRET not set, cluster mode disabled");
+                }
+                !missing
+            }),
+            peers: env::var("CLUSTER_PEERS")
+                .unwrap_or_default()
+                .split(',')
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .collect(),
+            node_id: env::var("CLUSTER_NODE_ID")
+                .ok()
+                .filter(|id| !id.is_empty())
+                .or(cluster_bind)
+                .unwrap_or_default(),
+            secret: cluster_secret,
+            sync_interval: parse_env_to_secs("CLUSTER_SYNC_INTERVAL", 10)
+                .max(Duration::from_secs(1)),
+        };
+
+        let timeouts = Timeouts {
+            first_byte: parse_env_to_secs("FIRST_BYTE_TIMEOUT", 5),
+            handshake: parse_env_to_secs("HANDSHAKE_TIMEOUT", 10),
+            login_start: parse_env_to_secs("LOGIN_START_TIMEOUT", 15),
+            idle: parse_env_to_secs("IDLE_TIMEOUT", 60),
+            backend_connect: parse_env_to_secs("BACKEND_CONNECT_TIMEOUT", 3),
+            max_lifetime: Some(parse_env_to_secs("MAX_SESSION_LIFETIME", 0))
+                .filter(|lifetime| !lifetime.is_zero()),
+        };
+
+        let limits = ConnectionLimits {
+            max_total: parse_env("MAX_CONNECTIONS", 0),
+            // Off unless set, so upgrading doesn't start refusing players
+            // behind a shared address.
+            max_per_ip: parse_env("MAX_CONNECTIONS_PER_IP", 0),
+            max_per_subnet: parse_env("MAX_CONNECTIONS_PER_SUBNET", 0),
+            max_half_open: parse_env("MAX_HALF_OPEN_CONNECTIONS", 1024),
+            subnet_prefix: SubnetPrefix::new(
+                parse_env("SUBNET_PREFIX_V4", 24),
+                parse_env("SUBNET_PREFIX_V6", 64),
+            ),
+        };
+
+        let bandwidth = BandwidthLimits {
+            conn_upstream: parse_env("CONN_BANDWIDTH_IN", 0),
+            conn_downstream: parse_env("CONN_BANDWIDTH_OUT", 0),
+            ip_upstream: parse_env("IP_BANDWIDTH_IN", 0),
+            ip_downstream: parse_env("IP_BANDWIDTH_OUT", 0),
+            global_egress: parse_env("GLOBAL_BANDWIDTH_OUT", 0),
+            overflow: parse_env("BANDWIDTH_OVERFLOW", OverflowAction::Throttle),
+        };
+
+        let shutdown = ShutdownPolicy {
+            drain: parse_env_to_secs("SHUTDOWN_DRAIN_PERIOD", 30),
+            message: env::var("SHUTDOWN_MESSAGE")
+                .unwrap_or_else(|_| "Прокси перезапускается".to_string()),
+        };
+
+        let sink = match parse_env("EVENT_SINK", EventSink::None) {
+            EventSink::File { path, .. } => EventSink::File {
+                path,
+                max_bytes: parse_env("EVENT_FILE_MAX_BYTES", 100 * 1024 * 1024),
+                keep: parse_env("EVENT_FILE_KEEP", 5),
+            },
+            sink => sink,
+        };
+        let events = EventSettings {
+            sink,
+            queue: parse_env("EVENT_QUEUE", 65536).max(1),
+        };
+
+        let heavy_hitters = HeavyHitterSettings {
+            capacity: parse_env("HEAVY_HITTERS_CAPACITY", 1000).max(1),
+            exported: parse_env("HEAVY_HITTERS_EXPORTED", 10),
+            export_interval: parse_env_to_secs("HEAVY_HITTERS_EXPORT_INTERVAL", 15)
+                .max(Duration::from_secs(1)),
+            decay_period: parse_env_to_secs("HEAVY_HITTERS_DECAY_PERIOD", 60)
+                .max(Duration::from_secs(1)),
+        };
+
+        let admin = AdminSettings {
+            addr: env::var("ADMIN_ADDR").ok().filter(|addr| !addr.is_empty()),
+            token: env::var("ADMIN_TOKEN")
+                .ok()
+                .filter(|token| !token.is_empty()),
+        };
+
+        let tarpit = TarpitSettings {
+            reasons: env::var("TARPIT_REASONS")
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .filter_map(|s| {
+                    s.parse()
+                        .map_err(|e| error!("Invalid TARPIT_REASONS value: {}", e))
+                        .ok()
+                })
+                .collect(),
+            duration: parse_env_to_secs("TARPIT_DURATION", 60),
+            drip_interval: parse_env_to_secs("TARPIT_DRIP_INTERVAL", 10)
+                .max(Duration::from_secs(1)),
+            max_connections: parse_env("TARPIT_MAX_CONNECTIONS", 1024),
+        };
+
+        let honeypot = HoneypotSettings {
+            ip: env::var("HONEYPOT_IP").unwrap_or_else(|_| "0.0.0.0".to_string()),
+            ports: parse_ports("HONEYPOT_PORTS"),
+            ban_ttl: parse_env("HONEYPOT_BAN_TTL", 24 * 60 * 60).max(1),
+            version: env::var("HONEYPOT_VERSION").unwrap_or_else(|_| "1.20.4".to_string()),
+            protocol: parse_env("HONEYPOT_PROTOCOL", 765),
+            motd: env::var("HONEYPOT_MOTD").unwrap_or_else(|_| "A Minecraft Server".to_string()),
+            online_players: parse_env("HONEYPOT_ONLINE_PLAYERS", 3),
+            max_players: parse_env("HONEYPOT_MAX_PLAYERS", 20),
+        };
+
         Settings {
             auto_mmdb,
-            haproxy,
+            prometheus_addr,
+            splice,
             mmdb_asn,
             mmdb_city,
-            l4_ip,
-            l4_port,
-            outbound_ip,
-            outbound_port,
-            blocked_asn,
-            blocked_country,
-            rate_limit,
-            connect_rate_limit,
-            memcached_addrs,
+            mmdb_refresh,
+            listeners,
+            bedrock,
+            backend_retry,
+            ban_store,
+            strikes,
+            whitelist,
+            blocklists,
+            feeds,
+            cluster,
+            timeouts,
+            limits,
+            bandwidth,
+            shutdown,
+            events,
+            heavy_hitters,
+            admin,
+            tarpit,
+            honeypot,
         }
     }
 }
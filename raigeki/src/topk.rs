@@ -0,0 +1,206 @@
+//! Bounded heavy-hitter tracking with the Space-Saving algorithm.
+
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+/// One tracked key. The true count lies in `count - error ..= count`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry<K> {
+    pub key: K,
+    pub count: u64,
+    pub error: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Counter {
+    count: u64,
+    error: u64,
+    seq: u64,
+}
+
+/// Keeps the approximate top keys of a stream in at most `capacity` slots.
+///
+/// A key that is not tracked while all slots are taken replaces the key with
+/// the lowest count and inherits that count as its error, so every key seen
+/// more often than `total / capacity` times is guaranteed to be tracked.
+#[derive(Debug)]
+pub struct TopK<K> {
+    capacity: usize,
+    counters: HashMap<K, Counter>,
+    /// Tracked keys ordered by count, `seq` breaks ties.
+    by_count: BTreeMap<(u64, u64), K>,
+    next_seq: u64,
+}
+
+impl<K: Clone + Eq + Hash> TopK<K> {
+    pub fn new(capacity: usize) -> Self {
+        TopK {
+            capacity: capacity.max(1),
+            counters: HashMap::new(),
+            by_count: BTreeMap::new(),
+            next_seq: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.counters.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.counters.is_empty()
+    }
+
+    /// Counts `n` occurrences of `key`.
+    pub fn observe(&mut self, key: &K, n: u64) {
+        if let Some(counter) = self.counters.get_mut(key) {
+            let k = self.by_count.remove(&(counter.count, counter.seq)).unwrap();
+            counter.count += n;
+            self.by_count.insert((counter.count, counter.seq), k);
+            return;
+        }
+
+        let mut error = 0;
+        if self.counters.len() >= self.capacity {
+            let ((min, _), evicted) = self.by_count.pop_first().unwrap();
+            self.counters.remove(&evicted);
+            error = min;
+        }
+
+        let counter = Counter {
+            count: error + n,
+            error,
+            seq: self.next_seq,
+        };
+        self.next_seq += 1;
+        self.by_count
+            .insert((counter.count, counter.seq), key.clone());
+        self.counters.insert(key.clone(), counter);
+    }
+
+    /// The `n` keys with the highest counts, highest first.
+    pub fn top(&self, n: usize) -> Vec<Entry<K>> {
+        self.by_count
+            .iter()
+            .rev()
+            .take(n)
+            .map(|(_, key)| {
+                let counter = self.counters[key];
+                Entry {
+                    key: key.clone(),
+                    count: counter.count,
+                    error: counter.error,
+                }
+            })
+            .collect()
+    }
+
+    /// Halves every count so old traffic fades out. Keys that drop to zero
+    /// are forgotten.
+    pub fn decay(&mut self) {
+        self.by_count.clear();
+        self.counters.retain(|_, counter| {
+            counter.count /= 2;
+            counter.error /= 2;
+            counter.count > 0
+        });
+        for (key, counter) in &self.counters {
+            self.by_count
+                .insert((counter.count, counter.seq), key.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(key: &str, count: u64, error: u64) -> Entry<&str> {
+        Entry { key, count, error }
+    }
+
+    #[test]
+    fn new_keys_replace_the_lowest_count() {
+        let mut topk = TopK::new(2);
+        topk.observe(&"a", 3);
+        topk.observe(&"b", 1);
+        topk.observe(&"c", 1);
+
+        assert_eq!(topk.len(), 2);
+        assert_eq!(topk.top(2), vec![entry("a", 3, 0), entry("c", 2, 1)]);
+    }
+
+    #[test]
+    fn ties_evict_the_oldest_key() {
+        let mut topk = TopK::new(2);
+        topk.observe(&"a", 1);
+        topk.observe(&"b", 1);
+        topk.observe(&"c", 1);
+        assert_eq!(topk.top(2), vec![entry("c", 2, 1), entry("b", 1, 0)]);
+    }
+
+    #[test]
+    fn counts_bound_the_true_counts() {
+        let capacity = 8;
+        let mut topk = TopK::new(capacity);
+        let mut truth: HashMap<u64, u64> = HashMap::new();
+
+        // A skewed stream: a few heavy keys in a tail of light ones.
+        let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut total = 0;
+        for _ in 0..10_000 {
+            seed = seed
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1_442_695_040_888_963_407);
+            let roll = seed >> 33;
+            let key = if roll.is_multiple_of(2) {
+                roll % 3
+            } else {
+                3 + roll % 200
+            };
+            topk.observe(&key, 1);
+            *truth.entry(key).or_default() += 1;
+            total += 1;
+        }
+
+        let top = topk.top(capacity);
+        assert_eq!(top.len(), capacity);
+        // Every observation lands on some tracked counter.
+        assert_eq!(top.iter().map(|entry| entry.count).sum::<u64>(), total);
+        for entry in &top {
+            assert!(entry.error <= total / capacity as u64, "{:?}", entry);
+            let actual = truth[&entry.key];
+            assert!(entry.count - entry.error <= actual, "{:?}", entry);
+            assert!(actual <= entry.count, "{:?}", entry);
+        }
+        // The heavy keys take a sixth of the stream each, so they must be
+        // tracked.
+        assert!((0..3).all(|key| truth[&key] > total / capacity as u64));
+        for (key, &count) in &truth {
+            if count > total / capacity as u64 {
+                assert!(top.iter().any(|entry| entry.key == *key), "{} missing", key);
+            }
+        }
+    }
+
+    #[test]
+    fn decay_halves_and_forgets() {
+        let mut topk = TopK::new(4);
+        topk.observe(&"a", 9);
+        topk.observe(&"b", 1);
+        topk.decay();
+
+        assert_eq!(topk.top(4), vec![entry("a", 4, 0)]);
+        topk.decay();
+        topk.decay();
+        topk.decay();
+        assert!(topk.is_empty());
+    }
+
+    #[test]
+    fn zero_capacity_keeps_one_key() {
+        let mut topk = TopK::new(0);
+        topk.observe(&"a", 1);
+        topk.observe(&"b", 1);
+        assert_eq!(topk.top(10), vec![entry("b", 2, 1)]);
+    }
+}